@@ -0,0 +1,169 @@
+//! Derive macros for [kv](https://docs.rs/kv), these are re-exported by `kv` when the `derive`
+//! feature is enabled.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, LitStr, Path};
+
+/// Derive `kv::Key` for a struct
+///
+/// Newtypes use the encoding of the inner key, structs with more than one field are encoded like
+/// tuple keys so they sort by each field in order. The type tag is the name of the struct.
+#[proc_macro_derive(Key)]
+pub fn derive_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    key(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Derive `kv::Value` for a serde type, the codec is selected using `#[kv(codec = "json")]`
+///
+/// The codec can be one of `json`, `msgpack`, `bincode` or `lexpr` (the matching `kv` feature must
+/// be enabled) or the path to any type defined using `kv::codec!`. The type tag names the codec
+/// and the type, e.g. `Json(User)`.
+#[proc_macro_derive(Value, attributes(kv))]
+pub fn derive_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    value(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn key(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(s) if !s.fields.is_empty() => &s.fields,
+        _ => {
+            return Err(Error::new_spanned(
+                name,
+                "Key can only be derived for structs with at least one field",
+            ))
+        }
+    };
+
+    let members: Vec<_> = fields.members().collect();
+    let vars: Vec<_> = (0..fields.len())
+        .map(|i| format_ident!("__field{}", i))
+        .collect();
+    let construct = match fields {
+        Fields::Named(_) => quote!(#name { #(#members: #vars),* }),
+        _ => quote!(#name(#(#vars),*)),
+    };
+
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, parse_quote!('__kv));
+    let where_clause = generics.make_where_clause();
+
+    let body = if fields.len() == 1 {
+        let ty = &fields.iter().next().unwrap().ty;
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::kv::Key<'__kv>));
+        quote! {
+            fn from_raw_key(r: &'__kv ::kv::Raw) -> Result<Self, ::kv::Error> {
+                #(let #vars = ::kv::Key::from_raw_key(r)?;)*
+                Ok(#construct)
+            }
+
+            fn to_raw_key(&self) -> Result<::kv::Raw, ::kv::Error> {
+                #(::kv::Key::to_raw_key(&self.#members))*
+            }
+        }
+    } else {
+        for field in fields {
+            let ty = &field.ty;
+            where_clause
+                .predicates
+                .push(parse_quote!(for<'__x> #ty: ::kv::Key<'__x>));
+        }
+        quote! {
+            fn from_raw_key(r: &'__kv ::kv::Raw) -> Result<Self, ::kv::Error> {
+                let src: &[u8] = r.as_ref();
+                #(
+                    let (#vars, src) = ::kv::__private::decode_escaped(src)?;
+                    let #vars = ::kv::Key::from_raw_key(&::kv::Raw::from(#vars))?;
+                )*
+                if !src.is_empty() {
+                    return Err(::kv::Error::Message("Invalid key encoding".into()));
+                }
+                Ok(#construct)
+            }
+
+            fn to_raw_key(&self) -> Result<::kv::Raw, ::kv::Error> {
+                let mut dst = Vec::new();
+                #(::kv::__private::encode_escaped(&::kv::Key::to_raw_key(&self.#members)?, &mut dst);)*
+                Ok(dst.into())
+            }
+        }
+    };
+
+    let tag = LitStr::new(&name.to_string(), name.span());
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kv::Key<'__kv> for #name #ty_generics #where_clause {
+            const TYPE_TAG: Option<&'static str> = Some(#tag);
+
+            #body
+        }
+    })
+}
+
+fn value(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+
+    let mut codec = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("kv") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("codec") {
+                let s: LitStr = meta.value()?.parse()?;
+                codec = Some(codec_path(&s)?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported kv attribute"))
+            }
+        })?;
+    }
+
+    let codec = match codec {
+        Some(c) => c,
+        None => {
+            return Err(Error::new_spanned(
+                name,
+                "missing codec, add #[kv(codec = \"...\")]",
+            ))
+        }
+    };
+
+    let codec_name = &codec.segments.last().unwrap().ident;
+    let tag = LitStr::new(&format!("{}({})", codec_name, name), name.span());
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kv::Value for #name #ty_generics #where_clause {
+            const TYPE_TAG: Option<&'static str> = Some(#tag);
+
+            fn to_raw_value(&self) -> Result<::kv::Raw, ::kv::Error> {
+                #codec::<Self>::encode(self)
+            }
+
+            fn from_raw_value(r: ::kv::Raw) -> Result<Self, ::kv::Error> {
+                #codec::<Self>::decode(&r)
+            }
+        }
+    })
+}
+
+fn codec_path(s: &LitStr) -> Result<Path, Error> {
+    let path = match s.value().as_str() {
+        "json" => parse_quote!(::kv::Json),
+        "msgpack" => parse_quote!(::kv::Msgpack),
+        "bincode" => parse_quote!(::kv::Bincode),
+        "lexpr" => parse_quote!(::kv::Lexpr),
+        _ => s.parse()?,
+    };
+    Ok(path)
+}
@@ -0,0 +1,324 @@
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use crate::pool::{Task, ThreadPool};
+use crate::{
+    Batch, Bucket, CompareAndSwapError, Error, Event, Item, Iter, Key, Prefix, Store, Transaction,
+    TransactionError, Value, Watch,
+};
+
+type ItemResult<K, V> = Result<Item<K, V>, Error>;
+
+/// Async wrapper around `Store`, blocking operations are run on a thread pool so they don't
+/// block the executor. Stores created using `AsyncStore::new` share a single pool
+///
+/// ```rust
+/// use kv::*;
+///
+/// # struct Unpark(std::thread::Thread);
+/// # impl std::task::Wake for Unpark {
+/// #     fn wake(self: std::sync::Arc<Self>) {
+/// #         self.0.unpark()
+/// #     }
+/// # }
+/// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+/// #     let mut f = std::pin::pin!(f);
+/// #     let waker = std::sync::Arc::new(Unpark(std::thread::current())).into();
+/// #     let mut cx = std::task::Context::from_waker(&waker);
+/// #     loop {
+/// #         if let std::task::Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+/// #             return x;
+/// #         }
+/// #         std::thread::park();
+/// #     }
+/// # }
+/// # block_on(async {
+/// let store = AsyncStore::new(Store::new(Config::new("./test/example-async")).unwrap());
+/// let bucket = store.bucket::<String, String>(Some("async")).await.unwrap();
+/// bucket.set("a".into(), "1".into()).await.unwrap();
+/// assert_eq!(bucket.get("a".into()).await.unwrap().unwrap(), "1");
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct AsyncStore {
+    store: Store,
+    pool: Arc<ThreadPool>,
+}
+
+/// Async wrapper around `Bucket`, created using `AsyncStore::bucket`
+pub struct AsyncBucket<K, V>
+where
+    K: 'static + Key<'static>,
+    V: Value,
+{
+    bucket: Bucket<'static, K, V>,
+    pool: Arc<ThreadPool>,
+}
+
+impl<K: 'static + Key<'static>, V: Value> Clone for AsyncBucket<K, V> {
+    fn clone(&self) -> Self {
+        AsyncBucket {
+            bucket: self.bucket.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+/// Async iterator over bucket keys and values, items are fetched on the thread pool
+///
+/// `futures::Stream` isn't implemented yet, so `StreamExt` and other stream combinators can't be
+/// used directly. Use `poll_next` with `futures::stream::poll_fn` to get a stream
+pub struct AsyncIter<K, V> {
+    iter: Arc<Mutex<Iter<K, V>>>,
+    pool: Arc<ThreadPool>,
+    pending: Option<Task<Option<ItemResult<K, V>>>>,
+}
+
+/// Async subscriber created using `AsyncBucket::watch_prefix`, see `AsyncIter` for using it as a
+/// `futures::Stream`
+pub struct AsyncWatch<K, V>(Watch<K, V>);
+
+impl AsyncStore {
+    /// Wrap `store` using a thread pool shared by every `AsyncStore` created this way, with one
+    /// worker thread for each available CPU
+    pub fn new(store: Store) -> AsyncStore {
+        static POOL: OnceLock<Arc<ThreadPool>> = OnceLock::new();
+        let pool = POOL.get_or_init(|| {
+            let threads = thread::available_parallelism().map_or(4, |n| n.get());
+            Arc::new(ThreadPool::new(threads))
+        });
+        AsyncStore {
+            store,
+            pool: pool.clone(),
+        }
+    }
+
+    /// Wrap `store` using a new pool of `threads` worker threads
+    pub fn with_threads(store: Store, threads: usize) -> AsyncStore {
+        AsyncStore {
+            store,
+            pool: Arc::new(ThreadPool::new(threads)),
+        }
+    }
+
+    /// Wrap `store` using the same thread pool as `other`
+    pub fn with_pool(store: Store, other: &AsyncStore) -> AsyncStore {
+        AsyncStore {
+            store,
+            pool: other.pool.clone(),
+        }
+    }
+
+    /// Get the underlying store
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Run `f` on the thread pool
+    pub async fn spawn<T, F>(&self, f: F) -> T
+    where
+        T: 'static + Send,
+        F: 'static + Send + FnOnce(&Store) -> T,
+    {
+        let store = self.store.clone();
+        self.pool.spawn(move || f(&store)).await
+    }
+
+    /// Open a new bucket
+    pub async fn bucket<K, V>(&self, name: Option<&str>) -> Result<AsyncBucket<K, V>, Error>
+    where
+        K: 'static + Send + Key<'static>,
+        V: 'static + Send + Value,
+    {
+        let name = name.map(String::from);
+        let bucket = self.spawn(move |s| s.bucket(name.as_deref())).await?;
+        Ok(AsyncBucket {
+            bucket,
+            pool: self.pool.clone(),
+        })
+    }
+
+    /// Remove a bucket from the store
+    pub async fn drop_bucket<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        let name = name.into();
+        self.spawn(move |s| s.drop_bucket(name)).await
+    }
+
+    /// Generate monotonic ID
+    pub async fn generate_id(&self) -> Result<u64, Error> {
+        self.spawn(|s| s.generate_id()).await
+    }
+
+    /// Remove expired keys from all buckets, returning the number of keys removed
+    pub async fn purge_expired(&self) -> Result<usize, Error> {
+        self.spawn(|s| s.purge_expired()).await
+    }
+}
+
+impl<K, V> AsyncBucket<K, V>
+where
+    K: 'static + Send + Key<'static>,
+    V: 'static + Send + Value,
+{
+    /// Get the underlying bucket
+    pub fn bucket(&self) -> &Bucket<'static, K, V> {
+        &self.bucket
+    }
+
+    /// Run `f` on the thread pool
+    pub async fn spawn<T, F>(&self, f: F) -> T
+    where
+        T: 'static + Send,
+        F: 'static + Send + FnOnce(&Bucket<'static, K, V>) -> T,
+    {
+        let bucket = self.bucket.clone();
+        self.pool.spawn(move || f(&bucket)).await
+    }
+
+    /// Returns true if the bucket contains the given key
+    pub async fn contains(&self, key: K) -> Result<bool, Error> {
+        self.spawn(move |b| b.contains(&key)).await
+    }
+
+    /// Get the value associated with the specified key
+    pub async fn get(&self, key: K) -> Result<Option<V>, Error> {
+        self.spawn(move |b| b.get(&key)).await
+    }
+
+    /// Set the value associated with the specified key to the provided value
+    pub async fn set(&self, key: K, value: V) -> Result<Option<V>, Error> {
+        self.spawn(move |b| b.set(&key, &value)).await
+    }
+
+    /// Set the value associated with the specified key, the key will be removed after `ttl`, see
+    /// `Bucket::set_with_ttl`
+    pub async fn set_with_ttl(&self, key: K, value: V, ttl: Duration) -> Result<Option<V>, Error> {
+        self.spawn(move |b| b.set_with_ttl(&key, &value, ttl)).await
+    }
+
+    /// Set the value associated with the specified key to the provided value, only if the existing
+    /// value matches the `old` parameter. When the values don't match, the inner error contains
+    /// the current value
+    pub async fn compare_and_swap(
+        &self,
+        key: K,
+        old: Option<V>,
+        value: Option<V>,
+    ) -> Result<Result<(), CompareAndSwapError<V>>, Error> {
+        self.spawn(move |b| b.compare_and_swap(&key, old.as_ref(), value.as_ref()))
+            .await
+    }
+
+    /// Remove the value associated with the specified key from the database
+    pub async fn remove(&self, key: K) -> Result<Option<V>, Error> {
+        self.spawn(move |b| b.remove(&key)).await
+    }
+
+    /// Apply batch update
+    pub async fn batch(&self, batch: Batch<K, V>) -> Result<(), Error> {
+        self.spawn(move |b| b.batch(batch)).await
+    }
+
+    /// Execute a transaction, `f` runs on the thread pool and may be called more than once
+    pub async fn transaction<A, E, F>(&self, f: F) -> Result<A, E>
+    where
+        A: 'static + Send,
+        E: 'static + Send + From<Error>,
+        F: 'static + Send + Fn(Transaction<K, V>) -> Result<A, TransactionError<E>>,
+    {
+        self.spawn(move |b| b.transaction(f)).await
+    }
+
+    /// Get an async iterator over keys/values
+    pub fn iter(&self) -> AsyncIter<K, V> {
+        AsyncIter::new(self.bucket.iter(), &self.pool)
+    }
+
+    /// Get an async iterator over keys/values in the specified range
+    pub fn iter_range(&self, a: &K, b: &K) -> Result<AsyncIter<K, V>, Error> {
+        Ok(AsyncIter::new(self.bucket.iter_range(a, b)?, &self.pool))
+    }
+
+    /// Get an async iterator over keys/values with the specified prefix
+    pub fn iter_prefix<P: Prefix<K>>(&self, a: &P) -> Result<AsyncIter<K, V>, Error> {
+        Ok(AsyncIter::new(self.bucket.iter_prefix(a)?, &self.pool))
+    }
+
+    /// Get updates when a key with the given prefix is changed
+    pub fn watch_prefix(&self, prefix: Option<&K>) -> Result<AsyncWatch<K, V>, Error> {
+        Ok(AsyncWatch(self.bucket.watch_prefix(prefix)?))
+    }
+
+    /// Flush to disk
+    pub async fn flush(&self) -> Result<usize, Error> {
+        self.spawn(|b| b.flush()).await
+    }
+
+    /// Remove all items
+    pub async fn clear(&self) -> Result<(), Error> {
+        self.spawn(|b| b.clear()).await
+    }
+}
+
+impl<K, V> AsyncIter<K, V>
+where
+    K: 'static + Send + Key<'static>,
+    V: 'static + Send + Value,
+{
+    fn new(iter: Iter<K, V>, pool: &Arc<ThreadPool>) -> AsyncIter<K, V> {
+        AsyncIter {
+            iter: Arc::new(Mutex::new(iter)),
+            pool: pool.clone(),
+            pending: None,
+        }
+    }
+
+    /// Poll for the next item, this has the same signature as `futures::Stream::poll_next` so it
+    /// can be used to implement `Stream` without adding a dependency to `kv`
+    pub fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<ItemResult<K, V>>> {
+        let this = &mut *self;
+        let task = this.pending.get_or_insert_with(|| {
+            let iter = this.iter.clone();
+            this.pool
+                .spawn(move || iter.lock().unwrap_or_else(PoisonError::into_inner).next())
+        });
+
+        let x = Pin::new(task).poll(cx);
+        if x.is_ready() {
+            this.pending = None;
+        }
+        x
+    }
+
+    /// Get the next item
+    pub async fn next(&mut self) -> Option<ItemResult<K, V>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<K, V> AsyncWatch<K, V>
+where
+    K: Key<'static>,
+    V: Value,
+{
+    /// Poll for the next event, this has the same signature as `futures::Stream::poll_next` so it
+    /// can be used to implement `Stream` without adding a dependency to `kv`
+    pub fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Event<K, V>, Error>>> {
+        Pin::new(&mut self.0).poll(cx).map(|x| x.map(Ok))
+    }
+
+    /// Wait for the next event, returns `None` when the bucket is dropped
+    pub async fn next(&mut self) -> Option<Result<Event<K, V>, Error>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
@@ -0,0 +1,782 @@
+//! Storage backends, `sled` is used by default and `MemoryBackend` keeps everything in memory.
+//!
+//! Implement `Backend` and `Tree` to store data somewhere else, then pass the backend to
+//! `Store::with_backend`.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError as SledTransactionError,
+    UnabortableTransactionError,
+};
+use sled::Transactional;
+
+use crate::memory::Map;
+use crate::{CompareAndSwapError, Error, MemoryBackend, Raw, TransactionError};
+
+/// Iterator over raw keys and values
+pub type RawIter = Box<dyn DoubleEndedIterator<Item = Result<(Raw, Raw), Error>> + Send>;
+
+/// Database export, see `Store::export`
+pub type Export = Vec<(Vec<u8>, Vec<u8>, Box<dyn Iterator<Item = Vec<Vec<u8>>>>)>;
+
+/// Function used by `Tree::merge`, it is passed the key, the current value and the value being
+/// merged, and returns the new value
+pub type MergeFn =
+    Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Result<Option<Raw>, Error> + Send + Sync>;
+
+/// Result of an operation on a `TransactionalTree`, errors are either
+/// `TransactionError::Conflict` or `TransactionError::Storage`
+pub type TransactionResult<T> = Result<T, TransactionError<Error>>;
+
+/// Function executed by `Tree::transaction`, it is passed one `TransactionalTree` for each tree
+/// and may be called more than once if there is a conflict
+pub type TransactionFn<'a> =
+    dyn Fn(&[&dyn TransactionalTree]) -> Result<(), TransactionError<()>> + 'a;
+
+/// A storage engine that can be used by `Store`
+pub trait Backend: Send + Sync {
+    /// Open or create the tree `name`
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn Tree>, Error>;
+
+    /// Remove the tree `name`, returns false if it doesn't exist
+    fn drop_tree(&self, name: &[u8]) -> Result<bool, Error>;
+
+    /// Get the names of all trees
+    fn tree_names(&self) -> Vec<Raw>;
+
+    /// Generate a monotonic ID
+    fn generate_id(&self) -> Result<u64, Error>;
+
+    /// Get a lower bound for IDs generated after this call without generating one, every ID
+    /// generated so far is lower
+    fn next_id(&self) -> Result<u64, Error>;
+
+    /// Make sure IDs generated after this call are at least `min`
+    fn reserve_ids(&self, min: u64) -> Result<(), Error>;
+
+    /// Flush all trees to disk
+    fn flush(&self) -> Result<usize, Error>;
+
+    /// Size on disk in bytes
+    fn size_on_disk(&self) -> Result<u64, Error>;
+
+    /// Export all trees
+    fn export(&self) -> Export;
+
+    /// Import trees from `export`, returns an error if a tree isn't a list of key/value pairs
+    fn import(&self, export: Export) -> Result<(), Error>;
+
+    /// Copy every tree into memory at a single point in time, no writes may be applied while the
+    /// copy is being made
+    fn copy_to_memory(&self) -> Result<Arc<dyn Backend>, Error>;
+}
+
+/// An ordered map of raw keys and values
+pub trait Tree: Send + Sync {
+    /// Get the value associated with `key`
+    fn get(&self, key: &[u8]) -> Result<Option<Raw>, Error>;
+
+    /// Returns true if the tree contains `key`
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Set `key` to `value`, returning the previous value
+    fn insert(&self, key: &[u8], value: Raw) -> Result<Option<Raw>, Error>;
+
+    /// Remove `key`, returning the previous value
+    fn remove(&self, key: &[u8]) -> Result<Option<Raw>, Error>;
+
+    /// Set `key` to `new` if the current value is `old`
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<Raw>,
+        new: Option<Raw>,
+    ) -> Result<Result<(), CompareAndSwapError<Raw>>, Error>;
+
+    /// Atomically replace the value of `key` with the result of `f`, returning the new value.
+    /// `f` may be called more than once if there are concurrent writes to `key`
+    fn merge(&self, key: &[u8], value: &[u8], f: &MergeFn) -> Result<Option<Raw>, Error>;
+
+    /// Iterate over a range of keys
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> RawIter;
+
+    /// Iterate over all keys
+    fn iter(&self) -> RawIter {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Iterate over all keys starting with `prefix`
+    fn scan_prefix(&self, prefix: &[u8]) -> RawIter {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range(Bound::Included(prefix.into()), end)
+    }
+
+    /// Get the first key and value
+    fn first(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        self.iter().next().transpose()
+    }
+
+    /// Get the last key and value
+    fn last(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        self.iter().next_back().transpose()
+    }
+
+    /// Remove and return the first key and value
+    fn pop_min(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        loop {
+            match self.first()? {
+                None => return Ok(None),
+                Some((k, v)) => {
+                    if self.compare_and_swap(&k, Some(v.clone()), None)?.is_ok() {
+                        return Ok(Some((k, v)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove and return the last key and value
+    fn pop_max(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        loop {
+            match self.last()? {
+                None => return Ok(None),
+                Some((k, v)) => {
+                    if self.compare_and_swap(&k, Some(v.clone()), None)?.is_ok() {
+                        return Ok(Some((k, v)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Atomically apply a list of inserts and removals
+    fn apply_batch(&self, batch: &[(Raw, Option<Raw>)]) -> Result<(), Error>;
+
+    /// Number of keys
+    fn len(&self) -> usize;
+
+    /// Returns true when the tree is empty
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Remove all keys
+    fn clear(&self) -> Result<(), Error>;
+
+    /// CRC32 checksum of all keys and values
+    fn checksum(&self) -> Result<u32, Error>;
+
+    /// Flush to disk
+    fn flush(&self) -> Result<usize, Error>;
+
+    /// Flush to disk asynchronously
+    fn flush_async(&self) -> Pin<Box<dyn Future<Output = Result<usize, Error>> + Send + '_>>;
+
+    /// Execute `f` in a transaction over `trees`, all of which must be from the same backend as
+    /// `self`. `f` is called again when it returns `TransactionError::Conflict` or the commit
+    /// conflicts with another write, so the only errors returned are `Abort` and `Storage`
+    fn transaction(
+        &self,
+        trees: &[&dyn Tree],
+        f: &TransactionFn,
+    ) -> Result<(), TransactionError<()>>;
+
+    /// Used to downcast to the concrete tree type
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A view of a tree inside of a transaction
+pub trait TransactionalTree {
+    /// Get the value associated with `key`
+    fn get(&self, key: &[u8]) -> TransactionResult<Option<Raw>>;
+
+    /// Set `key` to `value`, returning the previous value
+    fn insert(&self, key: &[u8], value: Raw) -> TransactionResult<Option<Raw>>;
+
+    /// Remove `key`, returning the previous value
+    fn remove(&self, key: &[u8]) -> TransactionResult<Option<Raw>>;
+
+    /// Generate a monotonic ID
+    fn generate_id(&self) -> TransactionResult<u64>;
+
+    /// Get every key and value in the given range, including writes made earlier in the
+    /// transaction. The range is part of the transaction's read set: the transaction must not
+    /// commit if any key in the range has been inserted, changed or removed by someone else since
+    /// it was read
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> TransactionResult<Vec<(Raw, Raw)>>;
+}
+
+/// Smallest key that is greater than all keys starting with `prefix`
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Raw> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end.into());
+        }
+    }
+    None
+}
+
+/// Returns true when no key can be in the range, `BTreeMap::range` panics on these
+pub(crate) fn is_empty_range(start: &Bound<Raw>, end: &Bound<Raw>) -> bool {
+    match (start, end) {
+        (Bound::Included(a), Bound::Included(b)) => a > b,
+        (Bound::Included(a), Bound::Excluded(b))
+        | (Bound::Excluded(a), Bound::Included(b))
+        | (Bound::Excluded(a), Bound::Excluded(b)) => a >= b,
+        _ => false,
+    }
+}
+
+/// Split an exported entry from `tree` into its key and value
+pub(crate) fn import_entry(tree: &[u8], kv: Vec<Vec<u8>>) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut kv = kv.into_iter();
+    match (kv.next(), kv.next(), kv.next()) {
+        (Some(key), Some(value), None) => Ok((key, value)),
+        _ => Err(Error::Message(format!(
+            "Invalid import entry in tree: {}",
+            String::from_utf8_lossy(tree)
+        ))),
+    }
+}
+
+/// Run `f` in a transaction over `trees`, passing through errors returned by `f`
+pub(crate) fn transaction<A, E, F>(trees: &[&dyn Tree], f: F) -> Result<A, E>
+where
+    E: From<Error>,
+    F: Fn(&[&dyn TransactionalTree]) -> Result<A, TransactionError<E>>,
+{
+    let output = RefCell::new(None);
+    let aborted = RefCell::new(None);
+
+    loop {
+        let result = trees[0].transaction(trees, &|t| match f(t) {
+            Ok(x) => {
+                *output.borrow_mut() = Some(x);
+                Ok(())
+            }
+            Err(TransactionError::Abort(e)) => {
+                *aborted.borrow_mut() = Some(e);
+                Err(TransactionError::Abort(()))
+            }
+            Err(TransactionError::Conflict) => Err(TransactionError::Conflict),
+            Err(TransactionError::Storage(e)) => Err(TransactionError::Storage(e)),
+        });
+
+        return match result {
+            Ok(()) => Ok(output.into_inner().expect("transaction output")),
+            Err(TransactionError::Abort(())) => Err(aborted.into_inner().expect("abort error")),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+            // Backends are expected to retry, but a conflict is never returned to the caller
+            Err(TransactionError::Conflict) => continue,
+        };
+    }
+}
+
+/// Held for reading by every write to a sled database, and for writing when other writers need to
+/// be blocked: sled iterators can't be used inside of a transaction, so ranges are scanned before
+/// the transaction is retried, and whole stores are copied into memory while holding it
+#[derive(Default)]
+struct WriteLock(RwLock<()>);
+
+impl WriteLock {
+    /// Lock for a single write. sled transactions block every other write until they finish, so
+    /// writing outside of the transaction from inside its closure returns an error instead of
+    /// deadlocking
+    fn shared(&self) -> Result<RwLockReadGuard<'_, ()>, Error> {
+        if SLED_TRANSACTION.with(Cell::get) {
+            return Err(Error::Message(
+                "sled trees can't be written to from inside of a transaction, use the transaction \
+                 instead"
+                    .into(),
+            ));
+        }
+        Ok(self.read())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Block all other writers
+    fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Marks the current thread as running a sled transaction until it's dropped
+struct TransactionScope(bool);
+
+impl TransactionScope {
+    fn enter() -> TransactionScope {
+        TransactionScope(SLED_TRANSACTION.with(|x| x.replace(true)))
+    }
+}
+
+impl Drop for TransactionScope {
+    fn drop(&mut self) {
+        SLED_TRANSACTION.with(|x| x.set(self.0))
+    }
+}
+
+/// `Backend` using a `sled::Db`, this is used by `Store::new`
+pub(crate) struct SledBackend {
+    db: sled::Db,
+    lock: Arc<WriteLock>,
+    ids: Arc<Ids>,
+}
+
+impl SledBackend {
+    pub(crate) fn new(db: sled::Db) -> SledBackend {
+        SledBackend {
+            db,
+            lock: Arc::default(),
+            ids: Arc::default(),
+        }
+    }
+}
+
+/// sled can't read its ID counter without incrementing it, so IDs generated by a `SledBackend`
+/// are tracked instead. Zero means no ID has been generated yet
+#[derive(Default)]
+struct Ids(AtomicU64);
+
+impl Ids {
+    /// Record that `id` was generated
+    fn generated(&self, id: u64) -> u64 {
+        self.0.fetch_max(id + 1, Ordering::SeqCst);
+        id
+    }
+}
+
+/// A tree in a `SledBackend`
+struct SledTree {
+    tree: sled::Tree,
+    lock: Arc<WriteLock>,
+    ids: Arc<Ids>,
+}
+
+thread_local! {
+    /// Set while a sled transaction is running on the current thread
+    static SLED_TRANSACTION: Cell<bool> = const { Cell::new(false) };
+
+    /// The function used by `sled_merge` and its error, if any
+    static SLED_MERGE: RefCell<Option<(MergeFn, Option<Error>)>> = const { RefCell::new(None) };
+}
+
+/// Merge operator set on every sled tree, it calls the `MergeFn` passed to `Tree::merge` on the
+/// same thread. On error the current value is kept and the error is returned by `Tree::merge`
+fn sled_merge(key: &[u8], old: Option<&[u8]>, value: &[u8]) -> Option<Vec<u8>> {
+    let f = SLED_MERGE.with(|merge| merge.borrow().as_ref().map(|(f, _)| f.clone()));
+    let result = match f {
+        Some(f) => f(key, old, value),
+        None => return old.map(|x| x.to_vec()),
+    };
+
+    // sled may call the operator more than once, only the last result is used
+    let (new, error) = match result {
+        Ok(new) => (new.map(|x| x.to_vec()), None),
+        Err(e) => (old.map(|x| x.to_vec()), Some(e)),
+    };
+    SLED_MERGE.with(|merge| {
+        if let Some((_, e)) = merge.borrow_mut().as_mut() {
+            *e = error;
+        }
+    });
+    new
+}
+
+/// A range and the keys it contained when it was scanned
+type ScannedRange = (Bound<Raw>, Bound<Raw>, Vec<Raw>);
+
+/// Ranges requested by a transaction that haven't been scanned yet, with the index of their tree
+type MissingRanges = RefCell<Vec<(usize, Bound<Raw>, Bound<Raw>)>>;
+
+impl Backend for SledBackend {
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn Tree>, Error> {
+        let tree = self.db.open_tree(name)?;
+        tree.set_merge_operator(sled_merge);
+        Ok(Arc::new(SledTree {
+            tree,
+            lock: self.lock.clone(),
+            ids: self.ids.clone(),
+        }))
+    }
+
+    fn drop_tree(&self, name: &[u8]) -> Result<bool, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.db.drop_tree(name)?)
+    }
+
+    fn tree_names(&self) -> Vec<Raw> {
+        self.db.tree_names()
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        Ok(self.ids.generated(self.db.generate_id()?))
+    }
+
+    fn next_id(&self) -> Result<u64, Error> {
+        match self.ids.0.load(Ordering::SeqCst) {
+            // Only the first call generates an ID, when none have been generated since the
+            // database was opened
+            0 => Ok(self.generate_id()? + 1),
+            next => Ok(next),
+        }
+    }
+
+    fn reserve_ids(&self, min: u64) -> Result<(), Error> {
+        // sled can't set its ID counter, so IDs are generated until it has caught up
+        while self.generate_id()? < min {}
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<usize, Error> {
+        Ok(self.db.flush()?)
+    }
+
+    fn size_on_disk(&self) -> Result<u64, Error> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn export(&self) -> Export {
+        self.db
+            .export()
+            .into_iter()
+            .map(|(t, n, iter)| {
+                let iter: Box<dyn Iterator<Item = Vec<Vec<u8>>>> = Box::new(iter);
+                (t, n, iter)
+            })
+            .collect()
+    }
+
+    fn import(&self, export: Export) -> Result<(), Error> {
+        let _guard = self.lock.shared()?;
+        for (_, name, data) in export {
+            let tree = self.db.open_tree(name)?;
+            for kv in data {
+                let (key, value) = import_entry(&tree.name(), kv)?;
+                tree.insert(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_to_memory(&self) -> Result<Arc<dyn Backend>, Error> {
+        // sled has no snapshots, so other writers are blocked until every tree has been copied
+        let _exclusive = self.lock.exclusive();
+        let mut trees = Vec::new();
+        for name in self.db.tree_names() {
+            let mut data = Map::new();
+            for item in self.db.open_tree(&name)?.iter() {
+                let (k, v) = item?;
+                data.insert(k, v);
+            }
+            trees.push((name, data));
+        }
+        Ok(Arc::new(MemoryBackend::from_trees(trees)))
+    }
+}
+
+impl Tree for SledTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Raw>, Error> {
+        Ok(self.tree.get(key)?)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.tree.contains_key(key)?)
+    }
+
+    fn insert(&self, key: &[u8], value: Raw) -> Result<Option<Raw>, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.tree.insert(key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Raw>, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.tree.remove(key)?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<Raw>,
+        new: Option<Raw>,
+    ) -> Result<Result<(), CompareAndSwapError<Raw>>, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self
+            .tree
+            .compare_and_swap(key, old, new)?
+            .map_err(|e| CompareAndSwapError::new(e.current, e.proposed)))
+    }
+
+    fn merge(&self, key: &[u8], value: &[u8], f: &MergeFn) -> Result<Option<Raw>, Error> {
+        let _guard = self.lock.shared()?;
+        SLED_MERGE.with(|merge| *merge.borrow_mut() = Some((f.clone(), None)));
+        let result = self.tree.merge(key, value);
+        let error = SLED_MERGE.with(|merge| merge.borrow_mut().take().and_then(|(_, e)| e));
+        match error {
+            Some(e) => Err(e),
+            None => Ok(result?),
+        }
+    }
+
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> RawIter {
+        let iter = self.tree.range::<Raw, _>((start, end));
+        Box::new(iter.map(|x| x.map_err(Error::from)))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> RawIter {
+        let iter = self.tree.scan_prefix(prefix);
+        Box::new(iter.map(|x| x.map_err(Error::from)))
+    }
+
+    fn first(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        Ok(self.tree.first()?)
+    }
+
+    fn last(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        Ok(self.tree.last()?)
+    }
+
+    fn pop_min(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.tree.pop_min()?)
+    }
+
+    fn pop_max(&self) -> Result<Option<(Raw, Raw)>, Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.tree.pop_max()?)
+    }
+
+    fn apply_batch(&self, batch: &[(Raw, Option<Raw>)]) -> Result<(), Error> {
+        let mut b = sled::Batch::default();
+        for (k, v) in batch {
+            match v {
+                Some(v) => b.insert(k, v),
+                None => b.remove(k),
+            }
+        }
+        let _guard = self.lock.shared()?;
+        self.tree.apply_batch(b)?;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        let _guard = self.lock.shared()?;
+        Ok(self.tree.clear()?)
+    }
+
+    fn checksum(&self) -> Result<u32, Error> {
+        Ok(self.tree.checksum()?)
+    }
+
+    fn flush(&self) -> Result<usize, Error> {
+        Ok(self.tree.flush()?)
+    }
+
+    fn flush_async(&self) -> Pin<Box<dyn Future<Output = Result<usize, Error>> + Send + '_>> {
+        Box::pin(async move { Ok(self.tree.flush_async().await?) })
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&dyn Tree],
+        f: &TransactionFn,
+    ) -> Result<(), TransactionError<()>> {
+        let unsupported = |msg: &str| Err(TransactionError::Storage(Error::Message(msg.into())));
+        let mut sled_trees = Vec::with_capacity(trees.len());
+        for t in trees {
+            match t.as_any().downcast_ref::<SledTree>() {
+                Some(t) if Arc::ptr_eq(&t.lock, &self.lock) => sled_trees.push(&t.tree),
+                _ => {
+                    return unsupported(
+                        "cannot use trees from multiple stores in the same transaction",
+                    )
+                }
+            }
+        }
+        if SLED_TRANSACTION.with(Cell::get) {
+            return unsupported("sled transactions can't be nested");
+        }
+        let _scope = TransactionScope::enter();
+
+        let mut scanned: Vec<Vec<ScannedRange>> = vec![Vec::new(); sled_trees.len()];
+        let mut exclusive = None;
+        loop {
+            let missing = RefCell::new(Vec::new());
+            let failed = RefCell::new(None);
+            let shared = exclusive.is_none().then(|| self.lock.read());
+            let result = sled_trees.as_slice().transaction(|t| {
+                missing.borrow_mut().clear();
+                let views: Vec<SledTransactionalTree> = sled_trees
+                    .iter()
+                    .zip(t.iter())
+                    .zip(&scanned)
+                    .enumerate()
+                    .map(|(index, ((_, view), scanned))| SledTransactionalTree {
+                        index,
+                        view,
+                        ids: &self.ids,
+                        scanned,
+                        missing: &missing,
+                        written: RefCell::default(),
+                    })
+                    .collect();
+                let dyn_views: Vec<&dyn TransactionalTree> =
+                    views.iter().map(|x| x as &dyn TransactionalTree).collect();
+                let result = f(&dyn_views);
+
+                // Never commit an attempt that read a range that hasn't been scanned, even if `f`
+                // ignored the error
+                if !missing.borrow().is_empty() {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(TransactionError::Abort(())) => {
+                        Err(ConflictableTransactionError::Abort(()))
+                    }
+                    Err(TransactionError::Conflict) => Err(ConflictableTransactionError::Conflict),
+                    Err(TransactionError::Storage(Error::Sled(e))) => {
+                        Err(ConflictableTransactionError::Storage(e))
+                    }
+                    // sled only returns its own storage errors, other errors abort the
+                    // transaction and are returned once it's done
+                    Err(TransactionError::Storage(e)) => {
+                        *failed.borrow_mut() = Some(e);
+                        Err(ConflictableTransactionError::Abort(()))
+                    }
+                }
+            });
+            drop(shared);
+
+            let missing = missing.into_inner();
+            if missing.is_empty() {
+                return match result {
+                    Ok(()) => Ok(()),
+                    Err(SledTransactionError::Abort(())) => match failed.into_inner() {
+                        Some(e) => Err(TransactionError::Storage(e)),
+                        None => Err(TransactionError::Abort(())),
+                    },
+                    Err(SledTransactionError::Storage(e)) => {
+                        Err(TransactionError::Storage(e.into()))
+                    }
+                };
+            }
+
+            // Block other writers until the transaction has been committed, then scan the
+            // requested ranges and try again
+            if exclusive.is_none() {
+                exclusive = Some(self.lock.exclusive());
+            }
+            for (index, start, end) in missing {
+                let mut keys = Vec::new();
+                for item in sled_trees[index].range::<Raw, _>((start.clone(), end.clone())) {
+                    keys.push(item.map_err(|e| TransactionError::Storage(e.into()))?.0);
+                }
+                scanned[index].push((start, end, keys));
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Convert an error returned by a `sled::transaction::TransactionalTree`
+fn unabortable(e: UnabortableTransactionError) -> TransactionError<Error> {
+    match e {
+        UnabortableTransactionError::Conflict => TransactionError::Conflict,
+        UnabortableTransactionError::Storage(e) => TransactionError::Storage(e.into()),
+    }
+}
+
+/// A `sled::Tree` inside of a transaction, sled doesn't support iterating over transactional
+/// trees so ranges are scanned before the transaction starts and merged with the keys written by
+/// the transaction
+struct SledTransactionalTree<'a> {
+    index: usize,
+    view: &'a sled::transaction::TransactionalTree,
+    ids: &'a Ids,
+    scanned: &'a [ScannedRange],
+    missing: &'a MissingRanges,
+    written: RefCell<BTreeSet<Raw>>,
+}
+
+impl<'a> TransactionalTree for SledTransactionalTree<'a> {
+    fn get(&self, key: &[u8]) -> TransactionResult<Option<Raw>> {
+        self.view.get(key).map_err(unabortable)
+    }
+
+    fn insert(&self, key: &[u8], value: Raw) -> TransactionResult<Option<Raw>> {
+        self.written.borrow_mut().insert(key.into());
+        self.view.insert(key, value).map_err(unabortable)
+    }
+
+    fn remove(&self, key: &[u8]) -> TransactionResult<Option<Raw>> {
+        self.written.borrow_mut().insert(key.into());
+        self.view.remove(key).map_err(unabortable)
+    }
+
+    fn generate_id(&self) -> TransactionResult<u64> {
+        let id = self
+            .view
+            .generate_id()
+            .map_err(|e| TransactionError::Storage(e.into()))?;
+        Ok(self.ids.generated(id))
+    }
+
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> TransactionResult<Vec<(Raw, Raw)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+
+        let scanned = self
+            .scanned
+            .iter()
+            .find(|(a, b, _)| a == &start && b == &end);
+        let mut keys: BTreeSet<Raw> = match scanned {
+            Some((_, _, keys)) => keys.iter().cloned().collect(),
+            None => {
+                // Abort this attempt, the range is scanned before the transaction is retried
+                self.missing.borrow_mut().push((self.index, start, end));
+                return Err(TransactionError::Storage(Error::Message(
+                    "range has not been scanned".into(),
+                )));
+            }
+        };
+        keys.extend(self.written.borrow().range((start, end)).cloned());
+
+        let mut items = Vec::with_capacity(keys.len());
+        for k in keys {
+            if let Some(v) = self.get(&k)? {
+                items.push((k, v));
+            }
+        }
+        Ok(items)
+    }
+}
@@ -0,0 +1,200 @@
+//! Backup file format
+//!
+//! All integers are big-endian. A backup starts with a header:
+//!
+//! ```text
+//! magic: b"KVBACKUP"
+//! version: u32
+//! ```
+//!
+//! followed by a list of records, each starting with a one byte tag:
+//!
+//! ```text
+//! b'I' next_id: u64                   the store's ID counter, restored IDs are all lower
+//! b'T' name_len: u32, name            start of a tree, entries belong to the last tree
+//! b'E' key_len: u32, key, value_len: u32, value
+//! b'Z' entries: u64, crc32: u32       end of the backup
+//! ```
+//!
+//! The CRC32 checksum covers every byte before it, including the `b'Z'` tag and entry count.
+//! Every tree is included, so hidden metadata such as expiry times and indexes is preserved.
+//! Version 1 backups have no `b'I'` record.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::backend::Backend;
+use crate::{Error, Raw};
+
+const MAGIC: &[u8; 8] = b"KVBACKUP";
+const VERSION: u32 = 2;
+
+const ID: u8 = b'I';
+const TREE: u8 = b'T';
+const ENTRY: u8 = b'E';
+const END: u8 = b'Z';
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidBackup(msg.to_string())
+}
+
+/// Writer that computes the checksum of everything written
+struct Writer<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.hasher.update(data);
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len = u32::try_from(data.len()).map_err(|_| invalid("entry is too large"))?;
+        self.write(&len.to_be_bytes())?;
+        self.write(data)
+    }
+}
+
+/// Reader that computes the checksum of everything read
+struct Reader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Reader<R> {
+    fn read<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_bytes(&mut self) -> Result<Raw, Error> {
+        let len = u32::from_be_bytes(self.read()?) as usize;
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(invalid("unexpected end of file"));
+        }
+        self.hasher.update(&buf);
+        Ok(buf.into())
+    }
+}
+
+/// Write all trees in `backend` to `w`, returning the number of entries written
+pub(crate) fn backup<W: Write>(backend: &dyn Backend, w: W) -> Result<u64, Error> {
+    let mut w = Writer {
+        inner: w,
+        hasher: crc32fast::Hasher::new(),
+    };
+    w.write(MAGIC)?;
+    w.write(&VERSION.to_be_bytes())?;
+    w.write(&[ID])?;
+    w.write(&backend.next_id()?.to_be_bytes())?;
+
+    let mut count = 0u64;
+    for name in backend.tree_names() {
+        let tree = backend.open_tree(&name)?;
+        w.write(&[TREE])?;
+        w.write_bytes(&name)?;
+        for item in tree.iter() {
+            let (k, v) = item?;
+            w.write(&[ENTRY])?;
+            w.write_bytes(&k)?;
+            w.write_bytes(&v)?;
+            count += 1;
+        }
+    }
+
+    w.write(&[END])?;
+    w.write(&count.to_be_bytes())?;
+    let crc = w.hasher.clone().finalize();
+    w.inner.write_all(&crc.to_be_bytes())?;
+    w.inner.flush()?;
+    Ok(count)
+}
+
+/// A record read from a backup
+enum Record {
+    Id(u64),
+    Tree(Raw),
+    Entry(Raw, Raw),
+}
+
+/// Read the backup in `r`, passing each record to `f`. Returns the number of entries once the
+/// checksum and entry count have been verified
+fn read<R: Read, F: FnMut(Record) -> Result<(), Error>>(r: R, mut f: F) -> Result<u64, Error> {
+    let mut r = Reader {
+        inner: r,
+        hasher: crc32fast::Hasher::new(),
+    };
+    if &r.read::<8>()? != MAGIC {
+        return Err(invalid("not a kv backup"));
+    }
+    let version = u32::from_be_bytes(r.read()?);
+    if version == 0 || version > VERSION {
+        return Err(Error::InvalidBackup(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+
+    let mut has_tree = false;
+    let mut count = 0u64;
+    loop {
+        match r.read::<1>()?[0] {
+            ID => f(Record::Id(u64::from_be_bytes(r.read()?)))?,
+            TREE => {
+                has_tree = true;
+                f(Record::Tree(r.read_bytes()?))?
+            }
+            ENTRY => {
+                let k = r.read_bytes()?;
+                let v = r.read_bytes()?;
+                if !has_tree {
+                    return Err(invalid("entry before tree"));
+                }
+                f(Record::Entry(k, v))?;
+                count += 1;
+            }
+            END => break,
+            _ => return Err(invalid("unknown record")),
+        }
+    }
+
+    let expected = u64::from_be_bytes(r.read()?);
+    let crc = r.hasher.clone().finalize();
+    let mut buf = [0; 4];
+    r.inner.read_exact(&mut buf)?;
+    if u32::from_be_bytes(buf) != crc {
+        return Err(invalid("checksum mismatch"));
+    }
+    if expected != count {
+        return Err(invalid("entry count mismatch"));
+    }
+    Ok(count)
+}
+
+/// Restore a backup written by `backup` into `backend`, returning the number of entries restored.
+/// The whole backup is verified before anything is written
+pub(crate) fn restore<R: Read + Seek>(backend: &dyn Backend, mut r: R) -> Result<u64, Error> {
+    let start = r.stream_position()?;
+    read(&mut r, |_| Ok(()))?;
+    r.seek(SeekFrom::Start(start))?;
+
+    let mut tree = None;
+    read(r, |record| {
+        match record {
+            Record::Id(id) => backend.reserve_ids(id)?,
+            Record::Tree(name) => tree = Some(backend.open_tree(&name)?),
+            Record::Entry(k, v) => {
+                if let Some(t) = &tree {
+                    t.insert(&k, v)?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,391 @@
+//! Command line tool for inspecting and editing `kv` stores
+
+use std::io::{self, Write};
+use std::process;
+
+use kv::{Bucket, Config, Error, Integer, Raw, Store, I64, U64};
+
+const USAGE: &str = "Usage: kv (--path DIR | --config FILE) [OPTIONS] COMMAND [ARGS]
+
+Commands:
+    buckets                 List buckets
+    get KEY                 Print the value associated with KEY
+    set KEY VALUE           Set KEY to VALUE
+    rm KEY                  Remove KEY
+    scan                    Print all keys and values, see --prefix, --range and --limit
+    count                   Print the number of keys in the bucket
+    checksum                Print the CRC32 checksum of the bucket
+    size                    Print the size of the store on disk
+    drop-bucket NAME        Remove the bucket NAME
+
+Options:
+    -p, --path DIR          Open the store at DIR
+    -c, --config FILE       Load the store configuration from FILE
+    -b, --bucket NAME       Bucket to use, defaults to the default bucket
+    -k, --key-format FMT    Key format, defaults to utf8
+    -f, --format FMT        Value format, defaults to utf8
+        --prefix PREFIX     Only scan keys starting with PREFIX
+        --range START END   Only scan keys from START up to, but not including, END
+        --limit N           Stop scanning after N items
+    -h, --help              Print this message
+    --                      Treat the remaining arguments as the command, e.g. for negative numbers
+
+Formats: raw, hex, utf8, int, i64, u64, json, msgpack, bincode, lexpr
+`int` is the 128-bit `Integer` type, `i64` and `u64` are the `I64` and `U64` key types
+Encoded values are read and printed as JSON, codecs other than json require the matching feature";
+
+/// Encoding used to convert keys and values to and from command line arguments
+#[derive(Clone, Copy)]
+enum Format {
+    Raw,
+    Hex,
+    Utf8,
+    Int,
+    I64,
+    U64,
+    Json,
+    #[cfg(feature = "msgpack-value")]
+    Msgpack,
+    #[cfg(feature = "bincode-value")]
+    Bincode,
+    #[cfg(feature = "lexpr-value")]
+    Lexpr,
+}
+
+impl Format {
+    fn parse(s: &str) -> Result<Format, Error> {
+        let format = match s {
+            "raw" => Format::Raw,
+            "hex" => Format::Hex,
+            "utf8" => Format::Utf8,
+            "int" => Format::Int,
+            "i64" => Format::I64,
+            "u64" => Format::U64,
+            "json" => Format::Json,
+            #[cfg(feature = "msgpack-value")]
+            "msgpack" => Format::Msgpack,
+            #[cfg(feature = "bincode-value")]
+            "bincode" => Format::Bincode,
+            #[cfg(feature = "lexpr-value")]
+            "lexpr" => Format::Lexpr,
+            _ => return Err(Error::Message(format!("Unsupported format: {}", s))),
+        };
+        Ok(format)
+    }
+
+    /// Convert a command line argument to the stored representation
+    fn encode(self, s: &str) -> Result<Raw, Error> {
+        let raw = match self {
+            Format::Raw | Format::Utf8 => Raw::from(s),
+            Format::Hex => Raw::from(from_hex(s)?),
+            Format::Int => Raw::from(Integer::from(parse_int::<u128>(s)?).as_ref()),
+            Format::I64 => Raw::from(I64::from(parse_int::<i64>(s)?).as_ref()),
+            Format::U64 => Raw::from(U64::from(parse_int::<u64>(s)?).as_ref()),
+            Format::Json => kv::Json::<serde_json::Value>::encode(&parse_json(s)?)?,
+            #[cfg(feature = "msgpack-value")]
+            Format::Msgpack => kv::Msgpack::<serde_json::Value>::encode(&parse_json(s)?)?,
+            #[cfg(feature = "bincode-value")]
+            Format::Bincode => kv::Bincode::<serde_json::Value>::encode(&parse_json(s)?)?,
+            #[cfg(feature = "lexpr-value")]
+            Format::Lexpr => kv::Lexpr::<serde_json::Value>::encode(&parse_json(s)?)?,
+        };
+        Ok(raw)
+    }
+
+    /// Convert a stored key or value to the bytes that are printed
+    fn decode(self, raw: &Raw) -> Result<Vec<u8>, Error> {
+        let value = match self {
+            Format::Raw => return Ok(raw.to_vec()),
+            Format::Utf8 => return Ok(std::str::from_utf8(raw)?.as_bytes().to_vec()),
+            Format::Hex => return Ok(to_hex(raw).into_bytes()),
+            Format::Int => {
+                return Ok(u128::from(Integer::try_from(raw.as_ref())?)
+                    .to_string()
+                    .into_bytes())
+            }
+            Format::I64 => {
+                return Ok(i64::from(I64::try_from(raw.as_ref())?)
+                    .to_string()
+                    .into_bytes())
+            }
+            Format::U64 => {
+                return Ok(u64::from(U64::try_from(raw.as_ref())?)
+                    .to_string()
+                    .into_bytes())
+            }
+            Format::Json => kv::Json::<serde_json::Value>::decode(raw)?,
+            #[cfg(feature = "msgpack-value")]
+            Format::Msgpack => kv::Msgpack::<serde_json::Value>::decode(raw)?,
+            #[cfg(feature = "bincode-value")]
+            Format::Bincode => return Err(Error::Message(
+                "bincode values are not self-describing and can't be decoded without their type"
+                    .into(),
+            )),
+            #[cfg(feature = "lexpr-value")]
+            Format::Lexpr => kv::Lexpr::<serde_json::Value>::decode(raw)?,
+        };
+        Ok(serde_json::to_vec(&value)?)
+    }
+}
+
+fn parse_int<T: std::str::FromStr>(s: &str) -> Result<T, Error> {
+    s.parse()
+        .map_err(|_| Error::Message(format!("Invalid integer: {}", s)))
+}
+
+fn parse_json(s: &str) -> Result<serde_json::Value, Error> {
+    Ok(serde_json::from_str(s)?)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::Message(format!("Invalid hex: {}", s));
+    if s.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+/// Parsed command line arguments
+struct Args {
+    config: Option<Config>,
+    bucket: Option<String>,
+    key_format: Format,
+    format: Format,
+    prefix: Option<String>,
+    range: Option<(String, String)>,
+    limit: Option<usize>,
+    command: Vec<String>,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, Error> {
+        let mut parsed = Args {
+            config: None,
+            bucket: None,
+            key_format: Format::Utf8,
+            format: Format::Utf8,
+            prefix: None,
+            range: None,
+            limit: None,
+            command: Vec::new(),
+        };
+
+        let value = |args: &mut I, name: &str| {
+            args.next()
+                .ok_or_else(|| Error::Message(format!("Missing value for {}", name)))
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-p" | "--path" => parsed.config = Some(Config::new(value(&mut args, &arg)?)),
+                "-c" | "--config" => parsed.config = Some(Config::load(value(&mut args, &arg)?)?),
+                "-b" | "--bucket" => parsed.bucket = Some(value(&mut args, &arg)?),
+                "-k" | "--key-format" => {
+                    parsed.key_format = Format::parse(&value(&mut args, &arg)?)?
+                }
+                "-f" | "--format" => parsed.format = Format::parse(&value(&mut args, &arg)?)?,
+                "--prefix" => parsed.prefix = Some(value(&mut args, &arg)?),
+                "--range" => {
+                    let start = value(&mut args, &arg)?;
+                    parsed.range = Some((start, value(&mut args, &arg)?));
+                }
+                "--limit" => {
+                    let n = value(&mut args, &arg)?;
+                    parsed.limit = Some(
+                        n.parse()
+                            .map_err(|_| Error::Message(format!("Invalid limit: {}", n)))?,
+                    );
+                }
+                "--" => parsed.command.extend(&mut args),
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    return Err(Error::Message(format!("Unknown option: {}", arg)))
+                }
+                _ => parsed.command.push(arg),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn open(args: &Args) -> Result<Store, Error> {
+    match args.config.clone() {
+        Some(config) => Store::new(config),
+        None => Err(Error::Message(
+            "Either --path or --config is required".into(),
+        )),
+    }
+}
+
+fn run<W: Write>(args: &Args, store: &Store, out: &mut W) -> Result<(), Error> {
+    let key = |s: &str| args.key_format.encode(s);
+
+    // Only `set` may create the bucket, other commands fail if it doesn't exist
+    let bucket = || match &args.bucket {
+        Some(name) if !store.buckets().contains(name) => {
+            Err(Error::Message(format!("Bucket not found: {}", name)))
+        }
+        name => store.bucket::<Raw, Raw>(name.as_deref()),
+    };
+
+    let command: Vec<&str> = args.command.iter().map(String::as_str).collect();
+    match command.as_slice() {
+        ["buckets"] => {
+            for name in store.buckets() {
+                writeln!(out, "{}", name)?;
+            }
+        }
+        ["get", k] => match bucket()?.get(&key(k)?)? {
+            Some(v) => {
+                out.write_all(&args.format.decode(&v)?)?;
+                writeln!(out)?;
+            }
+            None => return Err(Error::Message(format!("Key not found: {}", k))),
+        },
+        ["set", k, v] => {
+            store
+                .bucket::<Raw, Raw>(args.bucket.as_deref())?
+                .set(&key(k)?, &args.format.encode(v)?)?;
+        }
+        ["rm", k] => {
+            if bucket()?.remove(&key(k)?)?.is_none() {
+                return Err(Error::Message(format!("Key not found: {}", k)));
+            }
+        }
+        ["scan"] => scan(args, &bucket()?, out)?,
+        ["count"] => writeln!(out, "{}", bucket()?.len())?,
+        ["checksum"] => writeln!(out, "{:08x}", bucket()?.checksum()?)?,
+        ["size"] => writeln!(out, "{}", store.size_on_disk()?)?,
+        ["drop-bucket", name] => store.drop_bucket(name)?,
+        _ => return Err(Error::Message(format!("Invalid command\n\n{}", USAGE))),
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+fn scan<W: Write>(args: &Args, bucket: &Bucket<Raw, Raw>, out: &mut W) -> Result<(), Error> {
+    let iter = match (&args.prefix, &args.range) {
+        (Some(_), Some(_)) => {
+            return Err(Error::Message(
+                "--prefix and --range can't be used together".into(),
+            ))
+        }
+        (Some(prefix), None) => bucket.iter_prefix(&args.key_format.encode(prefix)?)?,
+        (None, Some((start, end))) => bucket.iter_range(
+            &args.key_format.encode(start)?,
+            &args.key_format.encode(end)?,
+        )?,
+        (None, None) => bucket.iter(),
+    };
+
+    for item in iter.take(args.limit.unwrap_or(usize::MAX)) {
+        let item = item?;
+        out.write_all(&args.key_format.decode(&item.key()?)?)?;
+        out.write_all(b"\t")?;
+        out.write_all(&args.format.decode(&item.value()?)?)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|x| x == "-h" || x == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let result = Args::parse(args.into_iter()).and_then(|args| {
+        let store = open(&args)?;
+        run(&args, &store, &mut io::stdout().lock())
+    });
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kv(store: &Store, args: &[&str]) -> Result<String, Error> {
+        let args = Args::parse(args.iter().map(|x| x.to_string()))?;
+        let mut out = Vec::new();
+        run(&args, store, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_cli() {
+        let path = "./test/cli";
+        let _ = std::fs::remove_dir_all(path);
+        let store = &Store::new(Config::new(path).temporary(true)).unwrap();
+
+        kv(store, &["-b", "a", "set", "x", "1"]).unwrap();
+        kv(store, &["-b", "a", "set", "y", "2"]).unwrap();
+        kv(store, &["-b", "a", "-f", "json", "set", "z", "{\"n\":3}"]).unwrap();
+        assert_eq!(kv(store, &["-b", "a", "get", "x"]).unwrap(), "1\n");
+        assert_eq!(
+            kv(store, &["-b", "a", "-f", "json", "get", "z"]).unwrap(),
+            "{\"n\":3}\n"
+        );
+        assert_eq!(
+            kv(store, &["-b", "a", "-f", "hex", "get", "y"]).unwrap(),
+            "32\n"
+        );
+        assert_eq!(kv(store, &["-b", "a", "count"]).unwrap(), "3\n");
+        assert!(kv(store, &["buckets"]).unwrap().lines().any(|x| x == "a"));
+
+        // Scan output can be fed back into `set`
+        let scan = kv(
+            store,
+            &["-b", "a", "-f", "hex", "--range", "x", "z", "scan"],
+        )
+        .unwrap();
+        assert_eq!(scan, "x\t31\ny\t32\n");
+        for line in scan.lines() {
+            let (k, v) = line.split_once('\t').unwrap();
+            kv(store, &["-b", "b", "-f", "hex", "set", k, v]).unwrap();
+        }
+        assert_eq!(
+            kv(store, &["-b", "b", "--prefix", "y", "scan"]).unwrap(),
+            "y\t2\n"
+        );
+        assert_eq!(kv(store, &["-b", "a", "checksum"]).unwrap().trim().len(), 8);
+
+        for (format, n) in [
+            ("int", "340"),
+            ("i64", "-5"),
+            ("u64", "18446744073709551615"),
+        ] {
+            let set = ["-b", format, "-k", format, "-f", format, "--", "set", n, n];
+            kv(store, &set).unwrap();
+            let scan = kv(store, &["-b", format, "-k", format, "-f", format, "scan"]).unwrap();
+            assert_eq!(scan, format!("{}\t{}\n", n, n));
+        }
+        assert!(kv(store, &["-k", "i64", "get", "x"]).is_err());
+
+        // Read-only commands don't create buckets
+        assert!(kv(store, &["-b", "missing", "get", "x"]).is_err());
+        assert!(kv(store, &["-b", "missing", "scan"]).is_err());
+        assert!(!kv(store, &["buckets"]).unwrap().contains("missing"));
+
+        kv(store, &["-b", "a", "rm", "x"]).unwrap();
+        assert!(kv(store, &["-b", "a", "get", "x"]).is_err());
+        kv(store, &["drop-bucket", "a"]).unwrap();
+        assert!(!kv(store, &["buckets"]).unwrap().lines().any(|x| x == "a"));
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::backend::Tree;
+use crate::{
+    Batch, Bucket, Buckets, Error, Key, Raw, Store, TransactionError, Transactions, Value,
+};
+
+/// Size of each chunk written by `BlobBucket::put_blob`
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Manifests start with this header followed by the big-endian blob id, length, chunk size,
+/// chunk count and checksum
+const HEADER: [u8; 2] = [0xff, b'b'];
+const MANIFEST_LEN: usize = HEADER.len() + 32;
+
+/// Ids of the blobs in a bucket whose chunks are being written, shared by every handle to the
+/// bucket opened from the same `Store` so `BlobBucket::sweep` doesn't remove them
+pub(crate) type Pending = Mutex<HashSet<u64>>;
+
+// The chunk bucket maps blob id + chunk index => chunk, each blob gets a new id so the chunks of
+// the value being replaced stay readable until the new manifest is written
+fn chunk_key(id: u64, index: u64) -> Raw {
+    let mut dst = [0; 16];
+    dst[..8].copy_from_slice(&id.to_be_bytes());
+    dst[8..].copy_from_slice(&index.to_be_bytes());
+    Raw::from(&dst[..])
+}
+
+/// Blob id of a key in the chunk bucket
+fn chunk_id(key: &[u8]) -> Option<u64> {
+    let id = key.get(..8)?;
+    Some(u64::from_be_bytes(id.try_into().ok()?))
+}
+
+/// Read from `r` until `buf` is full or the end of the input is reached
+fn fill<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Manifest of a blob stored using `BlobBucket::put_blob`, the contents are split into
+/// fixed-size chunks stored separately. `Blob` isn't a `Value`: manifests are only written by
+/// `put_blob`, each with a new id, so no two keys ever share chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    id: u64,
+    len: u64,
+    chunk_size: u32,
+    chunks: u64,
+    checksum: u32,
+}
+
+impl Blob {
+    /// Length of the contents in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true when the blob is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of chunks used to store the contents
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    /// CRC32 checksum of the contents
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Keys of the chunks in the chunk bucket
+    fn chunk_keys(&self) -> impl Iterator<Item = Raw> + '_ {
+        (0..self.chunks).map(|i| chunk_key(self.id, i))
+    }
+}
+
+/// Encoded form of a `Blob`, stored at the blob's key
+pub(crate) struct Manifest(pub(crate) Blob);
+
+impl Value for Manifest {
+    const TYPE_TAG: Option<&'static str> = Some("Blob");
+
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        let blob = &self.0;
+        let mut dst = Vec::with_capacity(MANIFEST_LEN);
+        dst.extend_from_slice(&HEADER);
+        dst.extend_from_slice(&blob.id.to_be_bytes());
+        dst.extend_from_slice(&blob.len.to_be_bytes());
+        dst.extend_from_slice(&blob.chunk_size.to_be_bytes());
+        dst.extend_from_slice(&blob.chunks.to_be_bytes());
+        dst.extend_from_slice(&blob.checksum.to_be_bytes());
+        Ok(dst.into())
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        let x = match r.strip_prefix(&HEADER[..]) {
+            Some(x) if r.len() == MANIFEST_LEN => x,
+            _ => return Err(Error::Message("Invalid blob manifest".into())),
+        };
+        let u64_at = |i: usize| {
+            let mut dst = [0; 8];
+            dst.copy_from_slice(&x[i..i + 8]);
+            u64::from_be_bytes(dst)
+        };
+        let u32_at = |i: usize| {
+            let mut dst = [0; 4];
+            dst.copy_from_slice(&x[i..i + 4]);
+            u32::from_be_bytes(dst)
+        };
+        let blob = Blob {
+            id: u64_at(0),
+            len: u64_at(8),
+            chunk_size: u32_at(16),
+            chunks: u64_at(20),
+            checksum: u32_at(28),
+        };
+        if blob.chunk_size == 0 || blob.chunks != blob.len.div_ceil(u64::from(blob.chunk_size)) {
+            return Err(Error::Message("Invalid blob manifest".into()));
+        }
+        Ok(Manifest(blob))
+    }
+}
+
+/// Bucket storing large values as blobs, which are written and read as streams.
+///
+/// Manifests are stored in the bucket `name`, and the contents of each blob are split into
+/// fixed-size chunks stored in the bucket `<name>.chunks`. The chunks of a blob are removed in
+/// the same transaction as its key when it's replaced or removed. Chunks written by a `put_blob`
+/// that was interrupted, e.g. by a crash, are never referenced by a key, use `sweep` to remove
+/// them
+///
+/// ```rust
+/// use std::io::{Read, Seek, SeekFrom};
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-blob").temporary(true))?;
+/// let files = BlobBucket::<&str>::new(&store, "files")?;
+/// let blob = files.put_blob(&"hello.txt", &b"hello, world"[..])?;
+/// assert_eq!(blob.len(), 12);
+///
+/// let mut reader = files.get_blob(&"hello.txt")?.unwrap();
+/// reader.seek(SeekFrom::Start(7))?;
+/// let mut s = String::new();
+/// reader.read_to_string(&mut s)?;
+/// assert_eq!(s, "world");
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlobBucket<'a, K: Key<'a>> {
+    store: Store,
+    manifests: Bucket<'a, K, Manifest>,
+    chunks: Bucket<'a, Raw, Raw>,
+    pending: Arc<Pending>,
+}
+
+impl<'a, K: Key<'a>> Clone for BlobBucket<'a, K> {
+    fn clone(&self) -> Self {
+        BlobBucket {
+            store: self.store.clone(),
+            manifests: self.manifests.clone(),
+            chunks: self.chunks.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+/// Removes a blob id from the pending set when the write finishes, whether or not it succeeded
+struct PendingGuard<'b>(&'b Pending, u64);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.remove(&self.1);
+    }
+}
+
+impl<'a, K: Key<'a>> BlobBucket<'a, K> {
+    /// Open the blobs stored in the bucket `name`
+    pub fn new(store: &Store, name: &str) -> Result<BlobBucket<'a, K>, Error> {
+        Ok(BlobBucket {
+            store: store.clone(),
+            manifests: store.bucket(Some(name))?,
+            chunks: store.bucket(Some(&format!("{}.chunks", name)))?,
+            pending: store.pending_blobs(name.as_bytes()),
+        })
+    }
+
+    /// Store the contents of `r` as a blob, returning its manifest. The contents are streamed
+    /// into new chunks, then the manifest is stored at `key` and the chunks of any blob it
+    /// replaces are removed in a single transaction
+    pub fn put_blob<R: Read>(&self, key: &K, r: R) -> Result<Blob, Error> {
+        let id = self.store.generate_id()?;
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id);
+        let _guard = PendingGuard(&self.pending, id);
+
+        let blob = match self.write(id, r) {
+            Ok(blob) => blob,
+            Err((e, blob)) => {
+                self.forget(&blob)?;
+                return Err(e);
+            }
+        };
+
+        let manifest = Manifest(blob.clone());
+        let stored = self.transaction(|t| {
+            if let Some(Manifest(old)) = t.bucket(&self.manifests)?.set(key, &manifest)? {
+                let chunks = t.bucket(&self.chunks)?;
+                for k in old.chunk_keys() {
+                    chunks.remove(&k)?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = stored {
+            self.forget(&blob)?;
+            return Err(e);
+        }
+        Ok(blob)
+    }
+
+    /// Run `f` in a transaction over the manifests and chunks
+    fn transaction<A, F>(&self, f: F) -> Result<A, Error>
+    where
+        F: Fn(&Transactions) -> Result<A, TransactionError<Error>>,
+    {
+        let buckets = Buckets::new().with(&self.manifests).with(&self.chunks);
+        self.store.transaction(&buckets, f)
+    }
+
+    /// Write the contents of `r` to new chunks for the blob `id`, on error the chunks written so
+    /// far are returned along with it
+    fn write<R: Read>(&self, id: u64, mut r: R) -> Result<Blob, (Error, Blob)> {
+        let mut blob = Blob {
+            id,
+            len: 0,
+            chunk_size: CHUNK_SIZE,
+            chunks: 0,
+            checksum: 0,
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; CHUNK_SIZE as usize];
+        loop {
+            let n = match fill(&mut r, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => return Err((Error::IO(e), blob)),
+            };
+
+            hasher.update(&buf[..n]);
+            let key = chunk_key(blob.id, blob.chunks);
+            if let Err(e) = self.chunks.set(&key, &Raw::from(&buf[..n])) {
+                return Err((e, blob));
+            }
+            blob.chunks += 1;
+            blob.len += n as u64;
+        }
+        blob.checksum = hasher.finalize();
+        Ok(blob)
+    }
+
+    /// Remove the chunks of a blob that was never stored
+    fn forget(&self, blob: &Blob) -> Result<(), Error> {
+        let mut batch = Batch::new();
+        for k in blob.chunk_keys() {
+            batch.remove(&k)?;
+        }
+        self.chunks.batch(batch)
+    }
+
+    /// Get the manifest of the blob stored at `key`
+    pub fn get(&self, key: &K) -> Result<Option<Blob>, Error> {
+        Ok(self.manifests.get(key)?.map(|Manifest(blob)| blob))
+    }
+
+    /// Get a reader for the blob stored at `key`, chunks are read as they are needed
+    pub fn get_blob(&self, key: &K) -> Result<Option<BlobReader>, Error> {
+        Ok(self.get(key)?.map(|blob| BlobReader {
+            tree: self.chunks.0.clone(),
+            blob,
+            pos: 0,
+            chunk: None,
+            hasher: Some(crc32fast::Hasher::new()),
+        }))
+    }
+
+    /// Returns true if a blob is stored at `key`
+    pub fn contains(&self, key: &K) -> Result<bool, Error> {
+        self.manifests.contains(key)
+    }
+
+    /// Remove the blob stored at `key` along with its chunks, returning its manifest
+    pub fn remove(&self, key: &K) -> Result<Option<Blob>, Error> {
+        self.transaction(|t| {
+            let old = t.bucket(&self.manifests)?.remove(key)?;
+            let old = old.map(|Manifest(blob)| blob);
+            if let Some(old) = &old {
+                let chunks = t.bucket(&self.chunks)?;
+                for k in old.chunk_keys() {
+                    chunks.remove(&k)?;
+                }
+            }
+            Ok(old)
+        })
+    }
+
+    /// Get the number of blobs
+    pub fn len(&self) -> usize {
+        self.manifests.len()
+    }
+
+    /// Returns true when there are no blobs
+    pub fn is_empty(&self) -> bool {
+        self.manifests.is_empty()
+    }
+
+    /// Remove chunks that aren't referenced by any manifest, returning the number of chunks
+    /// removed. These are left behind when `put_blob` is interrupted by a crash, or when the
+    /// bucket `name` is written to directly. Blobs that are still being written are skipped, and
+    /// new blobs can't be stored until the sweep finishes
+    pub fn sweep(&self) -> Result<usize, Error> {
+        // Blobs stored after this point are pending until their manifest is written, so every
+        // chunk that isn't pending is either referenced by the manifests read below or orphaned
+        let pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut ids = HashSet::new();
+        for item in self.manifests.0.iter() {
+            let (_, v) = item?;
+            if let Ok(Manifest(blob)) = Manifest::from_raw_value(v) {
+                ids.insert(blob.id);
+            }
+        }
+
+        let mut count = 0;
+        for item in self.chunks.0.iter() {
+            let (k, _) = item?;
+            let id = chunk_id(&k);
+            if id.is_some_and(|id| ids.contains(&id) || pending.contains(&id)) {
+                continue;
+            }
+            self.chunks.remove(&k)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Streaming reader returned by `Bucket::get_blob`, chunks are loaded as they are needed. Use
+/// `seek` to read part of a blob.
+///
+/// Reading the whole blob from the start verifies the checksum, returning an `InvalidData` error
+/// at the end if it doesn't match. Reads return a `NotFound` error if the blob is replaced or
+/// removed while it's being read
+pub struct BlobReader {
+    tree: Arc<dyn Tree>,
+    blob: Blob,
+    pos: u64,
+    chunk: Option<(u64, Raw)>,
+    hasher: Option<crc32fast::Hasher>,
+}
+
+impl BlobReader {
+    /// Get the manifest of the blob being read
+    pub fn blob(&self) -> &Blob {
+        &self.blob
+    }
+
+    fn load(&mut self, index: u64) -> io::Result<&Raw> {
+        if self.chunk.as_ref().map(|(i, _)| *i) != Some(index) {
+            let chunk = self
+                .tree
+                .get(&chunk_key(self.blob.id, index))
+                .map_err(io::Error::other)?;
+            match chunk {
+                Some(chunk) => self.chunk = Some((index, chunk)),
+                None => {
+                    let msg = "blob chunk not found";
+                    return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+                }
+            }
+        }
+        Ok(&self.chunk.as_ref().unwrap().1)
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.blob.len {
+            if let Some(hasher) = self.hasher.take() {
+                if hasher.finalize() != self.blob.checksum {
+                    let msg = "blob checksum mismatch";
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+            }
+            return Ok(0);
+        }
+
+        let chunk_size = u64::from(self.blob.chunk_size);
+        let offset = (self.pos % chunk_size) as usize;
+        let chunk = self.load(self.pos / chunk_size)?;
+        let n = buf.len().min(chunk.len().saturating_sub(offset));
+        if n == 0 && !buf.is_empty() {
+            let msg = "blob chunk is truncated";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.blob.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                let msg = "invalid seek to a negative or overflowing position";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+
+        // Only sequential reads from the start can be verified
+        if pos != self.pos {
+            self.hasher = None;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
@@ -1,112 +1,71 @@
-use pin_project_lite::pin_project;
-use std::future::Future;
+use std::cell::RefCell;
 use std::marker::PhantomData;
-use std::pin::Pin;
-use std::task::{Context, Poll};
-
-use sled::Transactional;
-
-use crate::{Error, Key, Raw, Transaction, TransactionError, Value};
+use std::ops::Bound;
+use std::sync::{Arc, RwLockReadGuard};
+use std::time::Duration;
+
+use crate::backend::{self, MergeFn, RawIter, Tree};
+use crate::index::{IndexIter, Indexes, SharedIndexes};
+use crate::ttl::{self, Ttl};
+use crate::versioned;
+use crate::watch::{Change, Order, Watchers};
+use crate::{
+    CompareAndSwapError, Error, Event, Integer, Key, Migrate, Prefix, Raw, Transaction,
+    TransactionError, Value, Versioned, Watch, I64,
+};
+
+/// Number of values written in each transaction by `Bucket::migrate_all` and
+/// `TimeSeries::apply_retention`
+pub(crate) const REWRITE_BATCH_SIZE: usize = 1000;
 
 /// Provides typed access to the key/value store
-#[derive(Clone)]
 pub struct Bucket<'a, K: Key<'a>, V: Value>(
-    pub(crate) sled::Tree,
+    pub(crate) Arc<dyn Tree>,
+    pub(crate) Ttl,
+    pub(crate) Arc<SharedIndexes>,
+    pub(crate) Arc<Watchers>,
+    Option<MergeFn>,
     PhantomData<K>,
     PhantomData<V>,
     PhantomData<&'a ()>,
 );
 
+impl<'a, K: Key<'a>, V: Value> Clone for Bucket<'a, K, V> {
+    fn clone(&self) -> Self {
+        let mut bucket = Bucket::new(
+            self.0.clone(),
+            self.1.clone(),
+            self.2.clone(),
+            self.3.clone(),
+        );
+        bucket.4 = self.4.clone();
+        bucket
+    }
+}
+
 /// Key/value pair
 #[derive(Clone)]
-pub struct Item<K, V>(Raw, Raw, PhantomData<K>, PhantomData<V>);
+pub struct Item<K, V>(Raw, Raw, Raw, PhantomData<K>, PhantomData<V>);
 
 /// Batch update
 #[derive(Clone)]
-pub struct Batch<K, V>(pub(crate) sled::Batch, PhantomData<K>, PhantomData<V>);
-
-pin_project! {
-    /// Subscribe to key updated
-    pub struct Watch<K, V> {
-        #[pin]
-        subscriber: sled::Subscriber,
-        phantom: PhantomData<(K, V)>
-    }
-}
-
-/// Event is used to describe the type of update
-pub enum Event<K, V> {
-    /// A key has been updated
-    Set(Item<K, V>),
-    /// A key has been removed
-    Remove(Raw),
-}
-
-impl<'a, K: Key<'a>, V> Event<K, V> {
-    fn from_sled(event: sled::Event) -> Self {
-        match event {
-            sled::Event::Insert { key, value } => {
-                Event::Set(Item(key, value, PhantomData, PhantomData))
-            }
-            sled::Event::Remove { key } => Event::Remove(key),
-        }
-    }
-}
-
-impl<'a, K: Key<'a>, V> Iterator for Watch<K, V> {
-    type Item = Result<Event<K, V>, Error>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.subscriber.next() {
-            None => None,
-            Some(e) => Some(Ok(Event::from_sled(e))),
-        }
-    }
-}
-
-impl<'a, K: Key<'a>, V> Future for Watch<K, V> {
-    type Output = Option<Event<K, V>>;
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.subscriber.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(r) => Poll::Ready(r.map(Event::from_sled)),
-        }
-    }
-}
-
-impl<'a, K: Key<'a>, V: Value> Event<K, V> {
-    /// Returns true when event is `Set`
-    pub fn is_set(&self) -> bool {
-        matches!(self, Event::Set(_))
-    }
-
-    /// Returns true when event is `Remove`
-    pub fn is_remove(&self) -> bool {
-        matches!(self, Event::Remove(_))
-    }
-
-    /// Get event key
-    pub fn key(&'a self) -> Result<K, Error> {
-        match self {
-            Event::Remove(k) => K::from_raw_key(k),
-            Event::Set(item) => item.key(),
-        }
-    }
+pub struct Batch<K, V>(
+    pub(crate) Vec<(Raw, Option<Raw>)>,
+    PhantomData<K>,
+    PhantomData<V>,
+);
 
-    /// Get event value (for insert)
-    pub fn value(&'a self) -> Result<Option<V>, Error> {
-        match self {
-            Event::Remove(_) => Ok(None),
-            Event::Set(item) => item.value().map(Some),
-        }
+impl<K, V> Item<K, V> {
+    pub(crate) fn new(bucket: &[u8], key: Raw, value: Raw) -> Item<K, V> {
+        Item(key, value, bucket.into(), PhantomData, PhantomData)
     }
 }
 
 impl<'a, K: Key<'a>, V: Value> Item<K, V> {
     /// Get the value associated with the specified key
     pub fn value<T: From<V>>(&'a self) -> Result<T, Error> {
-        let x = V::from_raw_value(self.1.clone())?;
+        let x =
+            V::from_raw_value(self.1.clone()).map_err(|e| Error::decode(&self.2, &self.0, e))?;
         Ok(x.into())
     }
 
@@ -115,13 +74,45 @@ impl<'a, K: Key<'a>, V: Value> Item<K, V> {
     where
         K: Into<T>,
     {
-        let k = K::from_raw_key(&self.0)?;
+        let k = K::from_raw_key(&self.0).map_err(|e| Error::decode(&self.2, &self.0, e))?;
         Ok(k.into())
     }
 }
 
 /// Iterator over Bucket keys and values
-pub struct Iter<K, V>(sled::Iter, PhantomData<K>, PhantomData<V>);
+pub struct Iter<K, V>(
+    RawIter,
+    Option<(Ttl, Integer)>,
+    Raw,
+    PhantomData<K>,
+    PhantomData<V>,
+);
+
+impl<K, V> Iter<K, V> {
+    fn new(iter: RawIter, ttl: &Ttl, bucket: &[u8]) -> Result<Iter<K, V>, Error> {
+        let ttl = if ttl.is_empty()? {
+            None
+        } else {
+            Some((ttl.clone(), ttl.now()?))
+        };
+        Ok(Iter(iter, ttl, bucket.into(), PhantomData, PhantomData))
+    }
+
+    fn item(&self, x: Option<Result<(Raw, Raw), Error>>) -> Option<Result<Item<K, V>, Error>> {
+        match x {
+            None => None,
+            Some(Err(e)) => Some(Err(e)),
+            Some(Ok((k, v))) => Some(Ok(Item::new(&self.2, k, v))),
+        }
+    }
+
+    fn is_expired(&self, x: &Option<Result<(Raw, Raw), Error>>) -> Result<bool, Error> {
+        match (&self.1, x) {
+            (Some((ttl, now)), Some(Ok((k, _)))) => ttl.is_expired(k, now),
+            _ => Ok(false),
+        }
+    }
+}
 
 impl<'a, K, V> Iterator for Iter<K, V>
 where
@@ -131,10 +122,13 @@ where
     type Item = Result<Item<K, V>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            None => None,
-            Some(Err(e)) => Some(Err(e.into())),
-            Some(Ok((k, v))) => Some(Ok(Item(k, v, PhantomData, PhantomData))),
+        loop {
+            let x = self.0.next();
+            match self.is_expired(&x) {
+                Ok(true) => continue,
+                Ok(false) => return self.item(x),
+                Err(e) => return Some(Err(e)),
+            }
         }
     }
 }
@@ -145,54 +139,198 @@ where
     V: Value,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        match self.0.next_back() {
-            None => None,
-            Some(Err(e)) => Some(Err(e.into())),
-            Some(Ok((k, v))) => Some(Ok(Item(k, v, PhantomData, PhantomData))),
+        loop {
+            let x = self.0.next_back();
+            match self.is_expired(&x) {
+                Ok(true) => continue,
+                Ok(false) => return self.item(x),
+                Err(e) => return Some(Err(e)),
+            }
         }
     }
 }
 
 impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
-    pub(crate) fn new(t: sled::Tree) -> Bucket<'a, K, V> {
-        Bucket(t, PhantomData, PhantomData, PhantomData)
+    pub(crate) fn new(
+        t: Arc<dyn Tree>,
+        ttl: Ttl,
+        indexes: Arc<SharedIndexes>,
+        watchers: Arc<Watchers>,
+    ) -> Bucket<'a, K, V> {
+        Bucket(
+            t,
+            ttl,
+            indexes,
+            watchers,
+            None,
+            PhantomData,
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    /// All trees that need to be updated when a key is written: the bucket, expiry times, then
+    /// indexes
+    fn trees<'x>(&'x self, indexes: &'x Indexes) -> Result<Vec<&'x dyn Tree>, Error> {
+        let mut trees = vec![&*self.0, &**self.1.tree()?];
+        trees.extend(indexes.trees());
+        Ok(trees)
+    }
+
+    /// Returns a guard when a write only needs to update the bucket's tree: there are no expiry
+    /// times, indexes or subscribers. Expiry times can't be enabled while it's held
+    fn single_tree(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        if !self.2.is_empty() || self.3.has_subscribers() {
+            return None;
+        }
+        self.1.unused()
+    }
+
+    /// Set or remove `key`, keeping expiry times and indexes up to date
+    fn write(
+        &self,
+        key: Raw,
+        value: Option<Raw>,
+        at: Option<Integer>,
+    ) -> Result<Option<Raw>, Error> {
+        if at.is_some() {
+            self.1.enable()?;
+        } else if let Some(_guard) = self.single_tree() {
+            return match value {
+                Some(v) => self.0.insert(&key, v),
+                None => self.0.remove(&key),
+            };
+        }
+
+        let order = Order::new(vec![&self.3]);
+        let indexes = self.2.load();
+        let prev = backend::transaction(&self.trees(&indexes)?, |t| {
+            order.attempt(|| {
+                let prev = match &value {
+                    Some(v) => t[0].insert(&key, v.clone())?,
+                    None => t[0].remove(&key)?,
+                };
+                let expired = ttl::update(t[1], &key, at.as_ref())?;
+                indexes.update(&t[2..], &key, prev.as_ref(), value.as_ref())?;
+                Ok(if expired { None } else { prev })
+            })
+        })?;
+
+        order.notify(&[[(key, prev.clone(), value)]]);
+        Ok(prev)
+    }
+
+    /// Register a secondary index named `name`. The index keys for each value are produced by `f`
+    /// and maintained automatically by `set`, `remove`, `batch` and transactions on every handle
+    /// to this bucket opened from the same `Store`.
+    ///
+    /// Indexes are not persisted, they need to be registered again after the store is reopened.
+    /// Use `rebuild_index` to index existing data.
+    pub fn with_index<I, F>(self, name: &str, f: F) -> Result<Self, Error>
+    where
+        I: Key<'a>,
+        F: 'static + Send + Sync + Fn(&V) -> Vec<I>,
+    {
+        self.2.add(name, f)?;
+        Ok(self)
+    }
+
+    /// Rebuild the index `name` from the existing contents of the bucket, this is not atomic
+    pub fn rebuild_index(&self, name: &str) -> Result<(), Error> {
+        self.2.load().rebuild(name, &*self.0)
+    }
+
+    /// Iterate over all items with the index key `key` in the index `name`
+    pub fn iter_index<I: Key<'a>>(&self, name: &str, key: &I) -> Result<IndexIter<K, V>, Error> {
+        let indexes = self.2.load();
+        let index = indexes.get(name)?;
+        IndexIter::new(index, &self.0, &self.1, self.2.name(), key.to_raw_key()?)
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool, Error> {
+        if self.1.is_empty()? {
+            return Ok(false);
+        }
+        self.1.is_expired(key, &self.1.now()?)
     }
 
     /// Returns true if the bucket contains the given key
     pub fn contains(&self, key: &K) -> Result<bool, Error> {
-        let v = self.0.contains_key(key.to_raw_key()?)?;
+        let key = key.to_raw_key()?;
+        if self.is_expired(&key)? {
+            return Ok(false);
+        }
+        let v = self.0.contains_key(&key)?;
         Ok(v)
     }
 
     /// Get the value associated with the specified key
     pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
-        let v = self.0.get(key.to_raw_key()?)?;
+        let key = key.to_raw_key()?;
+        let v = self.get_raw(&key)?;
+        self.decode(&key, v)
+    }
 
-        match v {
-            None => Ok(None),
-            Some(x) => Ok(Some(V::from_raw_value(x)?)),
+    /// Decode a stored value, adding the bucket name and key to any error
+    fn decode(&self, key: &[u8], value: Option<Raw>) -> Result<Option<V>, Error> {
+        value
+            .map(V::from_raw_value)
+            .transpose()
+            .map_err(|e| Error::decode(self.2.name(), key, e))
+    }
+
+    fn get_raw(&self, key: &Raw) -> Result<Option<Raw>, Error> {
+        if self.is_expired(key)? {
+            return Ok(None);
         }
+        self.0.get(key)
     }
 
     /// Set the value associated with the specified key to the provided value
     pub fn set(&self, key: &K, value: &V) -> Result<Option<V>, Error> {
         let v = value.to_raw_value()?;
-        Ok(self
-            .0
-            .insert(key.to_raw_key()?, v)?
-            .map(|x| V::from_raw_value(x))
-            // https://users.rust-lang.org/t/convenience-method-for-flipping-option-result-to-result-option/13695/7
-            .map_or(Ok(None), |v| v.map(Some))?)
+        let key = key.to_raw_key()?;
+        let old = self.write(key.clone(), Some(v), None)?;
+        self.decode(&key, old)
+    }
+
+    /// Set the value associated with the specified key to the provided value, the key will be
+    /// removed after `ttl` has elapsed. Expired keys are hidden from `get`, `contains` and
+    /// iterators until they are removed using `purge_expired`. Writing the key again using `set`,
+    /// `Batch` or a transaction clears the expiry time
+    pub fn set_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<Option<V>, Error> {
+        let v = value.to_raw_value()?;
+        let at = u128::from(Integer::timestamp_ms()?) + ttl.as_millis();
+        let key = key.to_raw_key()?;
+        let old = self.write(key.clone(), Some(v), Some(Integer::from(at)))?;
+        self.decode(&key, old)
+    }
+
+    /// Get the remaining time to live for the specified key, returns `None` if the key has no
+    /// expiry time
+    pub fn ttl(&self, key: &K) -> Result<Option<Duration>, Error> {
+        let at = match self.1.expiry(key.to_raw_key()?.as_ref())? {
+            Some(at) => u128::from(at),
+            None => return Ok(None),
+        };
+        let now = u128::from(self.1.now()?);
+        Ok(Some(Duration::from_millis(at.saturating_sub(now) as u64)))
+    }
+
+    /// Remove all expired keys, returning the number of keys removed
+    pub fn purge_expired(&self) -> Result<usize, Error> {
+        self.1.purge(&*self.0, &self.2.load(), &self.3)
     }
 
     /// Set the value associated with the specified key to the provided value, only if the existing
-    /// value matches the `old` parameter
+    /// value matches the `old` parameter. When the values don't match, the inner error contains
+    /// the current value
     pub fn compare_and_swap(
         &self,
         key: &K,
         old: Option<&V>,
         value: Option<&V>,
-    ) -> Result<(), Error> {
+    ) -> Result<Result<(), CompareAndSwapError<V>>, Error> {
         let old = match old {
             Some(x) => Some(x.to_raw_value()?),
             None => None,
@@ -203,77 +341,250 @@ impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
             None => None,
         };
 
-        let a = self.0.compare_and_swap(key.to_raw_key()?, old, value)?;
+        let key = key.to_raw_key()?;
+        match self.compare_and_swap_raw(key.clone(), old, value)? {
+            Ok(()) => Ok(Ok(())),
+            Err(e) => Ok(Err(CompareAndSwapError::decode(self.2.name(), &key, e)?)),
+        }
+    }
+
+    fn compare_and_swap_raw(
+        &self,
+        key: Raw,
+        old: Option<Raw>,
+        value: Option<Raw>,
+    ) -> Result<Result<(), CompareAndSwapError<Raw>>, Error> {
+        if let Some(_guard) = self.single_tree() {
+            return self.0.compare_and_swap(&key, old, value);
+        }
 
-        Ok(a?)
+        let order = Order::new(vec![&self.3]);
+        let indexes = self.2.load();
+        let result = backend::transaction(&self.trees(&indexes)?, |t| {
+            order.attempt(|| {
+                let stored = t[0].get(&key)?;
+                let current = if ttl::is_expired(t[1], &key)? {
+                    None
+                } else {
+                    stored.clone()
+                };
+                if current != old {
+                    return Ok(Err(CompareAndSwapError::new(current, value.clone())));
+                }
+
+                match &value {
+                    Some(v) => t[0].insert(&key, v.clone())?,
+                    None => t[0].remove(&key)?,
+                };
+                ttl::update(t[1], &key, None)?;
+                indexes.update(&t[2..], &key, stored.as_ref(), value.as_ref())?;
+                Ok(Ok(()))
+            })
+        })?;
+
+        if result.is_ok() {
+            order.notify(&[[(key, old, value)]]);
+        }
+        Ok(result)
+    }
+
+    /// Replace the value associated with the specified key with the result of `f`, which is
+    /// passed the current value. Returning `None` removes the key. `f` may be called more than
+    /// once if the key is modified concurrently. Returns the new value
+    pub fn update_and_fetch<F>(&self, key: &K, f: F) -> Result<Option<V>, Error>
+    where
+        F: Fn(Option<V>) -> Option<V>,
+    {
+        let key = key.to_raw_key()?;
+        let (_, new) = self.update_raw(&key, |old| encode_update(self.2.name(), &key, old, &f))?;
+        self.decode(&key, new)
+    }
+
+    /// Like `update_and_fetch`, but returns the previous value
+    pub fn fetch_and_update<F>(&self, key: &K, f: F) -> Result<Option<V>, Error>
+    where
+        F: Fn(Option<V>) -> Option<V>,
+    {
+        let key = key.to_raw_key()?;
+        let (old, _) = self.update_raw(&key, |old| encode_update(self.2.name(), &key, old, &f))?;
+        self.decode(&key, old)
+    }
+
+    /// Compare and swap loop, returns the previous and new values
+    fn update_raw<F>(&self, key: &Raw, f: F) -> Result<(Option<Raw>, Option<Raw>), Error>
+    where
+        F: Fn(Option<Raw>) -> Result<Option<Raw>, Error>,
+    {
+        loop {
+            let old = self.get_raw(key)?;
+            let new = f(old.clone())?;
+            if self
+                .compare_and_swap_raw(key.clone(), old.clone(), new.clone())?
+                .is_ok()
+            {
+                return Ok((old, new));
+            }
+        }
+    }
+
+    /// Set the function used by `merge` to combine the current value with the value being merged,
+    /// returning `None` removes the key
+    pub fn with_merge_operator<F>(mut self, f: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(Option<V>, V) -> Option<V>,
+    {
+        self.4 = Some(Arc::new(
+            move |_: &[u8], old: Option<&[u8]>, value: &[u8]| {
+                let old = old.map(|x| V::from_raw_value(x.into())).transpose()?;
+                let value = V::from_raw_value(value.into())?;
+                f(old, value).map(|x| x.to_raw_value()).transpose()
+            },
+        ));
+        self
+    }
+
+    /// Merge `value` into the value associated with the specified key using the operator set by
+    /// `with_merge_operator`, returning the new value. When the bucket has no expiry times,
+    /// indexes or subscribers the merge is applied by the backend's merge operator, so it never
+    /// has to be retried, otherwise it falls back to a compare and swap loop like
+    /// `update_and_fetch`
+    pub fn merge(&self, key: &K, value: &V) -> Result<Option<V>, Error> {
+        let f = match &self.4 {
+            Some(f) => f,
+            None => return Err(Error::Message("No merge operator has been set".into())),
+        };
+        let key = key.to_raw_key()?;
+        let new = self.merge_raw(key.clone(), value.to_raw_value()?, f)?;
+        self.decode(&key, new)
+    }
+
+    fn merge_raw(&self, key: Raw, value: Raw, f: &MergeFn) -> Result<Option<Raw>, Error> {
+        if let Some(_guard) = self.single_tree() {
+            return self.0.merge(&key, &value, f);
+        }
+
+        let (_, new) = self.update_raw(&key, |old| f(&key, old.as_deref(), &value))?;
+        Ok(new)
     }
 
     /// Remove the value associated with the specified key from the database
     pub fn remove(&self, key: &K) -> Result<Option<V>, Error> {
-        Ok(self
-            .0
-            .remove(key.to_raw_key()?)?
-            .map(|x| V::from_raw_value(x))
-            // https://users.rust-lang.org/t/convenience-method-for-flipping-option-result-to-result-option/13695/7
-            .map_or(Ok(None), |v| v.map(Some))?)
+        let key = key.to_raw_key()?;
+        let old = self.write(key.clone(), None, None)?;
+        self.decode(&key, old)
     }
 
-    /// Get an iterator over keys/values
+    /// Get an iterator over keys/values. If expiry times can't be checked, e.g. because the
+    /// system clock is set before the Unix epoch, the iterator returns the error
     pub fn iter(&self) -> Iter<K, V> {
-        Iter(self.0.iter(), PhantomData, PhantomData)
+        match Iter::new(self.0.iter(), &self.1, self.2.name()) {
+            Ok(iter) => iter,
+            Err(e) => Iter(
+                Box::new(std::iter::once(Err(e))),
+                None,
+                self.2.name().into(),
+                PhantomData,
+                PhantomData,
+            ),
+        }
     }
 
     /// Get an iterator over keys/values in the specified range
     pub fn iter_range(&self, a: &K, b: &K) -> Result<Iter<K, V>, Error> {
         let a = a.to_raw_key()?;
         let b = b.to_raw_key()?;
-        Ok(Iter(self.0.range(a..b), PhantomData, PhantomData))
+        Iter::new(
+            self.0.range(Bound::Included(a), Bound::Excluded(b)),
+            &self.1,
+            self.2.name(),
+        )
     }
 
-    /// Iterate over keys/values with the specified prefix
-    pub fn iter_prefix(&self, a: &K) -> Result<Iter<K, V>, Error> {
-        let a = a.to_raw_key()?;
-        Ok(Iter(self.0.scan_prefix(a), PhantomData, PhantomData))
+    pub(crate) fn iter_bounds(
+        &self,
+        start: Bound<Raw>,
+        end: Bound<Raw>,
+    ) -> Result<Iter<K, V>, Error> {
+        Iter::new(self.0.range(start, end), &self.1, self.2.name())
+    }
+
+    /// Iterate over keys/values with the specified prefix, for tuple keys the prefix can also be
+    /// a tuple of leading elements
+    pub fn iter_prefix<P: Prefix<K>>(&self, a: &P) -> Result<Iter<K, V>, Error> {
+        let a = a.to_raw_prefix()?;
+        Iter::new(self.0.scan_prefix(&a), &self.1, self.2.name())
     }
 
     /// Apply batch update
     pub fn batch(&self, batch: Batch<K, V>) -> Result<(), Error> {
-        self.0.apply_batch(batch.0)?;
+        if let Some(_guard) = self.single_tree() {
+            return self.0.apply_batch(&batch.0);
+        }
+
+        let order = Order::new(vec![&self.3]);
+        let changes = RefCell::new(Vec::new());
+        let indexes = self.2.load();
+        backend::transaction(&self.trees(&indexes)?, |t| {
+            order.attempt(|| {
+                changes.borrow_mut().clear();
+                let txn: Transaction<K, V> = Transaction::new(t, &indexes, &changes);
+                txn.batch(&batch)
+            })
+        })?;
+
+        order.notify(&[changes.into_inner()]);
         Ok(())
     }
 
-    /// Get updates when a key with the given prefix is changed
+    /// Get updates when a key with the given prefix is changed. Only writes made through buckets
+    /// opened from the same `Store` are reported
     pub fn watch_prefix(&self, prefix: Option<&K>) -> Result<Watch<K, V>, Error> {
         let k = match prefix {
             Some(k) => k.to_raw_key()?,
             None => b"".into(),
         };
-        let subscriber = self.0.watch_prefix(k);
-        Ok(Watch {
-            subscriber,
-            phantom: PhantomData {},
-        })
+        Ok(Watch::new(&self.3, k))
     }
 
-    /// Execute a transaction
+    /// Get updates when a key with the given prefix is changed and `f` returns true for the
+    /// event. `f` is called by the subscriber when it receives the event
+    pub fn watch_prefix_filter<F>(&self, prefix: Option<&K>, f: F) -> Result<Watch<K, V>, Error>
+    where
+        K: 'static,
+        V: 'static,
+        F: 'static + Send + Sync + Fn(&Event<K, V>) -> bool,
+    {
+        let k = match prefix {
+            Some(k) => k.to_raw_key()?,
+            None => b"".into(),
+        };
+        Ok(Watch::with_filter(&self.3, k, f))
+    }
+
+    /// Execute a transaction. `f` may be called more than once if the transaction conflicts with
+    /// another write, and should only write using the transaction: with `sled` every other write
+    /// waits for the transaction to finish, so writing to a bucket directly from `f` returns an
+    /// error
     pub fn transaction<
         A,
-        E: From<sled::Error>,
+        E: From<Error>,
         F: Fn(Transaction<K, V>) -> Result<A, TransactionError<E>>,
     >(
         &self,
         f: F,
     ) -> Result<A, E> {
-        let result = self.0.transaction(|t| {
-            let txn = Transaction::new(t);
-            f(txn)
-        });
+        let order = Order::new(vec![&self.3]);
+        let changes = RefCell::new(Vec::new());
+        let indexes = self.2.load();
+        let x = backend::transaction(&self.trees(&indexes)?, |t| {
+            order.attempt(|| {
+                changes.borrow_mut().clear();
+                f(Transaction::new(t, &indexes, &changes))
+            })
+        })?;
 
-        match result {
-            Ok(x) => Ok(x),
-            Err(sled::transaction::TransactionError::Abort(x)) => Err(x),
-            Err(sled::transaction::TransactionError::Storage(e)) => Err(e.into()),
-        }
+        order.notify(&[changes.into_inner()]);
+        Ok(x)
     }
 
     /// Create a transaction with access to two buckets
@@ -281,24 +592,30 @@ impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
         A,
         T: Key<'a>,
         U: Value,
-        E: From<sled::Error>,
+        E: From<Error>,
         F: Fn(Transaction<K, V>, Transaction<T, U>) -> Result<A, TransactionError<E>>,
     >(
         &self,
         other: &Bucket<'a, T, U>,
         f: F,
     ) -> Result<A, E> {
-        let result = (&self.0, &other.0).transaction(|(a, b)| {
-            let a = Transaction::new(a);
-            let b = Transaction::new(b);
-            f(a, b)
-        });
-
-        match result {
-            Ok(x) => Ok(x),
-            Err(sled::transaction::TransactionError::Abort(x)) => Err(x),
-            Err(sled::transaction::TransactionError::Storage(e)) => Err(e.into()),
-        }
+        let order = Order::new(vec![&self.3, &other.3]);
+        let changes: [RefCell<Vec<Change>>; 2] = Default::default();
+        let indexes = [self.2.load(), other.2.load()];
+        let mut trees = self.trees(&indexes[0])?;
+        let i = trees.len();
+        trees.extend(other.trees(&indexes[1])?);
+        let x = backend::transaction(&trees, |t| {
+            order.attempt(|| {
+                changes.iter().for_each(|c| c.borrow_mut().clear());
+                let a = Transaction::new(&t[..i], &indexes[0], &changes[0]);
+                let b = Transaction::new(&t[i..], &indexes[1], &changes[1]);
+                f(a, b)
+            })
+        })?;
+
+        order.notify(&changes.map(RefCell::into_inner));
+        Ok(x)
     }
 
     /// Create a transaction with access to three buckets
@@ -308,7 +625,7 @@ impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
         U: Value,
         X: Key<'a>,
         Y: Value,
-        E: From<sled::Error>,
+        E: From<Error>,
         F: Fn(
             Transaction<K, V>,
             Transaction<T, U>,
@@ -320,68 +637,132 @@ impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
         other1: &Bucket<'a, X, Y>,
         f: F,
     ) -> Result<A, E> {
-        let result = (&self.0, &other.0, &other1.0).transaction(|(a, b, c)| {
-            let a = Transaction::new(a);
-            let b = Transaction::new(b);
-            let c = Transaction::new(c);
-            f(a, b, c)
-        });
-
-        match result {
-            Ok(x) => Ok(x),
-            Err(sled::transaction::TransactionError::Abort(x)) => Err(x),
-            Err(sled::transaction::TransactionError::Storage(e)) => Err(e.into()),
-        }
+        let order = Order::new(vec![&self.3, &other.3, &other1.3]);
+        let changes: [RefCell<Vec<Change>>; 3] = Default::default();
+        let indexes = [self.2.load(), other.2.load(), other1.2.load()];
+        let mut trees = self.trees(&indexes[0])?;
+        let i = trees.len();
+        trees.extend(other.trees(&indexes[1])?);
+        let j = trees.len();
+        trees.extend(other1.trees(&indexes[2])?);
+        let x = backend::transaction(&trees, |t| {
+            order.attempt(|| {
+                changes.iter().for_each(|c| c.borrow_mut().clear());
+                let a = Transaction::new(&t[..i], &indexes[0], &changes[0]);
+                let b = Transaction::new(&t[i..j], &indexes[1], &changes[1]);
+                let c = Transaction::new(&t[j..], &indexes[2], &changes[2]);
+                f(a, b, c)
+            })
+        })?;
+
+        order.notify(&changes.map(RefCell::into_inner));
+        Ok(x)
     }
 
     /// Get previous key and value in order, if one exists
     pub fn prev_key(&self, key: &K) -> Result<Option<Item<K, V>>, Error> {
-        let item = self.0.get_lt(key)?;
-        Ok(item.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        let key = key.to_raw_key()?;
+        let mut iter = Iter::new(
+            self.0.range(Bound::Unbounded, Bound::Excluded(key)),
+            &self.1,
+            self.2.name(),
+        )?;
+        iter.next_back().transpose()
     }
 
     /// Get next key and value in order, if one exists
     pub fn next_key(&self, key: &K) -> Result<Option<Item<K, V>>, Error> {
-        let item = self.0.get_gt(key)?;
-        Ok(item.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        let key = key.to_raw_key()?;
+        let mut iter = Iter::new(
+            self.0.range(Bound::Excluded(key), Bound::Unbounded),
+            &self.1,
+            self.2.name(),
+        )?;
+        iter.next().transpose()
     }
 
     /// Flush to disk
     pub fn flush(&self) -> Result<usize, Error> {
-        Ok(self.0.flush()?)
+        self.0.flush()
     }
 
     /// Flush to disk
     pub async fn flush_async(&self) -> Result<usize, Error> {
-        let f = self.0.flush_async().await?;
-        Ok(f)
+        self.0.flush_async().await
+    }
+
+    fn pop(&self, back: bool) -> Result<Option<Item<K, V>>, Error> {
+        if let Some(_guard) = self.single_tree() {
+            let item = if back {
+                self.0.pop_max()?
+            } else {
+                self.0.pop_min()?
+            };
+            return Ok(item.map(|(k, v)| Item::new(self.2.name(), k, v)));
+        }
+
+        loop {
+            let item = if back {
+                self.0.last()?
+            } else {
+                self.0.first()?
+            };
+            let (k, v) = match item {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            let order = Order::new(vec![&self.3]);
+            let indexes = self.2.load();
+            let popped = backend::transaction(&self.trees(&indexes)?, |t| {
+                order.attempt(|| {
+                    // Another writer may have changed the key since it was read
+                    if t[0].get(&k)?.as_ref() != Some(&v) {
+                        return Ok(None);
+                    }
+                    t[0].remove(&k)?;
+                    let expired = ttl::update(t[1], &k, None)?;
+                    indexes.update(&t[2..], &k, Some(&v), None)?;
+                    Ok(Some(expired))
+                })
+            })?;
+
+            if let Some(expired) = popped {
+                order.notify(&[[(k.clone(), Some(v.clone()), None)]]);
+
+                // Expired items are discarded
+                if !expired {
+                    return Ok(Some(Item::new(self.2.name(), k, v)));
+                }
+            }
+        }
     }
 
     /// Remove and return the last item
     pub fn pop_back(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.pop_max()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        self.pop(true)
     }
 
     /// Remove and return the first item
     pub fn pop_front(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.pop_min()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        self.pop(false)
     }
 
     /// Get the first item
     pub fn first(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.first()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        Iter::new(self.0.iter(), &self.1, self.2.name())?
+            .next()
+            .transpose()
     }
 
     /// Get the last item
     pub fn last(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.last()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        Iter::new(self.0.iter(), &self.1, self.2.name())?
+            .next_back()
+            .transpose()
     }
 
-    /// Get the number of items
+    /// Get the number of items, this includes expired items that haven't been purged yet
     pub fn len(&self) -> usize {
         self.0.len()
     }
@@ -393,16 +774,125 @@ impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
 
     /// Remove all items
     pub fn clear(&self) -> Result<(), Error> {
+        let mut removed: Vec<Change> = Vec::new();
+        if self.3.has_subscribers() {
+            for item in self.0.iter() {
+                let (k, v) = item?;
+                removed.push((k, Some(v), None));
+            }
+        }
+
         self.0.clear()?;
+        self.1.clear()?;
+        self.2.load().clear()?;
+        self.3.send(&removed);
         Ok(())
     }
 
     /// CRC32 checksum of all keys and values
     pub fn checksum(&self) -> Result<u32, Error> {
-        Ok(self.0.checksum()?)
+        self.0.checksum()
     }
 }
 
+impl<'a, K: Key<'a>> Bucket<'a, K, I64> {
+    /// Add `n` to the counter associated with the specified key, missing keys start at zero.
+    /// Returns the new value. Like `merge`, this doesn't retry when the bucket has no expiry
+    /// times, indexes or subscribers
+    pub fn increment(&self, key: &K, n: i64) -> Result<i64, Error> {
+        let f: MergeFn = Arc::new(|_, old, value| {
+            let old = old.map_or(Ok(0), |x| I64::try_from(x).map(i64::from))?;
+            let n = i64::from(I64::try_from(value)?);
+            let new = old
+                .checked_add(n)
+                .ok_or_else(|| Error::Message("Counter overflow".into()))?;
+            Ok(Some(I64::from(new).to_raw_value()?))
+        });
+        let new = self.merge_raw(key.to_raw_key()?, I64::from(n).to_raw_value()?, &f)?;
+        match new {
+            Some(x) => Ok(I64::try_from(x.as_ref())?.into()),
+            None => Ok(0),
+        }
+    }
+}
+
+impl<'a, K: Key<'a>, T: Migrate> Bucket<'a, K, Versioned<T>> {
+    /// Rewrite every value stored using an older schema version, returning the number of values
+    /// migrated. Values are migrated in batches, each batch is written in a single transaction
+    pub fn migrate_all(&self) -> Result<usize, Error> {
+        self.rewrite_all(versioned::upgrade::<T>)
+    }
+}
+
+impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
+    /// Replace every value for which `f` returns a new value
+    fn rewrite_all<F>(&self, f: F) -> Result<usize, Error>
+    where
+        F: Fn(&[u8]) -> Result<Option<Raw>, Error>,
+    {
+        let mut count = 0;
+        let mut iter = self.0.iter();
+        loop {
+            let mut keys = Vec::with_capacity(REWRITE_BATCH_SIZE);
+            for x in iter.by_ref() {
+                let (k, v) = x?;
+                if f(&v)
+                    .map_err(|e| Error::decode(self.2.name(), &k, e))?
+                    .is_some()
+                {
+                    keys.push(k);
+                    if keys.len() == REWRITE_BATCH_SIZE {
+                        break;
+                    }
+                }
+            }
+            if keys.is_empty() {
+                return Ok(count);
+            }
+
+            // Values are read again in the transaction in case they were modified
+            let order = Order::new(vec![&self.3]);
+            let indexes = self.2.load();
+            let changes = backend::transaction(&self.trees(&indexes)?, |t| {
+                order.attempt(|| {
+                    let mut changes = Vec::new();
+                    for key in &keys {
+                        let old = match t[0].get(key)? {
+                            Some(v) => v,
+                            None => continue,
+                        };
+                        let new = f(&old).map_err(|e| {
+                            TransactionError::Abort(Error::decode(self.2.name(), key, e))
+                        })?;
+                        if let Some(new) = new {
+                            t[0].insert(key, new.clone())?;
+                            indexes.update(&t[2..], key, Some(&old), Some(&new))?;
+                            changes.push((key.clone(), Some(old), Some(new)));
+                        }
+                    }
+                    Ok(changes)
+                })
+            })?;
+
+            count += changes.len();
+            order.notify(&[&changes]);
+        }
+    }
+}
+
+fn encode_update<V: Value, F: Fn(Option<V>) -> Option<V>>(
+    bucket: &[u8],
+    key: &[u8],
+    old: Option<Raw>,
+    f: &F,
+) -> Result<Option<Raw>, Error> {
+    let old = old
+        .map(V::from_raw_value)
+        .transpose()
+        .map_err(|e| Error::decode(bucket, key, e))?;
+    f(old).map(|x| x.to_raw_value()).transpose()
+}
+
 impl<'a, K: Key<'a>, V: Value> Default for Batch<K, V> {
     fn default() -> Self {
         Batch::new()
@@ -412,19 +902,19 @@ impl<'a, K: Key<'a>, V: Value> Default for Batch<K, V> {
 impl<'a, K: Key<'a>, V: Value> Batch<K, V> {
     /// Create a new Batch instance
     pub fn new() -> Batch<K, V> {
-        Batch(sled::Batch::default(), PhantomData, PhantomData)
+        Batch(Vec::new(), PhantomData, PhantomData)
     }
 
     /// Set the value associated with the specified key to the provided value
     pub fn set(&mut self, key: &K, value: &V) -> Result<(), Error> {
         let v = value.to_raw_value()?;
-        self.0.insert(key.to_raw_key()?, v);
+        self.0.push((key.to_raw_key()?, Some(v)));
         Ok(())
     }
 
     /// Remove the value associated with the specified key from the database
     pub fn remove(&mut self, key: &K) -> Result<(), Error> {
-        self.0.remove(key.to_raw_key()?);
+        self.0.push((key.to_raw_key()?, None));
         Ok(())
     }
 }
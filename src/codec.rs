@@ -44,15 +44,29 @@ macro_rules! codec {
     ($x:ident, {$ser:expr, $de:expr}) => {
         codec!($x);
 
+        impl<T: serde::Serialize + serde::de::DeserializeOwned> $x<T> {
+            /// Encode a value without wrapping it
+            pub fn encode(value: &T) -> Result<Raw, Error> {
+                let x = $ser(value)?;
+                Ok(x.into())
+            }
+
+            /// Decode a value without wrapping it
+            pub fn decode(r: &Raw) -> Result<T, Error> {
+                let x = $de(r)?;
+                Ok(x)
+            }
+        }
+
         impl<T: serde::Serialize + serde::de::DeserializeOwned> Value for $x<T> {
+            const TYPE_TAG: Option<&'static str> = Some(stringify!($x));
+
             fn to_raw_value(&self) -> Result<Raw, Error> {
-                let x = $ser(&self.0)?;
-                Ok(x.into())
+                Self::encode(&self.0)
             }
 
             fn from_raw_value(r: Raw) -> Result<Self, Error> {
-                let x = $de(&r)?;
-                Ok($x(x))
+                Ok($x(Self::decode(&r)?))
             }
         }
     };
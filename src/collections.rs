@@ -0,0 +1,372 @@
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use crate::backend;
+use crate::key::encode_escaped;
+use crate::{
+    Bucket, Buckets, Error, Key, Raw, Store, Transaction, TransactionError, Transactions, Value,
+    F64,
+};
+
+/// Raw prefix of the score index entries with the score `score`
+fn score_prefix(score: f64) -> Result<Raw, Error> {
+    let mut dst = Vec::new();
+    encode_escaped(&F64::from(score).to_raw_key()?, &mut dst);
+    Ok(dst.into())
+}
+
+/// Set of unique members ordered by score, members with the same score are ordered by key.
+///
+/// Scores are stored in the bucket `name`, and a score ordered index is stored in the bucket
+/// `<name>.by_score`. Use `Buckets::with_sorted_set` and `Transactions::sorted_set` to update a
+/// sorted set in a transaction
+///
+/// ```rust
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-sorted-set").temporary(true))?;
+/// let scores = SortedSet::<String>::new(&store, "scores")?;
+/// scores.add(&"alice".to_string(), 10.0)?;
+/// scores.add(&"bob".to_string(), 5.0)?;
+///
+/// assert_eq!(scores.rank(&"alice".to_string())?, Some(1));
+/// let top: Vec<(String, f64)> = scores.range_by_rank(0, 1)?;
+/// assert_eq!(top, [("bob".to_string(), 5.0)]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct SortedSet<M: for<'x> Key<'x>> {
+    members: Bucket<'static, M, F64>,
+    scores: Bucket<'static, (F64, M), Raw>,
+}
+
+impl<M: for<'x> Key<'x>> Clone for SortedSet<M> {
+    fn clone(&self) -> Self {
+        SortedSet {
+            members: self.members.clone(),
+            scores: self.scores.clone(),
+        }
+    }
+}
+
+impl<M: Clone + for<'x> Key<'x>> SortedSet<M> {
+    /// Open the sorted set stored in the bucket `name`
+    pub fn new(store: &Store, name: &str) -> Result<SortedSet<M>, Error> {
+        Ok(SortedSet {
+            members: store.bucket(Some(name))?,
+            scores: store.bucket(Some(&format!("{}.by_score", name)))?,
+        })
+    }
+
+    fn transaction<A, F>(&self, f: F) -> Result<A, Error>
+    where
+        F: Fn(SortedSetTransaction<M>) -> Result<A, TransactionError<Error>>,
+    {
+        Buckets::new()
+            .with_sorted_set(self)
+            .transaction(|t| f(t.sorted_set(self)?))
+    }
+
+    /// Add `member` with the given score, or update its score. Returns the previous score
+    pub fn add(&self, member: &M, score: f64) -> Result<Option<f64>, Error> {
+        self.transaction(|t| t.add(member, score))
+    }
+
+    /// Remove `member`, returning its score
+    pub fn remove(&self, member: &M) -> Result<Option<f64>, Error> {
+        self.transaction(|t| t.remove(member))
+    }
+
+    /// Get the score of `member`
+    pub fn score(&self, member: &M) -> Result<Option<f64>, Error> {
+        Ok(self.members.get(member)?.map(f64::from))
+    }
+
+    /// Returns true if the set contains `member`
+    pub fn contains(&self, member: &M) -> Result<bool, Error> {
+        self.members.contains(member)
+    }
+
+    /// Get the position of `member` in score order, starting from 0. This counts the members
+    /// with lower scores so it takes time proportional to the rank
+    pub fn rank(&self, member: &M) -> Result<Option<usize>, Error> {
+        let score = match self.members.get(member)? {
+            Some(score) => score,
+            None => return Ok(None),
+        };
+        let end = (score, member.clone()).to_raw_key()?;
+        let iter = self
+            .scores
+            .iter_bounds(Bound::Unbounded, Bound::Excluded(end))?;
+        Ok(Some(iter.count()))
+    }
+
+    /// Get the members with scores between `min` and `max`, inclusive, in score order
+    pub fn range_by_score(&self, min: f64, max: f64) -> Result<Vec<(M, f64)>, Error> {
+        let end = match backend::prefix_end(&score_prefix(max)?) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        let iter = self
+            .scores
+            .iter_bounds(Bound::Included(score_prefix(min)?), end)?;
+        iter.map(|item| {
+            let (score, member): (F64, M) = item?.key()?;
+            Ok((member, f64::from(score)))
+        })
+        .collect()
+    }
+
+    /// Get the members with ranks from `start` up to, but not including, `end`
+    pub fn range_by_rank(&self, start: usize, end: usize) -> Result<Vec<(M, f64)>, Error> {
+        self.scores
+            .iter()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .map(|item| {
+                let (score, member): (F64, M) = item?.key()?;
+                Ok((member, f64::from(score)))
+            })
+            .collect()
+    }
+
+    /// Get the number of members
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns true when the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// Access to a `SortedSet` in a transaction, created using `Transactions::sorted_set`
+pub struct SortedSetTransaction<'b, M: for<'x> Key<'x>> {
+    members: Transaction<'static, 'b, M, F64>,
+    scores: Transaction<'static, 'b, (F64, M), Raw>,
+}
+
+impl<'b, M: Clone + for<'x> Key<'x>> SortedSetTransaction<'b, M> {
+    /// Add `member` with the given score, or update its score. Returns the previous score
+    pub fn add(&self, member: &M, score: f64) -> Result<Option<f64>, TransactionError<Error>> {
+        let score = F64::from(score);
+        let old = self.members.set(member, &score)?;
+        if let Some(old) = old {
+            self.scores.remove(&(old, member.clone()))?;
+        }
+        self.scores.set(&(score, member.clone()), &Raw::default())?;
+        Ok(old.map(f64::from))
+    }
+
+    /// Remove `member`, returning its score
+    pub fn remove(&self, member: &M) -> Result<Option<f64>, TransactionError<Error>> {
+        let old = self.members.remove(member)?;
+        if let Some(old) = old {
+            self.scores.remove(&(old, member.clone()))?;
+        }
+        Ok(old.map(f64::from))
+    }
+
+    /// Get the score of `member`
+    pub fn score(&self, member: &M) -> Result<Option<f64>, TransactionError<Error>> {
+        Ok(self.members.get(member)?.map(f64::from))
+    }
+}
+
+/// Map from each key to a set of values, stored in a single bucket using `(key, value)` keys so
+/// the values for a key can be iterated and removed without reading other keys. Values are
+/// ordered by their encoding, adding a value more than once has no effect.
+///
+/// Use `Buckets::with_multimap` and `Transactions::multimap` to update a multimap in a
+/// transaction
+///
+/// ```rust
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-multimap").temporary(true))?;
+/// let tags = MultiMap::<String, String>::new(&store, "tags")?;
+/// tags.insert(&"post-1".to_string(), &"rust".to_string())?;
+/// tags.insert(&"post-1".to_string(), &"kv".to_string())?;
+///
+/// assert_eq!(tags.get(&"post-1".to_string())?, ["kv", "rust"]);
+/// assert_eq!(tags.remove_all(&"post-1".to_string())?, 2);
+/// # Ok(())
+/// # }
+/// ```
+pub struct MultiMap<K: for<'x> Key<'x>, V: Value> {
+    bucket: Bucket<'static, (K, Raw), Raw>,
+    phantom: PhantomData<V>,
+}
+
+impl<K: for<'x> Key<'x>, V: Value> Clone for MultiMap<K, V> {
+    fn clone(&self) -> Self {
+        MultiMap {
+            bucket: self.bucket.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<K: Clone + for<'x> Key<'x>, V: Value> MultiMap<K, V> {
+    /// Open the multimap stored in the bucket `name`
+    pub fn new(store: &Store, name: &str) -> Result<MultiMap<K, V>, Error> {
+        Ok(MultiMap {
+            bucket: store.bucket(Some(name))?,
+            phantom: PhantomData,
+        })
+    }
+
+    fn key(key: &K, value: &V) -> Result<(K, Raw), Error> {
+        Ok((key.clone(), value.to_raw_value()?))
+    }
+
+    /// Add `value` to the values associated with `key`, returns false if it was already present
+    pub fn insert(&self, key: &K, value: &V) -> Result<bool, Error> {
+        let k = Self::key(key, value)?;
+        Ok(self.bucket.set(&k, &Raw::default())?.is_none())
+    }
+
+    /// Remove `value` from the values associated with `key`, returns false if it wasn't present
+    pub fn remove(&self, key: &K, value: &V) -> Result<bool, Error> {
+        let k = Self::key(key, value)?;
+        Ok(self.bucket.remove(&k)?.is_some())
+    }
+
+    /// Returns true if `value` is associated with `key`
+    pub fn contains(&self, key: &K, value: &V) -> Result<bool, Error> {
+        self.bucket.contains(&Self::key(key, value)?)
+    }
+
+    /// Get the values associated with `key`
+    pub fn get(&self, key: &K) -> Result<Vec<V>, Error> {
+        self.iter_key(key)?.collect()
+    }
+
+    /// Iterate over the values associated with `key`
+    pub fn iter_key(
+        &self,
+        key: &K,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<V, Error>>, Error> {
+        let iter = self.bucket.iter_prefix(&(key.clone(),))?;
+        Ok(iter.map(|item| {
+            let (_, value): (K, Raw) = item?.key()?;
+            V::from_raw_value(value)
+        }))
+    }
+
+    /// Remove every value associated with `key` in a single transaction, returning the number of
+    /// values removed
+    pub fn remove_all(&self, key: &K) -> Result<usize, Error> {
+        Buckets::new()
+            .with_multimap(self)
+            .transaction(|t| t.multimap(self)?.remove_all(key))
+    }
+
+    /// Get the number of values associated with `key`
+    pub fn count(&self, key: &K) -> Result<usize, Error> {
+        Ok(self.bucket.iter_prefix(&(key.clone(),))?.count())
+    }
+
+    /// Get the total number of values
+    pub fn len(&self) -> usize {
+        self.bucket.len()
+    }
+
+    /// Returns true when the multimap is empty
+    pub fn is_empty(&self) -> bool {
+        self.bucket.is_empty()
+    }
+}
+
+/// Access to a `MultiMap` in a transaction, created using `Transactions::multimap`
+pub struct MultiMapTransaction<'b, K: for<'x> Key<'x>, V: Value> {
+    bucket: Transaction<'static, 'b, (K, Raw), Raw>,
+    phantom: PhantomData<V>,
+}
+
+impl<'b, K: Clone + for<'x> Key<'x>, V: Value> MultiMapTransaction<'b, K, V> {
+    fn key(key: &K, value: &V) -> Result<(K, Raw), TransactionError<Error>> {
+        MultiMap::<K, V>::key(key, value).map_err(TransactionError::Abort)
+    }
+
+    /// Add `value` to the values associated with `key`, returns false if it was already present
+    pub fn insert(&self, key: &K, value: &V) -> Result<bool, TransactionError<Error>> {
+        let k = Self::key(key, value)?;
+        Ok(self.bucket.set(&k, &Raw::default())?.is_none())
+    }
+
+    /// Remove `value` from the values associated with `key`, returns false if it wasn't present
+    pub fn remove(&self, key: &K, value: &V) -> Result<bool, TransactionError<Error>> {
+        let k = Self::key(key, value)?;
+        Ok(self.bucket.remove(&k)?.is_some())
+    }
+
+    /// Returns true if `value` is associated with `key`
+    pub fn contains(&self, key: &K, value: &V) -> Result<bool, TransactionError<Error>> {
+        self.bucket.contains(&Self::key(key, value)?)
+    }
+
+    /// Get the values associated with `key`, including writes made earlier in the transaction
+    pub fn get(&self, key: &K) -> Result<Vec<V>, TransactionError<Error>> {
+        self.bucket
+            .iter_prefix(&(key.clone(),))?
+            .map(|item| {
+                let (_, value): (K, Raw) = item.key()?;
+                V::from_raw_value(value)
+            })
+            .collect::<Result<_, _>>()
+            .map_err(TransactionError::Abort)
+    }
+
+    /// Remove every value associated with `key`, returning the number of values removed
+    pub fn remove_all(&self, key: &K) -> Result<usize, TransactionError<Error>> {
+        let items: Vec<_> = self.bucket.iter_prefix(&(key.clone(),))?.collect();
+        for item in &items {
+            let k: (K, Raw) = item.key().map_err(TransactionError::Abort)?;
+            self.bucket.remove(&k)?;
+        }
+        Ok(items.len())
+    }
+}
+
+impl<'b> Buckets<'b> {
+    /// Add the buckets used by `set` to the transaction
+    pub fn with_sorted_set<M: for<'x> Key<'x>>(self, set: &'b SortedSet<M>) -> Buckets<'b> {
+        self.with(&set.members).with(&set.scores)
+    }
+
+    /// Add the bucket used by `map` to the transaction
+    pub fn with_multimap<K: for<'x> Key<'x>, V: Value>(
+        self,
+        map: &'b MultiMap<K, V>,
+    ) -> Buckets<'b> {
+        self.with(&map.bucket)
+    }
+}
+
+impl<'b> Transactions<'b> {
+    /// Get a view of `set`, which must have been added using `Buckets::with_sorted_set`
+    pub fn sorted_set<M: for<'x> Key<'x>>(
+        &self,
+        set: &SortedSet<M>,
+    ) -> Result<SortedSetTransaction<'_, M>, TransactionError<Error>> {
+        Ok(SortedSetTransaction {
+            members: self.bucket(&set.members)?,
+            scores: self.bucket(&set.scores)?,
+        })
+    }
+
+    /// Get a view of `map`, which must have been added using `Buckets::with_multimap`
+    pub fn multimap<K: for<'x> Key<'x>, V: Value>(
+        &self,
+        map: &MultiMap<K, V>,
+    ) -> Result<MultiMapTransaction<'_, K, V>, TransactionError<Error>> {
+        Ok(MultiMapTransaction {
+            bucket: self.bucket(&map.bucket)?,
+            phantom: PhantomData,
+        })
+    }
+}
@@ -1,8 +1,11 @@
+use std::fmt;
 use std::io;
 use std::sync::PoisonError;
 
 use thiserror::Error as TError;
 
+use crate::{BucketInfo, Raw, Value};
+
 #[derive(Debug, TError)]
 /// Error type
 pub enum Error {
@@ -12,12 +15,48 @@ pub enum Error {
 
     /// Error when calling `Bucket::compare_and_swap`
     #[error("Compare and swap error: {0}")]
-    CompareAndSwap(#[from] sled::CompareAndSwapError),
+    CompareAndSwap(CompareAndSwapError<Raw>),
+
+    /// A bucket was opened with different types or an older schema version than it was
+    /// created with
+    #[error("Bucket {bucket} was created with {found}, not {expected}")]
+    SchemaMismatch {
+        /// Bucket name
+        bucket: String,
+        /// Types and version used to open the bucket
+        expected: Box<BucketInfo>,
+        /// Types and version recorded in the store
+        found: Box<BucketInfo>,
+    },
+
+    /// A key or value stored in a bucket could not be decoded
+    #[error("Unable to decode {} in bucket {bucket}: {source}", display_key(key))]
+    Decode {
+        /// Bucket name
+        bucket: String,
+        /// Raw key
+        key: Vec<u8>,
+        /// Decoding error
+        source: Box<Error>,
+    },
 
     /// An IO error
     #[error("IO error: {0}")]
     IO(#[from] io::Error),
 
+    /// A fixed width key has the wrong length
+    #[error("Invalid key length: expected {expected} bytes, found {found}")]
+    InvalidKeyLength {
+        /// Expected length
+        expected: usize,
+        /// Actual length
+        found: usize,
+    },
+
+    /// A backup file is invalid or corrupt
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
     /// Configuration is invalid
     #[error("Configuration is invalid")]
     InvalidConfiguration,
@@ -66,6 +105,135 @@ pub enum Error {
     #[cfg(feature = "lexpr-value")]
     #[error("S-Expression error: {0}")]
     Lexpr(#[from] serde_lexpr::Error),
+
+    /// Zstd error
+    #[cfg(feature = "zstd-value")]
+    #[error("Zstd error: {0}")]
+    Zstd(io::Error),
+}
+
+/// Keys are displayed as strings when they are valid UTF-8, otherwise as hex
+fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) => format!("key {:?}", s),
+        Err(_) => {
+            let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("key 0x{}", hex)
+        }
+    }
+}
+
+impl Error {
+    /// Add bucket and key context to a decoding error
+    pub(crate) fn decode(bucket: &[u8], key: &[u8], e: Error) -> Error {
+        if let Error::Decode { .. } = e {
+            return e;
+        }
+        Error::Decode {
+            bucket: String::from_utf8_lossy(bucket).into_owned(),
+            key: key.to_vec(),
+            source: Box::new(e),
+        }
+    }
+
+    /// Returns true when a write failed because the stored value was changed by someone else,
+    /// the operation can be retried
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Error::CompareAndSwap(_))
+    }
+
+    /// Returns true when stored data or a backup could not be decoded
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Error::Sled(sled::Error::Corruption { .. })
+            | Error::Decode { .. }
+            | Error::InvalidBackup(_)
+            | Error::InvalidKeyLength { .. }
+            | Error::Utf8(_)
+            | Error::FromUtf8(_) => true,
+            #[cfg(feature = "json-value")]
+            Error::Json(_) => true,
+            #[cfg(feature = "msgpack-value")]
+            Error::MsgpackDecode(_) => true,
+            #[cfg(feature = "bincode-value")]
+            Error::Bincode(_) => true,
+            #[cfg(feature = "lexpr-value")]
+            Error::Lexpr(_) => true,
+            #[cfg(feature = "zstd-value")]
+            Error::Zstd(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true for errors reading or writing files
+    pub fn is_io(&self) -> bool {
+        matches!(self, Error::IO(_) | Error::Sled(sled::Error::Io(_)))
+    }
+}
+
+/// Returned by `Bucket::compare_and_swap` when the current value doesn't match the expected value
+pub struct CompareAndSwapError<V> {
+    /// The current value
+    pub current: Option<V>,
+    /// The value that was not written
+    pub proposed: Option<V>,
+    raw: Box<(Option<Raw>, Option<Raw>)>,
+}
+
+impl CompareAndSwapError<Raw> {
+    /// Create an error from the raw current and proposed values, returned by
+    /// `Tree::compare_and_swap`
+    pub fn new(current: Option<Raw>, proposed: Option<Raw>) -> CompareAndSwapError<Raw> {
+        CompareAndSwapError {
+            raw: Box::new((current.clone(), proposed.clone())),
+            current,
+            proposed,
+        }
+    }
+}
+
+impl<V: Value> CompareAndSwapError<V> {
+    pub(crate) fn decode(
+        bucket: &[u8],
+        key: &[u8],
+        raw: CompareAndSwapError<Raw>,
+    ) -> Result<CompareAndSwapError<V>, Error> {
+        let decode = |x: &Option<Raw>| {
+            x.clone()
+                .map(V::from_raw_value)
+                .transpose()
+                .map_err(|e| Error::decode(bucket, key, e))
+        };
+        Ok(CompareAndSwapError {
+            current: decode(&raw.current)?,
+            proposed: decode(&raw.proposed)?,
+            raw: raw.raw,
+        })
+    }
+}
+
+impl<V> fmt::Debug for CompareAndSwapError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompareAndSwapError")
+            .field("current", &self.raw.0)
+            .field("proposed", &self.raw.1)
+            .finish()
+    }
+}
+
+impl<V> fmt::Display for CompareAndSwapError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Compare and swap conflict")
+    }
+}
+
+impl<V> std::error::Error for CompareAndSwapError<V> {}
+
+impl<V> From<CompareAndSwapError<V>> for Error {
+    fn from(e: CompareAndSwapError<V>) -> Error {
+        let (current, proposed) = *e.raw;
+        Error::CompareAndSwap(CompareAndSwapError::new(current, proposed))
+    }
 }
 
 impl<T> From<PoisonError<T>> for Error {
@@ -85,9 +253,3 @@ impl From<std::string::FromUtf8Error> for Error {
         Error::FromUtf8(e)
     }
 }
-
-impl From<Error> for sled::transaction::ConflictableTransactionError<Error> {
-    fn from(e: Error) -> sled::transaction::ConflictableTransactionError<Error> {
-        sled::transaction::ConflictableTransactionError::Abort(e)
-    }
-}
@@ -0,0 +1,276 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::backend::{Backend, RawIter, TransactionalTree, Tree};
+use crate::key::{decode_escaped, encode_escaped};
+use crate::ttl::Ttl;
+use crate::{Error, Integer, Item, Key, Raw, TransactionError, Value};
+
+/// Prefix of the hidden trees used to store secondary indexes
+pub(crate) const INDEX_PREFIX: &str = "__kv_index__";
+
+type Extractor = Arc<dyn Fn(&Raw) -> Result<Vec<Raw>, Error> + Send + Sync>;
+
+/// Name of the index tree `index` associated with the tree `name`
+pub(crate) fn tree_name(name: &[u8], index: &str) -> Vec<u8> {
+    let mut dst = INDEX_PREFIX.as_bytes().to_vec();
+    encode_escaped(name, &mut dst);
+    dst.extend_from_slice(index.as_bytes());
+    dst
+}
+
+// Index trees map escaped(index key) + primary key => empty
+fn entry(index_key: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(index_key.len() + key.len() + 2);
+    encode_escaped(index_key, &mut dst);
+    dst.extend_from_slice(key);
+    dst
+}
+
+#[derive(Clone)]
+pub(crate) struct Index {
+    name: String,
+    tree: Arc<dyn Tree>,
+    extract: Extractor,
+}
+
+impl Index {
+    /// Returns true when `value` produces `index_key`
+    fn matches(&self, index_key: &[u8], value: &Raw) -> Result<bool, Error> {
+        let keys = (self.extract)(value)?;
+        Ok(keys.iter().any(|k| k == index_key))
+    }
+}
+
+/// Secondary indexes registered on a bucket
+#[derive(Clone)]
+pub(crate) struct Indexes {
+    backend: Arc<dyn Backend>,
+    name: Vec<u8>,
+    list: Vec<Index>,
+}
+
+impl Indexes {
+    pub(crate) fn new(backend: Arc<dyn Backend>, name: &[u8]) -> Indexes {
+        Indexes {
+            backend,
+            name: name.to_vec(),
+            list: Vec::new(),
+        }
+    }
+
+    /// Name of the bucket the indexes belong to
+    pub(crate) fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Returns true when there are no indexes
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Index trees, in the same order expected by `update`
+    pub(crate) fn trees(&self) -> impl Iterator<Item = &dyn Tree> {
+        self.list.iter().map(|i| &*i.tree)
+    }
+
+    /// Register a new index, replacing any existing index with the same name
+    pub(crate) fn add<'a, V, I, F>(&mut self, name: &str, f: F) -> Result<(), Error>
+    where
+        V: Value,
+        I: Key<'a>,
+        F: 'static + Send + Sync + Fn(&V) -> Vec<I>,
+    {
+        let tree = self.backend.open_tree(&tree_name(&self.name, name))?;
+        let extract: Extractor = Arc::new(move |raw: &Raw| {
+            let value = V::from_raw_value(raw.clone())?;
+            f(&value).iter().map(|k| k.to_raw_key()).collect()
+        });
+        self.list.retain(|i| i.name != name);
+        self.list.push(Index {
+            name: name.to_string(),
+            tree,
+            extract,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<&Index, Error> {
+        match self.list.iter().find(|i| i.name == name) {
+            Some(index) => Ok(index),
+            None => Err(Error::Message(format!("Index not found: {}", name))),
+        }
+    }
+
+    /// Update the index trees `trees` after `key` changes from `old` to `new`
+    pub(crate) fn update(
+        &self,
+        trees: &[&dyn TransactionalTree],
+        key: &[u8],
+        old: Option<&Raw>,
+        new: Option<&Raw>,
+    ) -> Result<(), TransactionError<Error>> {
+        for (index, t) in self.list.iter().zip(trees) {
+            let old = match old {
+                Some(x) => (index.extract)(x)?,
+                None => Vec::new(),
+            };
+            let new = match new {
+                Some(x) => (index.extract)(x)?,
+                None => Vec::new(),
+            };
+
+            for k in old.iter().filter(|k| !new.contains(k)) {
+                t.remove(&entry(k, key))?;
+            }
+
+            for k in new.iter().filter(|k| !old.contains(k)) {
+                t.insert(&entry(k, key), Raw::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuild the index `name` from the contents of `tree`
+    pub(crate) fn rebuild(&self, name: &str, tree: &dyn Tree) -> Result<(), Error> {
+        let index = self.get(name)?;
+        index.tree.clear()?;
+        for x in tree.iter() {
+            let (k, v) = x?;
+            for i in (index.extract)(&v)? {
+                index.tree.insert(&entry(&i, &k), Raw::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all index entries
+    pub(crate) fn clear(&self) -> Result<(), Error> {
+        for index in &self.list {
+            index.tree.clear()?;
+        }
+        Ok(())
+    }
+}
+
+/// Indexes registered on a bucket, shared by every handle to the bucket opened from the same
+/// store. Each write uses the indexes that were registered when it started
+pub(crate) struct SharedIndexes {
+    name: Vec<u8>,
+    current: RwLock<Arc<Indexes>>,
+}
+
+impl SharedIndexes {
+    pub(crate) fn new(indexes: Indexes) -> SharedIndexes {
+        SharedIndexes {
+            name: indexes.name.clone(),
+            current: RwLock::new(Arc::new(indexes)),
+        }
+    }
+
+    /// Name of the bucket the indexes belong to
+    pub(crate) fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Get the indexes that are currently registered
+    pub(crate) fn load(&self) -> Arc<Indexes> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns true when there are no indexes to maintain
+    pub(crate) fn is_empty(&self) -> bool {
+        self.load().is_empty()
+    }
+
+    /// Replace the registered indexes with the result of `f`
+    fn modify<F: FnOnce(&mut Indexes) -> Result<(), Error>>(&self, f: F) -> Result<(), Error> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let mut indexes = Indexes::clone(&current);
+        f(&mut indexes)?;
+        *current = Arc::new(indexes);
+        Ok(())
+    }
+
+    /// Register a new index, replacing any existing index with the same name
+    pub(crate) fn add<'a, V, I, F>(&self, name: &str, f: F) -> Result<(), Error>
+    where
+        V: Value,
+        I: Key<'a>,
+        F: 'static + Send + Sync + Fn(&V) -> Vec<I>,
+    {
+        self.modify(|indexes| indexes.add(name, f))
+    }
+}
+
+/// Iterator over the items in a bucket that match an index key
+pub struct IndexIter<K, V> {
+    iter: RawIter,
+    tree: Arc<dyn Tree>,
+    index: Index,
+    index_key: Raw,
+    ttl: Option<(Ttl, Integer)>,
+    bucket: Raw,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> IndexIter<K, V> {
+    pub(crate) fn new(
+        index: &Index,
+        tree: &Arc<dyn Tree>,
+        ttl: &Ttl,
+        bucket: &[u8],
+        index_key: Raw,
+    ) -> Result<IndexIter<K, V>, Error> {
+        let ttl = if ttl.is_empty()? {
+            None
+        } else {
+            Some((ttl.clone(), ttl.now()?))
+        };
+        let mut prefix = Vec::with_capacity(index_key.len() + 2);
+        encode_escaped(&index_key, &mut prefix);
+        Ok(IndexIter {
+            iter: index.tree.scan_prefix(&prefix),
+            tree: tree.clone(),
+            index: index.clone(),
+            index_key,
+            ttl,
+            bucket: bucket.into(),
+            phantom: PhantomData,
+        })
+    }
+
+    fn next_item(&mut self) -> Result<Option<Item<K, V>>, Error> {
+        for x in self.iter.by_ref() {
+            let (x, _) = x?;
+            let (_, key) = decode_escaped(&x)?;
+            let key = Raw::from(key);
+
+            if let Some((ttl, now)) = &self.ttl {
+                if ttl.is_expired(&key, now)? {
+                    continue;
+                }
+            }
+
+            // Entries can be stale if the bucket was modified without this index registered
+            if let Some(value) = self.tree.get(&key)? {
+                if self.index.matches(&self.index_key, &value)? {
+                    return Ok(Some(Item::new(&self.bucket, key, value)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<K, V> Iterator for IndexIter<K, V> {
+    type Item = Result<Item<K, V>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().transpose()
+    }
+}
@@ -1,16 +1,32 @@
 use std::mem;
 use std::time::SystemTime;
 
-use crate::{Error, Raw};
+use crate::{Error, Raw, Value};
 
 /// A Key can be used as a key to a database
-pub trait Key<'a>: Sized + AsRef<[u8]> {
+pub trait Key<'a>: Sized {
+    /// Stable name of the key encoding, recorded the first time a bucket is opened with this
+    /// key type. Opening the bucket again with a key type that has a different tag returns
+    /// `Error::SchemaMismatch`, types without a tag are not checked
+    const TYPE_TAG: Option<&'static str> = None;
+
     /// Convert from Raw
     fn from_raw_key(r: &'a Raw) -> Result<Self, Error>;
 
-    /// Wrapper around AsRef<[u8]>
-    fn to_raw_key(&self) -> Result<Raw, Error> {
-        Ok(self.as_ref().into())
+    /// Convert to Raw
+    fn to_raw_key(&self) -> Result<Raw, Error>;
+}
+
+/// A value that can be used to scan keys of type `K` by prefix, this is implemented for all keys
+/// and for the leading elements of tuple keys
+pub trait Prefix<K> {
+    /// Convert to Raw prefix
+    fn to_raw_prefix(&self) -> Result<Raw, Error>;
+}
+
+impl<'a, K: Key<'a>> Prefix<K> for K {
+    fn to_raw_prefix(&self) -> Result<Raw, Error> {
+        self.to_raw_key()
     }
 }
 
@@ -18,45 +34,141 @@ impl<'a> Key<'a> for Raw {
     fn from_raw_key(x: &Raw) -> Result<Self, Error> {
         Ok(x.clone())
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.clone())
+    }
 }
 
 impl<'a> Key<'a> for &'a [u8] {
+    const TYPE_TAG: Option<&'static str> = Some("Bytes");
+
     fn from_raw_key(x: &'a Raw) -> Result<&'a [u8], Error> {
         Ok(x.as_ref())
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.as_ref().into())
+    }
 }
 
 impl<'a> Key<'a> for &'a str {
+    const TYPE_TAG: Option<&'static str> = Some("String");
+
     fn from_raw_key(x: &'a Raw) -> Result<Self, Error> {
         Ok(std::str::from_utf8(x.as_ref())?)
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.as_bytes().into())
+    }
 }
 
 impl<'a> Key<'a> for Vec<u8> {
+    const TYPE_TAG: Option<&'static str> = Some("Bytes");
+
     fn from_raw_key(r: &Raw) -> Result<Self, Error> {
         Ok(r.to_vec())
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.as_slice().into())
+    }
 }
 
 impl<'a> Key<'a> for String {
+    const TYPE_TAG: Option<&'static str> = Some("String");
+
     fn from_raw_key(x: &Raw) -> Result<Self, Error> {
         Ok(std::str::from_utf8(x.as_ref())?.to_string())
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.as_bytes().into())
+    }
 }
 
 impl<'a> Key<'a> for Integer {
+    const TYPE_TAG: Option<&'static str> = Some("Integer");
+
     fn from_raw_key(x: &Raw) -> Result<Integer, Error> {
-        Ok(Integer::from(x.as_ref()))
+        Integer::try_from(x.as_ref())
     }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.as_ref().into())
+    }
+}
+
+// Tuple elements are escaped and terminated by `encode_escaped`, so the encoding of the leading
+// elements of a tuple is a prefix of the encoding of the whole tuple
+macro_rules! tuple_key {
+    ($($t:ident $v:ident $i:tt),+) => {
+        impl<'a, $($t: for<'x> Key<'x>),+> Key<'a> for ($($t,)+) {
+            fn from_raw_key(r: &'a Raw) -> Result<Self, Error> {
+                let src: &[u8] = r.as_ref();
+                $(
+                    let ($v, src) = decode_escaped(src)?;
+                    let $v = $t::from_raw_key(&Raw::from($v))?;
+                )+
+                if !src.is_empty() {
+                    return Err(Error::Message("Invalid tuple key encoding".into()));
+                }
+                Ok(($($v,)+))
+            }
+
+            fn to_raw_key(&self) -> Result<Raw, Error> {
+                let mut dst = Vec::new();
+                $(encode_escaped(&self.$i.to_raw_key()?, &mut dst);)+
+                Ok(dst.into())
+            }
+        }
+    };
+}
+
+macro_rules! tuple_prefix {
+    (($($t:ident $i:tt),+) for $k:ty, $($u:ident),+) => {
+        impl<$($u: for<'x> Key<'x>),+> Prefix<$k> for ($($t,)+) {
+            fn to_raw_prefix(&self) -> Result<Raw, Error> {
+                let mut dst = Vec::new();
+                $(encode_escaped(&self.$i.to_raw_key()?, &mut dst);)+
+                Ok(dst.into())
+            }
+        }
+    };
 }
 
-/// Integer key type
+tuple_key!(A a 0, B b 1);
+tuple_key!(A a 0, B b 1, C c 2);
+tuple_key!(A a 0, B b 1, C c 2, D d 3);
+tuple_key!(A a 0, B b 1, C c 2, D d 3, E e 4);
+tuple_key!(A a 0, B b 1, C c 2, D d 3, E e 4, F f 5);
+
+tuple_prefix!((A 0) for (A, B), A, B);
+tuple_prefix!((A 0) for (A, B, C), A, B, C);
+tuple_prefix!((A 0, B 1) for (A, B, C), A, B, C);
+tuple_prefix!((A 0) for (A, B, C, D), A, B, C, D);
+tuple_prefix!((A 0, B 1) for (A, B, C, D), A, B, C, D);
+tuple_prefix!((A 0, B 1, C 2) for (A, B, C, D), A, B, C, D);
+tuple_prefix!((A 0) for (A, B, C, D, E), A, B, C, D, E);
+tuple_prefix!((A 0, B 1) for (A, B, C, D, E), A, B, C, D, E);
+tuple_prefix!((A 0, B 1, C 2) for (A, B, C, D, E), A, B, C, D, E);
+tuple_prefix!((A 0, B 1, C 2, D 3) for (A, B, C, D, E), A, B, C, D, E);
+tuple_prefix!((A 0) for (A, B, C, D, E, F), A, B, C, D, E, F);
+tuple_prefix!((A 0, B 1) for (A, B, C, D, E, F), A, B, C, D, E, F);
+tuple_prefix!((A 0, B 1, C 2) for (A, B, C, D, E, F), A, B, C, D, E, F);
+tuple_prefix!((A 0, B 1, C 2, D 3) for (A, B, C, D, E, F), A, B, C, D, E, F);
+tuple_prefix!((A 0, B 1, C 2, D 3, E 4) for (A, B, C, D, E, F), A, B, C, D, E, F);
+
+/// Integer key type, an unsigned 128-bit integer stored in big-endian order
+///
+/// Note: signed values are converted to `u128`, use `I32`, `I64`, ... for signed keys
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Integer([u8; 16]);
 
 impl From<u128> for Integer {
     fn from(i: u128) -> Integer {
-        unsafe { Integer(mem::transmute(i.to_be())) }
+        Integer(i.to_be_bytes())
     }
 }
 
@@ -89,14 +201,8 @@ impl From<usize> for Integer {
 }
 
 impl From<Integer> for u128 {
-    #[cfg(target_endian = "big")]
-    fn from(i: Integer) -> u128 {
-        unsafe { mem::transmute(i.0) }
-    }
-
-    #[cfg(target_endian = "little")]
     fn from(i: Integer) -> u128 {
-        u128::from_be(unsafe { mem::transmute(i.0) })
+        u128::from_be_bytes(i.0)
     }
 }
 
@@ -120,14 +226,142 @@ impl AsRef<[u8]> for Integer {
     }
 }
 
-impl<'a> From<&'a [u8]> for Integer {
-    fn from(buf: &'a [u8]) -> Integer {
-        let mut dst = Integer::from(0u128);
-        dst.0[..16].clone_from_slice(&buf[..16]);
-        dst
+impl<'a> TryFrom<&'a [u8]> for Integer {
+    type Error = Error;
+
+    fn try_from(buf: &'a [u8]) -> Result<Integer, Error> {
+        Ok(Integer(fixed_width(buf)?))
+    }
+}
+
+fn fixed_width<const N: usize>(buf: &[u8]) -> Result<[u8; N], Error> {
+    match <[u8; N]>::try_from(buf) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(Error::InvalidKeyLength {
+            expected: N,
+            found: buf.len(),
+        }),
     }
 }
 
+macro_rules! int_key {
+    ($name:ident, $t:ty, $u:ty, $flip:expr, $doc:expr) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name([u8; mem::size_of::<$t>()]);
+
+        impl From<$t> for $name {
+            fn from(i: $t) -> $name {
+                $name(((i as $u) ^ $flip).to_be_bytes())
+            }
+        }
+
+        impl From<$name> for $t {
+            fn from(i: $name) -> $t {
+                (<$u>::from_be_bytes(i.0) ^ $flip) as $t
+            }
+        }
+
+        ordered_key!($name);
+    };
+}
+
+macro_rules! float_key {
+    ($name:ident, $t:ty, $u:ty, $doc:expr) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name([u8; mem::size_of::<$t>()]);
+
+        impl From<$t> for $name {
+            fn from(f: $t) -> $name {
+                let bits = f.to_bits();
+                // Negative values have all bits flipped so larger magnitudes sort first,
+                // positive values only have the sign bit set so they sort after negatives
+                let bits = if bits >> (<$u>::BITS - 1) == 1 {
+                    !bits
+                } else {
+                    bits | (1 << (<$u>::BITS - 1))
+                };
+                $name(bits.to_be_bytes())
+            }
+        }
+
+        impl From<$name> for $t {
+            fn from(f: $name) -> $t {
+                let bits = <$u>::from_be_bytes(f.0);
+                let bits = if bits >> (<$u>::BITS - 1) == 1 {
+                    bits & !(1 << (<$u>::BITS - 1))
+                } else {
+                    !bits
+                };
+                <$t>::from_bits(bits)
+            }
+        }
+
+        ordered_key!($name);
+    };
+}
+
+macro_rules! ordered_key {
+    ($name:ident) => {
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl<'a> TryFrom<&'a [u8]> for $name {
+            type Error = Error;
+
+            fn try_from(buf: &'a [u8]) -> Result<$name, Error> {
+                Ok($name(fixed_width(buf)?))
+            }
+        }
+
+        impl<'a> Key<'a> for $name {
+            const TYPE_TAG: Option<&'static str> = Some(stringify!($name));
+
+            fn from_raw_key(x: &Raw) -> Result<$name, Error> {
+                $name::try_from(x.as_ref())
+            }
+
+            fn to_raw_key(&self) -> Result<Raw, Error> {
+                Ok(self.0.as_ref().into())
+            }
+        }
+
+        impl Value for $name {
+            const TYPE_TAG: Option<&'static str> = Some(stringify!($name));
+
+            fn to_raw_value(&self) -> Result<Raw, Error> {
+                Ok(self.0.as_ref().into())
+            }
+
+            fn from_raw_value(r: Raw) -> Result<$name, Error> {
+                $name::try_from(r.as_ref())
+            }
+        }
+    };
+}
+
+int_key!(I8, i8, u8, 1 << 7, "Order-preserving `i8` key type");
+int_key!(I16, i16, u16, 1 << 15, "Order-preserving `i16` key type");
+int_key!(I32, i32, u32, 1 << 31, "Order-preserving `i32` key type");
+int_key!(I64, i64, u64, 1 << 63, "Order-preserving `i64` key type");
+int_key!(
+    I128,
+    i128,
+    u128,
+    1 << 127,
+    "Order-preserving `i128` key type"
+);
+int_key!(U8, u8, u8, 0, "Order-preserving `u8` key type");
+int_key!(U16, u16, u16, 0, "Order-preserving `u16` key type");
+int_key!(U32, u32, u32, 0, "Order-preserving `u32` key type");
+int_key!(U64, u64, u64, 0, "Order-preserving `u64` key type");
+float_key!(F32, f32, u32, "Order-preserving `f32` key type");
+float_key!(F64, f64, u64, "Order-preserving `f64` key type");
+
 impl Integer {
     /// Current timestamp in seconds from the Unix epoch
     pub fn timestamp() -> Result<Integer, Error> {
@@ -141,3 +375,38 @@ impl Integer {
         Ok(Integer::from(ts.as_millis()))
     }
 }
+
+/// Append `src` to `dst` so that the encoded values sort in the same order as the inputs and can
+/// be followed by other data: `0x00` is escaped as `0x00 0xff` and the end is marked by
+/// `0x00 0x00`
+pub fn encode_escaped(src: &[u8], dst: &mut Vec<u8>) {
+    for b in src {
+        dst.push(*b);
+        if *b == 0 {
+            dst.push(0xff);
+        }
+    }
+    dst.extend_from_slice(&[0, 0]);
+}
+
+/// Decode a value written using `encode_escaped`, returning the value and the remaining input
+pub fn decode_escaped(src: &[u8]) -> Result<(Vec<u8>, &[u8]), Error> {
+    let mut dst = Vec::with_capacity(src.len());
+    let mut i = 0;
+    while i < src.len() {
+        if src[i] == 0 {
+            match src.get(i + 1) {
+                Some(0) => return Ok((dst, &src[i + 2..])),
+                Some(0xff) => {
+                    dst.push(0);
+                    i += 2;
+                    continue;
+                }
+                _ => break,
+            }
+        }
+        dst.push(src[i]);
+        i += 1;
+    }
+    Err(Error::Message("Invalid escaped key encoding".into()))
+}
@@ -74,23 +74,66 @@
 //! # }
 //! ```
 
+mod async_store;
+pub mod backend;
+mod backup;
+mod blob;
 mod bucket;
 mod codec;
+mod collections;
+#[cfg(feature = "zstd-value")]
+mod compressed;
 mod config;
 mod error;
+mod index;
 mod key;
+mod memory;
+mod memory_copy;
+mod pool;
+mod queue;
+mod schema;
 mod store;
+mod timeseries;
 mod transaction;
+mod ttl;
 mod value;
+mod versioned;
+mod watch;
 
-pub use bucket::{Batch, Bucket, Event, Item, Iter, Watch};
+pub use async_store::{AsyncBucket, AsyncIter, AsyncStore, AsyncWatch};
+pub use backend::Backend;
+pub use blob::{Blob, BlobBucket, BlobReader};
+pub use bucket::{Batch, Bucket, Item, Iter};
 pub use codec::*;
+pub use collections::{MultiMap, MultiMapTransaction, SortedSet, SortedSetTransaction};
+#[cfg(feature = "zstd-value")]
+pub use compressed::Zstd;
 pub use config::Config;
-pub use error::Error;
-pub use key::{Integer, Key};
+pub use error::{CompareAndSwapError, Error};
+pub use index::IndexIter;
+pub use key::{Integer, Key, Prefix, F32, F64, I128, I16, I32, I64, I8, U16, U32, U64, U8};
+pub use memory::MemoryBackend;
+pub use memory_copy::{MemoryCopy, MemoryCopyBucket};
+pub use queue::{Lease, Queue};
+pub use schema::BucketInfo;
 pub use store::Store;
-pub use transaction::{Transaction, TransactionError};
+pub use timeseries::{Aggregate, TimeSeries};
+pub use transaction::{Buckets, Transaction, TransactionError, Transactions};
 pub use value::{Raw, Value};
+pub use versioned::{Migrate, Versioned};
+pub use watch::{Event, Watch};
+
+#[cfg(feature = "derive")]
+pub use kv_derive::{Key, Value};
+
+// Allows the derive macros to refer to `::kv` in tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as kv;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::key::{decode_escaped, encode_escaped};
+}
 
 /// Abort a transaction
 pub fn abort<E>(x: E) -> TransactionError<E> {
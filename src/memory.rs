@@ -0,0 +1,438 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use crate::backend::{
+    import_entry, is_empty_range, Backend, Export, MergeFn, RawIter, TransactionFn,
+    TransactionResult, TransactionalTree, Tree,
+};
+use crate::{CompareAndSwapError, Error, Raw, TransactionError};
+
+pub(crate) type Map = BTreeMap<Raw, Raw>;
+
+/// A range read inside of a transaction and the committed items it contained
+type RangeRead = (Bound<Raw>, Bound<Raw>, Vec<(Raw, Raw)>);
+
+fn read<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(lock: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A backend that keeps all data in memory using `BTreeMap`, nothing is written to disk
+///
+/// ```rust
+/// use kv::*;
+///
+/// let store = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+/// let bucket = store.bucket::<&str, String>(Some("test")).unwrap();
+/// bucket.set(&"a", &String::from("b")).unwrap();
+/// assert_eq!(bucket.get(&"a").unwrap(), Some(String::from("b")));
+/// ```
+#[derive(Default)]
+pub struct MemoryBackend {
+    trees: RwLock<BTreeMap<Raw, Arc<MemoryTree>>>,
+    shared: Arc<Shared>,
+}
+
+/// State shared by all trees in a `MemoryBackend`
+#[derive(Default)]
+struct Shared {
+    id: AtomicU64,
+    commit: Mutex<()>,
+}
+
+impl MemoryBackend {
+    /// Create a new, empty in-memory backend
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// Create a backend containing the given trees
+    pub(crate) fn from_trees<I: IntoIterator<Item = (Raw, Map)>>(trees: I) -> MemoryBackend {
+        let backend = MemoryBackend::new();
+        {
+            let mut dst = write(&backend.trees);
+            for (name, data) in trees {
+                let tree = MemoryTree {
+                    name: name.clone(),
+                    data: Arc::new(RwLock::new(data)),
+                    shared: backend.shared.clone(),
+                };
+                dst.insert(name, Arc::new(tree));
+            }
+        }
+        backend
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn Tree>, Error> {
+        let mut trees = write(&self.trees);
+        let tree = trees.entry(name.into()).or_insert_with(|| {
+            Arc::new(MemoryTree {
+                name: name.into(),
+                data: Arc::default(),
+                shared: self.shared.clone(),
+            })
+        });
+        Ok(tree.clone())
+    }
+
+    fn drop_tree(&self, name: &[u8]) -> Result<bool, Error> {
+        Ok(write(&self.trees).remove(name).is_some())
+    }
+
+    fn tree_names(&self) -> Vec<Raw> {
+        read(&self.trees).keys().cloned().collect()
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        Ok(self.shared.id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn next_id(&self) -> Result<u64, Error> {
+        Ok(self.shared.id.load(Ordering::SeqCst))
+    }
+
+    fn reserve_ids(&self, min: u64) -> Result<(), Error> {
+        self.shared.id.fetch_max(min, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn size_on_disk(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    fn export(&self) -> Export {
+        read(&self.trees)
+            .values()
+            .map(|tree| {
+                let data: Vec<Vec<Vec<u8>>> = read(&tree.data)
+                    .iter()
+                    .map(|(k, v)| vec![k.to_vec(), v.to_vec()])
+                    .collect();
+                let iter: Box<dyn Iterator<Item = Vec<Vec<u8>>>> = Box::new(data.into_iter());
+                (b"tree".to_vec(), tree.name.to_vec(), iter)
+            })
+            .collect()
+    }
+
+    fn copy_to_memory(&self) -> Result<Arc<dyn Backend>, Error> {
+        // Trees are locked in name order, the same as transactions, so every tree is copied at
+        // the same point in time
+        let trees = read(&self.trees);
+        let guards: Vec<_> = trees.values().map(|tree| read(&tree.data)).collect();
+        let data = trees
+            .keys()
+            .zip(guards.iter())
+            .map(|(name, data)| (name.clone(), (**data).clone()));
+        Ok(Arc::new(MemoryBackend::from_trees(data)))
+    }
+
+    fn import(&self, export: Export) -> Result<(), Error> {
+        for (_, name, data) in export {
+            let tree = self.open_tree(&name)?;
+            for kv in data {
+                let (key, value) = import_entry(&name, kv)?;
+                tree.insert(&key, value.into())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single tree in a `MemoryBackend`
+struct MemoryTree {
+    name: Raw,
+    data: Arc<RwLock<Map>>,
+    shared: Arc<Shared>,
+}
+
+/// Set or remove `key`, returning the previous value
+fn update(data: &mut Map, key: &[u8], value: Option<Raw>) -> Option<Raw> {
+    match value {
+        Some(value) => data.insert(key.into(), value),
+        None => data.remove(key),
+    }
+}
+
+impl Tree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Raw>, Error> {
+        Ok(read(&self.data).get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: Raw) -> Result<Option<Raw>, Error> {
+        Ok(update(&mut write(&self.data), key, Some(value)))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Raw>, Error> {
+        Ok(update(&mut write(&self.data), key, None))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<Raw>,
+        new: Option<Raw>,
+    ) -> Result<Result<(), CompareAndSwapError<Raw>>, Error> {
+        let mut data = write(&self.data);
+        let current = data.get(key).cloned();
+        if current != old {
+            return Ok(Err(CompareAndSwapError::new(current, new)));
+        }
+        update(&mut data, key, new);
+        Ok(Ok(()))
+    }
+
+    fn merge(&self, key: &[u8], value: &[u8], f: &MergeFn) -> Result<Option<Raw>, Error> {
+        let mut data = write(&self.data);
+        let new = f(key, data.get(key).map(|x| x.as_ref()), value)?;
+        update(&mut data, key, new.clone());
+        Ok(new)
+    }
+
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> RawIter {
+        Box::new(MemoryIter {
+            data: self.data.clone(),
+            start,
+            end,
+        })
+    }
+
+    fn apply_batch(&self, batch: &[(Raw, Option<Raw>)]) -> Result<(), Error> {
+        let mut data = write(&self.data);
+        for (k, v) in batch {
+            update(&mut data, k, v.clone());
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        read(&self.data).len()
+    }
+
+    fn is_empty(&self) -> bool {
+        read(&self.data).is_empty()
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        write(&self.data).clear();
+        Ok(())
+    }
+
+    fn checksum(&self) -> Result<u32, Error> {
+        let mut hasher = crc32fast::Hasher::new();
+        for (k, v) in read(&self.data).iter() {
+            hasher.update(k);
+            hasher.update(v);
+        }
+        Ok(hasher.finalize())
+    }
+
+    fn flush(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn flush_async(&self) -> Pin<Box<dyn Future<Output = Result<usize, Error>> + Send + '_>> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&dyn Tree],
+        f: &TransactionFn,
+    ) -> Result<(), TransactionError<()>> {
+        let mut memory_trees = Vec::with_capacity(trees.len());
+        for t in trees {
+            match t.as_any().downcast_ref::<MemoryTree>() {
+                Some(t) if Arc::ptr_eq(&t.shared, &self.shared) => memory_trees.push(t),
+                _ => {
+                    return Err(TransactionError::Storage(Error::Message(
+                        "cannot use trees from multiple backends in the same transaction".into(),
+                    )))
+                }
+            }
+        }
+
+        loop {
+            let views: Vec<MemoryTransactionalTree> = memory_trees
+                .iter()
+                .map(|tree| MemoryTransactionalTree {
+                    tree,
+                    reads: RefCell::default(),
+                    ranges: RefCell::default(),
+                    writes: RefCell::default(),
+                })
+                .collect();
+            let dyn_views: Vec<&dyn TransactionalTree> =
+                views.iter().map(|x| x as &dyn TransactionalTree).collect();
+
+            match f(&dyn_views) {
+                Ok(()) => (),
+                Err(TransactionError::Conflict) => continue,
+                Err(e) => return Err(e),
+            }
+
+            if commit(&self.shared, &views) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Apply the writes from `views` if none of the keys they read have changed, returns false if
+/// the transaction needs to be retried
+fn commit(shared: &Shared, views: &[MemoryTransactionalTree]) -> bool {
+    let _commit = lock(&shared.commit);
+
+    // Lock each tree once, in a consistent order
+    let mut trees: Vec<&MemoryTree> = views.iter().map(|v| v.tree).collect();
+    trees.sort_by(|a, b| a.name.cmp(&b.name));
+    trees.dedup_by(|a, b| a.name == b.name);
+    let mut guards: Vec<_> = trees.iter().map(|t| write(&t.data)).collect();
+    let index = |tree: &MemoryTree| trees.iter().position(|t| t.name == tree.name).unwrap();
+
+    for view in views {
+        let data = &guards[index(view.tree)];
+        for (k, v) in view.reads.borrow().iter() {
+            if data.get(k) != v.as_ref() {
+                return false;
+            }
+        }
+        for (start, end, items) in view.ranges.borrow().iter() {
+            let current = data.range((start.clone(), end.clone()));
+            if !current.eq(items.iter().map(|(k, v)| (k, v))) {
+                return false;
+            }
+        }
+    }
+
+    for view in views {
+        let data = &mut guards[index(view.tree)];
+        for (k, v) in view.writes.borrow_mut().iter() {
+            update(data, k, v.clone());
+        }
+    }
+
+    true
+}
+
+/// A `MemoryTree` inside of a transaction, writes are buffered until the transaction is committed
+struct MemoryTransactionalTree<'a> {
+    tree: &'a MemoryTree,
+    reads: RefCell<Vec<(Raw, Option<Raw>)>>,
+    ranges: RefCell<Vec<RangeRead>>,
+    writes: RefCell<BTreeMap<Raw, Option<Raw>>>,
+}
+
+impl<'a> TransactionalTree for MemoryTransactionalTree<'a> {
+    fn get(&self, key: &[u8]) -> TransactionResult<Option<Raw>> {
+        if let Some(v) = self.writes.borrow().get(key) {
+            return Ok(v.clone());
+        }
+        let v = read(&self.tree.data).get(key).cloned();
+        self.reads.borrow_mut().push((key.into(), v.clone()));
+        Ok(v)
+    }
+
+    fn insert(&self, key: &[u8], value: Raw) -> TransactionResult<Option<Raw>> {
+        let prev = TransactionalTree::get(self, key)?;
+        self.writes.borrow_mut().insert(key.into(), Some(value));
+        Ok(prev)
+    }
+
+    fn remove(&self, key: &[u8]) -> TransactionResult<Option<Raw>> {
+        let prev = TransactionalTree::get(self, key)?;
+        self.writes.borrow_mut().insert(key.into(), None);
+        Ok(prev)
+    }
+
+    fn generate_id(&self) -> TransactionResult<u64> {
+        Ok(self.tree.shared.id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn range(&self, start: Bound<Raw>, end: Bound<Raw>) -> TransactionResult<Vec<(Raw, Raw)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+
+        // The committed items are validated on commit, then the buffered writes are applied
+        let items: Vec<(Raw, Raw)> = read(&self.tree.data)
+            .range((start.clone(), end.clone()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let mut merged: Map = items.iter().cloned().collect();
+        for (k, v) in self.writes.borrow().range((start.clone(), end.clone())) {
+            update(&mut merged, k, v.clone());
+        }
+        self.ranges.borrow_mut().push((start, end, items));
+        Ok(merged.into_iter().collect())
+    }
+}
+
+/// Lazy iterator over a range of a `MemoryTree`, the lock is only held while fetching each item
+struct MemoryIter {
+    data: Arc<RwLock<Map>>,
+    start: Bound<Raw>,
+    end: Bound<Raw>,
+}
+
+impl MemoryIter {
+    fn is_empty(&self) -> bool {
+        is_empty_range(&self.start, &self.end)
+    }
+
+    fn range(&self) -> (Bound<Raw>, Bound<Raw>) {
+        (self.start.clone(), self.end.clone())
+    }
+}
+
+impl Iterator for MemoryIter {
+    type Item = Result<(Raw, Raw), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_empty() {
+            return None;
+        }
+        let (k, v) = read(&self.data)
+            .range(self.range())
+            .next()
+            .map(|(k, v)| (k.clone(), v.clone()))?;
+        self.start = Bound::Excluded(k.clone());
+        Some(Ok((k, v)))
+    }
+}
+
+impl DoubleEndedIterator for MemoryIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.is_empty() {
+            return None;
+        }
+        let (k, v) = read(&self.data)
+            .range(self.range())
+            .next_back()
+            .map(|(k, v)| (k.clone(), v.clone()))?;
+        self.end = Bound::Excluded(k.clone());
+        Some(Ok((k, v)))
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use crate::{Bucket, Error, Integer, Item, Iter, Key, Prefix, Store, Value};
+
+/// Read-only copy of every bucket in a `Store`, held in memory and created using
+/// `Store::copy_to_memory`
+///
+/// Writes to the store after the copy has been made are not visible. Keys with an expiry time are
+/// expired as of the time the copy was made, and opening a bucket never records its types.
+/// Making a copy blocks writers, see `Store::copy_to_memory` for the cost
+///
+/// ```rust
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-memory-copy").temporary(true))?;
+/// let bucket = store.bucket::<&str, String>(Some("a"))?;
+/// bucket.set(&"x", &"1".to_string())?;
+///
+/// let copy = store.copy_to_memory()?;
+/// bucket.set(&"x", &"2".to_string())?;
+///
+/// let a = copy.bucket::<&str, String>(Some("a"))?;
+/// assert_eq!(a.get(&"x")?, Some("1".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MemoryCopy(Store, Integer);
+
+/// Read-only view of a bucket in a `MemoryCopy`
+pub struct MemoryCopyBucket<'a, K: Key<'a>, V: Value>(Bucket<'a, K, V>);
+
+impl<'a, K: Key<'a>, V: Value> Clone for MemoryCopyBucket<'a, K, V> {
+    fn clone(&self) -> Self {
+        MemoryCopyBucket(self.0.clone())
+    }
+}
+
+impl MemoryCopy {
+    pub(crate) fn new(store: Store, now: Integer) -> MemoryCopy {
+        MemoryCopy(store, now)
+    }
+
+    /// Get a list of bucket names
+    pub fn buckets(&self) -> Vec<String> {
+        self.0.buckets()
+    }
+
+    /// Open a bucket, buckets that didn't exist when the copy was made are empty.
+    /// Returns `Error::SchemaMismatch` if the types don't match those recorded for the bucket
+    pub fn bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+    ) -> Result<MemoryCopyBucket<'a, K, V>, Error> {
+        Ok(MemoryCopyBucket(self.0.copied_bucket(name, self.1)?))
+    }
+}
+
+impl<'a, K: Key<'a>, V: Value> MemoryCopyBucket<'a, K, V> {
+    /// Returns true if the bucket contains the given key
+    pub fn contains(&self, key: &K) -> Result<bool, Error> {
+        self.0.contains(key)
+    }
+
+    /// Get the value associated with the specified key
+    pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        self.0.get(key)
+    }
+
+    /// Get the time remaining until the specified key expires, measured from the time the
+    /// copy was made
+    pub fn ttl(&self, key: &K) -> Result<Option<Duration>, Error> {
+        self.0.ttl(key)
+    }
+
+    /// Get an iterator over keys/values
+    pub fn iter(&self) -> Iter<K, V> {
+        self.0.iter()
+    }
+
+    /// Get an iterator over keys/values in the specified range
+    pub fn iter_range(&self, a: &K, b: &K) -> Result<Iter<K, V>, Error> {
+        self.0.iter_range(a, b)
+    }
+
+    /// Iterate over keys/values with the specified prefix
+    pub fn iter_prefix<P: Prefix<K>>(&self, a: &P) -> Result<Iter<K, V>, Error> {
+        self.0.iter_prefix(a)
+    }
+
+    /// Get previous key and value in order, if one exists
+    pub fn prev_key(&self, key: &K) -> Result<Option<Item<K, V>>, Error> {
+        self.0.prev_key(key)
+    }
+
+    /// Get next key and value in order, if one exists
+    pub fn next_key(&self, key: &K) -> Result<Option<Item<K, V>>, Error> {
+        self.0.next_key(key)
+    }
+
+    /// Get the first item
+    pub fn first(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.0.first()
+    }
+
+    /// Get the last item
+    pub fn last(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.0.last()
+    }
+
+    /// Get the number of items, this includes expired items that haven't been purged yet
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true when there are no items
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// CRC32 checksum of all keys and values
+    pub fn checksum(&self) -> Result<u32, Error> {
+        self.0.checksum()
+    }
+}
@@ -0,0 +1,92 @@
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed size pool of threads used to run blocking operations
+pub(crate) struct ThreadPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    /// Start `threads` worker threads, they exit when the pool is dropped
+    pub(crate) fn new(threads: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("kv-worker-{}", i))
+                .spawn(move || loop {
+                    let job = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn kv worker thread");
+        }
+        ThreadPool {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Run `f` on the pool, the returned task resolves to its result. Panics are propagated to
+    /// the task
+    pub(crate) fn spawn<T, F>(&self, f: F) -> Task<T>
+    where
+        T: 'static + Send,
+        F: 'static + Send + FnOnce() -> T,
+    {
+        let state = Arc::new(Mutex::new(State {
+            result: None,
+            waker: None,
+        }));
+        let task = Task(state.clone());
+        let job = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        self.sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .send(job)
+            .expect("kv worker threads have stopped");
+        task
+    }
+}
+
+struct State<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// The result of a function running on a `ThreadPool`
+pub(crate) struct Task<T>(Arc<Mutex<State<T>>>);
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.result.take() {
+            Some(Ok(x)) => Poll::Ready(x),
+            Some(Err(e)) => panic::resume_unwind(e),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
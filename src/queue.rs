@@ -0,0 +1,411 @@
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use crate::key::encode_escaped;
+use crate::{Bucket, Buckets, Error, Integer, Item, Key, Raw, Store, TransactionError, Value, U64};
+
+// Queue entries are keyed by an id from `Store::generate_id` and encoded as a big-endian u64
+// lease deadline in milliseconds from the Unix epoch (0 when the entry isn't leased), a
+// big-endian u32 delivery count and the value. The schedule bucket has an empty entry keyed by
+// (deadline, id) for every entry, so available entries can be found without a scan
+const HEADER_LEN: usize = 12;
+
+type ScheduleKey = (U64, Integer);
+
+struct Entry {
+    deadline: u64,
+    attempts: u32,
+    value: Raw,
+}
+
+impl Entry {
+    fn encode(&self) -> Raw {
+        let mut dst = Vec::with_capacity(self.value.len() + HEADER_LEN);
+        dst.extend_from_slice(&self.deadline.to_be_bytes());
+        dst.extend_from_slice(&self.attempts.to_be_bytes());
+        dst.extend_from_slice(&self.value);
+        dst.into()
+    }
+
+    fn decode(r: &[u8]) -> Result<Entry, Error> {
+        if r.len() < HEADER_LEN {
+            return Err(Error::Message("Invalid queue entry".into()));
+        }
+        let mut deadline = [0; 8];
+        deadline.copy_from_slice(&r[..8]);
+        let mut attempts = [0; 4];
+        attempts.copy_from_slice(&r[8..HEADER_LEN]);
+        Ok(Entry {
+            deadline: u64::from_be_bytes(deadline),
+            attempts: u32::from_be_bytes(attempts),
+            value: Raw::from(&r[HEADER_LEN..]),
+        })
+    }
+}
+
+fn now_ms() -> Result<u64, Error> {
+    Ok(u64::from(Integer::timestamp_ms()?))
+}
+
+/// Raw prefix of the schedule entries with the lease deadline `deadline`
+fn deadline_prefix(deadline: u64) -> Result<Raw, Error> {
+    let mut dst = Vec::new();
+    encode_escaped(&U64::from(deadline).to_raw_key()?, &mut dst);
+    Ok(dst.into())
+}
+
+fn schedule_key(item: Result<Item<ScheduleKey, Raw>, Error>) -> Result<(u64, Integer), Error> {
+    let (deadline, id): ScheduleKey = item?.key()?;
+    Ok((u64::from(deadline), id))
+}
+
+/// A value taken from a `Queue` using `pop`, it's hidden from other consumers until it's
+/// acknowledged using `Queue::ack` or the visibility timeout expires
+pub struct Lease<V> {
+    id: u64,
+    attempts: u32,
+    value: V,
+    entry: Raw,
+}
+
+impl<V> Lease<V> {
+    /// Get the id assigned to the value by `Queue::push`
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the number of times the value has been delivered, including this one
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Get the value
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+
+    /// Convert into the value
+    pub fn into_value(self) -> V {
+        self.value
+    }
+}
+
+/// Persistent FIFO queue stored in a bucket. Values are delivered in the order they were pushed,
+/// including after the store is reopened.
+///
+/// Values returned by `pop` are leased: they are redelivered if they haven't been acknowledged
+/// using `ack` before the visibility timeout expires, or if they are returned using `nack`.
+/// Values that have been delivered `max_attempts` times without being acknowledged are moved to
+/// the dead letter bucket, named `<name>.dead_letters`. Expired leases are redelivered in the
+/// order they expired, merged by id with the values that are ready.
+///
+/// Entries are stored in the bucket `name`, and ordered by lease deadline in the bucket
+/// `<name>.by_deadline` so `pop` doesn't need to skip over leased values.
+///
+/// ```rust
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-queue").temporary(true))?;
+/// let queue = Queue::<String>::new(&store, "jobs")?.max_attempts(3);
+/// queue.push(&"a".to_string())?;
+///
+/// let lease = queue.pop()?.unwrap();
+/// assert_eq!(lease.value(), "a");
+/// assert!(queue.ack(&lease)?);
+/// assert!(queue.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Queue<V: Value> {
+    store: Store,
+    name: String,
+    items: Bucket<'static, Integer, Raw>,
+    schedule: Bucket<'static, ScheduleKey, Raw>,
+    dead_letters: Bucket<'static, Integer, Raw>,
+    visibility_timeout: Duration,
+    max_attempts: u32,
+    phantom: PhantomData<V>,
+}
+
+impl<V: Value> Clone for Queue<V> {
+    fn clone(&self) -> Self {
+        Queue {
+            store: self.store.clone(),
+            name: self.name.clone(),
+            items: self.items.clone(),
+            schedule: self.schedule.clone(),
+            dead_letters: self.dead_letters.clone(),
+            visibility_timeout: self.visibility_timeout,
+            max_attempts: self.max_attempts,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<V: Value> Queue<V> {
+    /// Open the queue stored in the bucket `name`, with a 30 second visibility timeout and no
+    /// limit on the number of delivery attempts
+    pub fn new(store: &Store, name: &str) -> Result<Queue<V>, Error> {
+        Ok(Queue {
+            store: store.clone(),
+            name: name.to_string(),
+            items: store.bucket(Some(name))?,
+            schedule: store.bucket(Some(&format!("{}.by_deadline", name)))?,
+            dead_letters: store.bucket(Some(&dead_letters_name(name)))?,
+            visibility_timeout: Duration::from_secs(30),
+            max_attempts: u32::MAX,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Set how long values are hidden from other consumers after they're returned by `pop`
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    /// Set the number of times a value is delivered before it's moved to the dead letter bucket
+    pub fn max_attempts(mut self, n: u32) -> Self {
+        self.max_attempts = n;
+        self
+    }
+
+    /// Add `value` to the end of the queue, returning its id
+    pub fn push(&self, value: &V) -> Result<u64, Error> {
+        let id = self.store.generate_id()?;
+        let entry = Entry {
+            deadline: 0,
+            attempts: 0,
+            value: value.to_raw_value()?,
+        };
+        let key = Integer::from(id);
+        self.items.transaction2(&self.schedule, |items, schedule| {
+            items.set(&key, &entry.encode())?;
+            schedule.set(&(U64::from(0), key), &Raw::default())?;
+            Ok::<_, TransactionError<Error>>(())
+        })?;
+        Ok(id)
+    }
+
+    /// Take the first available value from the queue, returns `None` if the queue is empty or
+    /// every value is leased
+    pub fn pop(&self) -> Result<Option<Lease<V>>, Error> {
+        self.try_pop().map(|(lease, _)| lease)
+    }
+
+    /// Like `pop`, but waits up to `timeout` for a value to become available
+    pub fn pop_timeout(&self, timeout: Duration) -> Result<Option<Lease<V>>, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut watch = self.items.watch_prefix(None)?;
+        loop {
+            let (lease, next) = self.try_pop()?;
+            if lease.is_some() {
+                return Ok(lease);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            // Wake up when a value is pushed or returned, or the next lease expires
+            let mut wait = deadline - now;
+            if let Some(next) = next {
+                let expires = Duration::from_millis(next.saturating_sub(now_ms()?));
+                wait = wait.min(expires);
+            }
+            watch.next_timeout(wait);
+        }
+    }
+
+    /// Ids of the entries that can be delivered at `now` in delivery order, along with their
+    /// deadline, and the time the earliest lease that hasn't expired yet expires. Entries that
+    /// are ready have a deadline of 0, they're merged with expired leases by id
+    #[allow(clippy::type_complexity)]
+    fn available(
+        &self,
+        now: u64,
+    ) -> Result<
+        (
+            impl Iterator<Item = Result<(u64, Integer), Error>>,
+            Option<u64>,
+        ),
+        Error,
+    > {
+        let after = deadline_prefix(now.saturating_add(1))?;
+        let next = self
+            .schedule
+            .iter_bounds(Bound::Included(after.clone()), Bound::Unbounded)?
+            .next()
+            .map(schedule_key)
+            .transpose()?
+            .map(|(deadline, _)| deadline);
+
+        let mut ready = self
+            .schedule
+            .iter_prefix(&(U64::from(0),))?
+            .map(schedule_key)
+            .peekable();
+        let mut expired = self
+            .schedule
+            .iter_bounds(Bound::Included(deadline_prefix(1)?), Bound::Excluded(after))?
+            .map(schedule_key)
+            .peekable();
+        let merged = std::iter::from_fn(move || {
+            let take_ready = match (ready.peek(), expired.peek()) {
+                (Some(Ok((_, a))), Some(Ok((_, b)))) => a <= b,
+                (Some(_), _) => true,
+                (None, _) => false,
+            };
+            if take_ready {
+                ready.next()
+            } else {
+                expired.next()
+            }
+        });
+        Ok((merged, next))
+    }
+
+    /// Returns the first available value and the time the earliest lease expires
+    fn try_pop(&self) -> Result<(Option<Lease<V>>, Option<u64>), Error> {
+        let now = now_ms()?;
+        let (available, next) = self.available(now)?;
+        for x in available {
+            let (deadline, key) = x?;
+            let raw = match self.items.get(&key)? {
+                Some(raw) => raw,
+                None => continue,
+            };
+            let entry = Entry::decode(&raw)?;
+            if entry.deadline != deadline {
+                // Changed by another consumer since the schedule was read
+                continue;
+            }
+
+            if entry.attempts >= self.max_attempts {
+                self.dead_letter(&key, &raw, entry)?;
+                continue;
+            }
+
+            let leased = Entry {
+                deadline: now.saturating_add(self.visibility_timeout.as_millis() as u64),
+                attempts: entry.attempts + 1,
+                value: entry.value,
+            };
+            if !self.replace(&key, &raw, Some(&leased))? {
+                // Taken by another consumer
+                continue;
+            }
+
+            let lease = Lease {
+                id: u64::from(key),
+                attempts: leased.attempts,
+                entry: leased.encode(),
+                value: V::from_raw_value(leased.value)?,
+            };
+            return Ok((Some(lease), next));
+        }
+        Ok((None, next))
+    }
+
+    /// Replace the entry `key` with `new`, or remove it, if it's still `old`. Returns false if
+    /// it was changed by another consumer
+    fn replace(&self, key: &Integer, old: &Raw, new: Option<&Entry>) -> Result<bool, Error> {
+        let deadline = Entry::decode(old)?.deadline;
+        self.items.transaction2(&self.schedule, |items, schedule| {
+            if items.get(key)?.as_ref() != Some(old) {
+                return Ok(false);
+            }
+            schedule.remove(&(U64::from(deadline), *key))?;
+            match new {
+                Some(entry) => {
+                    items.set(key, &entry.encode())?;
+                    schedule.set(&(U64::from(entry.deadline), *key), &Raw::default())?;
+                }
+                None => {
+                    items.remove(key)?;
+                }
+            }
+            Ok::<_, TransactionError<Error>>(true)
+        })
+    }
+
+    /// Move the entry `key` to the dead letter bucket, if it hasn't been changed
+    fn dead_letter(&self, key: &Integer, raw: &Raw, entry: Entry) -> Result<(), Error> {
+        let buckets = Buckets::new()
+            .with(&self.items)
+            .with(&self.schedule)
+            .with(&self.dead_letters);
+        self.store.transaction(&buckets, |t| {
+            let items = t.bucket(&self.items)?;
+            if items.get(key)?.as_ref() != Some(raw) {
+                return Ok(());
+            }
+            items.remove(key)?;
+            t.bucket(&self.schedule)?
+                .remove(&(U64::from(entry.deadline), *key))?;
+            t.bucket(&self.dead_letters)?.set(key, &entry.value)?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+    }
+
+    /// Replace the leased entry with `new`, or remove it, unless the lease has expired or the
+    /// entry has been changed by another consumer
+    fn release(&self, lease: &Lease<V>, new: Option<&Entry>) -> Result<bool, Error> {
+        if Entry::decode(&lease.entry)?.deadline <= now_ms()? {
+            return Ok(false);
+        }
+        self.replace(&Integer::from(lease.id), &lease.entry, new)
+    }
+
+    /// Remove a leased value from the queue, returns false if the lease expired before it was
+    /// acknowledged, even if the value hasn't been delivered again yet
+    pub fn ack(&self, lease: &Lease<V>) -> Result<bool, Error> {
+        self.release(lease, None)
+    }
+
+    /// Return a leased value to the queue so it can be delivered again immediately, returns false
+    /// if the lease expired before it was returned
+    pub fn nack(&self, lease: &Lease<V>) -> Result<bool, Error> {
+        let mut entry = Entry::decode(&lease.entry)?;
+        entry.deadline = 0;
+        self.release(lease, Some(&entry))
+    }
+
+    /// Get the first available value without leasing it
+    pub fn peek(&self) -> Result<Option<V>, Error> {
+        let (available, _) = self.available(now_ms()?)?;
+        for x in available {
+            let (_, key) = x?;
+            if let Some(raw) = self.items.get(&key)? {
+                let entry = Entry::decode(&raw)?;
+                if entry.attempts < self.max_attempts {
+                    return Ok(Some(V::from_raw_value(entry.value)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the number of values in the queue, including leased values
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns true when the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Get the values that were delivered `max_attempts` times without being acknowledged,
+    /// keyed by the id returned by `push`
+    pub fn dead_letters(&self) -> Result<Bucket<'static, Integer, V>, Error> {
+        self.store.bucket(Some(&dead_letters_name(&self.name)))
+    }
+}
+
+fn dead_letters_name(name: &str) -> String {
+    format!("{}.dead_letters", name)
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+
+use crate::backend::Backend;
+use crate::{Error, Key, Raw, Value};
+
+/// Name of the hidden tree used to store bucket metadata, keyed by bucket name
+pub(crate) const META_TREE: &str = "__kv_meta__";
+
+// Metadata is encoded as a big-endian u32 version followed by the key and value type tags,
+// each prefixed by a big-endian u32 length, then a big-endian u32 containing the bucket's flags.
+// Missing tags are stored as empty strings, metadata written before flags were added has none
+
+/// Flag recorded the first time a key is stored with an expiry time, buckets without it don't
+/// read or update expiry times
+pub(crate) const EXPIRY: u32 = 1;
+
+/// Type tags and schema version recorded the first time a bucket is opened, see
+/// `Store::bucket_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketInfo {
+    /// Key type tag, see `Key::TYPE_TAG`
+    pub key: Option<String>,
+
+    /// Value type tag, this determines the codec used to store values, see `Value::type_tag`
+    pub value: Option<String>,
+
+    /// Schema version, set using `Store::bucket_with_version`
+    pub version: u32,
+}
+
+impl fmt::Display for BucketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = |x: &Option<String>| x.clone().unwrap_or_else(|| "(any)".into());
+        write!(
+            f,
+            "key type {}, value type {}, version {}",
+            tag(&self.key),
+            tag(&self.value),
+            self.version
+        )
+    }
+}
+
+fn invalid() -> Error {
+    Error::Message("Invalid bucket metadata".into())
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, Error> {
+    if data.len() < 4 {
+        return Err(invalid());
+    }
+    let (n, rest) = data.split_at(4);
+    *data = rest;
+    Ok(u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+fn read_tag(data: &mut &[u8]) -> Result<Option<String>, Error> {
+    let len = read_u32(data)? as usize;
+    if data.len() < len {
+        return Err(invalid());
+    }
+    let (s, rest) = data.split_at(len);
+    *data = rest;
+    match s {
+        [] => Ok(None),
+        s => Ok(Some(String::from_utf8(s.to_vec())?)),
+    }
+}
+
+impl BucketInfo {
+    pub(crate) fn new<'a, K: Key<'a>, V: Value>(version: u32) -> BucketInfo {
+        BucketInfo {
+            key: K::TYPE_TAG.map(String::from),
+            value: V::type_tag(),
+            version,
+        }
+    }
+
+    fn encode(&self, flags: u32) -> Raw {
+        let mut dst = Vec::with_capacity(16);
+        dst.extend_from_slice(&self.version.to_be_bytes());
+        for s in [&self.key, &self.value] {
+            let s = s.as_deref().unwrap_or_default();
+            dst.extend_from_slice(&(s.len() as u32).to_be_bytes());
+            dst.extend_from_slice(s.as_bytes());
+        }
+        dst.extend_from_slice(&flags.to_be_bytes());
+        dst.into()
+    }
+
+    /// Decode metadata, returning the bucket info and flags
+    fn decode(mut data: &[u8]) -> Result<(BucketInfo, u32), Error> {
+        let version = read_u32(&mut data)?;
+        let key = read_tag(&mut data)?;
+        let value = read_tag(&mut data)?;
+        let flags = if data.is_empty() {
+            0
+        } else {
+            read_u32(&mut data)?
+        };
+        let info = BucketInfo {
+            key,
+            value,
+            version,
+        };
+        Ok((info, flags))
+    }
+
+    /// Returns true when nothing but flags has been recorded
+    fn is_empty(&self) -> bool {
+        self.key.is_none() && self.value.is_none() && self.version == 0
+    }
+
+    /// Returns true if the bucket can be opened with the types in `other`, a missing tag on
+    /// either side matches any type so untagged types such as `Raw` are never rejected
+    fn is_compatible(&self, other: &BucketInfo) -> bool {
+        let matches = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        matches(&self.key, &other.key) && matches(&self.value, &other.value)
+    }
+
+    /// Returns true if `other` has a tag that isn't recorded yet
+    fn adds_tags(&self, other: &BucketInfo) -> bool {
+        (self.key.is_none() && other.key.is_some())
+            || (self.value.is_none() && other.value.is_some())
+    }
+}
+
+/// Get the metadata recorded for the bucket `name`
+pub(crate) fn get(db: &dyn Backend, name: &[u8]) -> Result<Option<BucketInfo>, Error> {
+    match db.open_tree(META_TREE.as_bytes())?.get(name)? {
+        Some(x) => {
+            let (info, _) = BucketInfo::decode(&x)?;
+            Ok(Some(info).filter(|info| !info.is_empty()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Get the flags recorded for the bucket `name`
+pub(crate) fn flags(db: &dyn Backend, name: &[u8]) -> Result<u32, Error> {
+    match db.open_tree(META_TREE.as_bytes())?.get(name)? {
+        Some(x) => Ok(BucketInfo::decode(&x)?.1),
+        None => Ok(0),
+    }
+}
+
+/// Record `flag` for the bucket `name`
+pub(crate) fn set_flag(db: &dyn Backend, name: &[u8], flag: u32) -> Result<(), Error> {
+    let tree = db.open_tree(META_TREE.as_bytes())?;
+    loop {
+        let current = tree.get(name)?;
+        let (info, flags) = match &current {
+            Some(x) => BucketInfo::decode(x)?,
+            None => {
+                let info = BucketInfo {
+                    key: None,
+                    value: None,
+                    version: 0,
+                };
+                (info, 0)
+            }
+        };
+        if flags & flag != 0 {
+            return Ok(());
+        }
+        if tree
+            .compare_and_swap(name, current, Some(info.encode(flags | flag)))?
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Check `info` against the metadata recorded for the bucket `name`, recording any tags the
+/// bucket doesn't have yet. When `version` is set it must not be older than the recorded
+/// version, newer versions replace it
+pub(crate) fn check(
+    db: &dyn Backend,
+    name: &[u8],
+    mut info: BucketInfo,
+    version: Option<u32>,
+) -> Result<(), Error> {
+    let tree = db.open_tree(META_TREE.as_bytes())?;
+    loop {
+        let current = tree.get(name)?;
+        let new = match &current {
+            None if info.key.is_none() && info.value.is_none() => return Ok(()),
+            None => {
+                info.version = version.unwrap_or_default();
+                (info.clone(), 0)
+            }
+            Some(x) => {
+                let (found, flags) = BucketInfo::decode(x)?;
+                let newer = version.is_some_and(|v| v > found.version);
+                if !found.is_compatible(&info) || version.is_some_and(|v| v < found.version) {
+                    info.version = version.unwrap_or(found.version);
+                    return Err(Error::SchemaMismatch {
+                        bucket: String::from_utf8_lossy(name).into_owned(),
+                        expected: Box::new(info),
+                        found: Box::new(found),
+                    });
+                }
+                if !newer && !found.adds_tags(&info) {
+                    return Ok(());
+                }
+                let info = BucketInfo {
+                    key: found.key.or(info.key.clone()),
+                    value: found.value.or(info.value.clone()),
+                    version: version.unwrap_or(found.version),
+                };
+                (info, flags)
+            }
+        };
+        if tree
+            .compare_and_swap(name, current, Some(new.0.encode(new.1)))?
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Check `info` against the metadata recorded for the bucket `name` without recording anything
+pub(crate) fn verify(db: &dyn Backend, name: &[u8], info: BucketInfo) -> Result<(), Error> {
+    match get(db, name)? {
+        Some(found) if !found.is_compatible(&info) => Err(Error::SchemaMismatch {
+            bucket: String::from_utf8_lossy(name).into_owned(),
+            expected: Box::new(BucketInfo {
+                version: found.version,
+                ..info
+            }),
+            found: Box::new(found),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Remove the metadata recorded for the bucket `name`
+pub(crate) fn remove(db: &dyn Backend, name: &[u8]) -> Result<(), Error> {
+    db.open_tree(META_TREE.as_bytes())?.remove(name)?;
+    Ok(())
+}
@@ -1,21 +1,111 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Seek, Write};
 use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
 
-use crate::{Bucket, Config, Error, Key, Value};
+use crate::backend::{Backend, SledBackend};
+use crate::backup;
+use crate::blob::Pending;
+use crate::index::{Indexes, SharedIndexes, INDEX_PREFIX};
+use crate::key::encode_escaped;
+use crate::schema::{self, BucketInfo};
+use crate::ttl::{self, Expiry, Ttl, TTL_PREFIX};
+use crate::watch::Watchers;
+use crate::{
+    Bucket, Buckets, Config, Error, Integer, Key, MemoryCopy, TransactionError, Transactions, Value,
+};
 
-/// Store is used to read/write data to disk using `sled`
-#[derive(Clone, Debug)]
+/// Name of the bucket used when no name is given
+const DEFAULT_BUCKET: &str = "__sled__default";
+
+/// Prefix of tree names reserved for internal use, these are hidden from `Store::buckets`
+pub(crate) const RESERVED_PREFIX: &str = "__kv_";
+
+/// Store is used to read/write data to disk using `sled`, or any other `Backend`
+#[derive(Clone)]
 pub struct Store {
     config: Config,
-    db: sled::Db,
+    db: Arc<dyn Backend>,
+    watchers: Arc<Mutex<HashMap<Vec<u8>, Arc<Watchers>>>>,
+    expiry: Arc<Mutex<HashMap<Vec<u8>, Arc<Expiry>>>>,
+    indexes: Arc<Mutex<HashMap<Vec<u8>, Arc<SharedIndexes>>>>,
+    pending_blobs: Arc<Mutex<HashMap<Vec<u8>, Arc<Pending>>>>,
+    order: Arc<Mutex<()>>,
+}
+
+impl fmt::Debug for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Store")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Store {
     /// Create a new store from the given config
     pub fn new(mut config: Config) -> Result<Store, Error> {
-        Ok(Store {
-            db: config.open()?,
+        let db = config.open()?;
+        Ok(Store::with_backend(config, SledBackend::new(db)))
+    }
+
+    /// Create a new store using `backend` for storage instead of `sled`
+    pub fn with_backend<B: 'static + Backend>(config: Config, backend: B) -> Store {
+        Store::from_backend(config, Arc::new(backend))
+    }
+
+    fn from_backend(config: Config, db: Arc<dyn Backend>) -> Store {
+        Store {
             config,
-        })
+            db,
+            watchers: Arc::default(),
+            expiry: Arc::default(),
+            indexes: Arc::default(),
+            pending_blobs: Arc::default(),
+            order: Arc::default(),
+        }
+    }
+
+    /// Subscribers for the bucket `name`
+    fn watchers(&self, name: &[u8]) -> Arc<Watchers> {
+        let mut watchers = self.watchers.lock().unwrap_or_else(PoisonError::into_inner);
+        watchers
+            .entry(name.to_vec())
+            .or_insert_with(|| Arc::new(Watchers::new(name, self.order.clone())))
+            .clone()
+    }
+
+    /// Indexes registered on the bucket `name`
+    fn indexes(&self, name: &[u8]) -> Arc<SharedIndexes> {
+        let mut indexes = self.indexes.lock().unwrap_or_else(PoisonError::into_inner);
+        indexes
+            .entry(name.to_vec())
+            .or_insert_with(|| Arc::new(SharedIndexes::new(Indexes::new(self.db.clone(), name))))
+            .clone()
+    }
+
+    /// Ids of the blobs being written to the blob bucket `name`
+    pub(crate) fn pending_blobs(&self, name: &[u8]) -> Arc<Pending> {
+        let mut pending = self
+            .pending_blobs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        pending.entry(name.to_vec()).or_default().clone()
+    }
+
+    /// Expiry state for the bucket `name`, checked against `now` instead of the current time
+    /// when it's set
+    fn ttl(&self, name: &[u8], now: Option<Integer>) -> Result<Ttl, Error> {
+        let mut expiry = self.expiry.lock().unwrap_or_else(PoisonError::into_inner);
+        let x = match expiry.get(name) {
+            Some(x) => x.clone(),
+            None => {
+                let x = Arc::new(Expiry::new(self.db.clone(), name)?);
+                expiry.insert(name.to_vec(), x.clone());
+                x
+            }
+        };
+        Ok(Ttl(x, now))
     }
 
     /// Get the store's path
@@ -25,8 +115,7 @@ impl Store {
 
     /// Generate monotonic ID
     pub fn generate_id(&self) -> Result<u64, Error> {
-        let id = self.db.generate_id()?;
-        Ok(id)
+        self.db.generate_id()
     }
 
     /// Get a list of bucket names
@@ -35,41 +124,198 @@ impl Store {
             .tree_names()
             .into_iter()
             .map(|x| String::from_utf8(x.to_vec()))
-            .filter_map(|x| match x {
-                Ok(x) => Some(x),
-                Err(_) => None,
-            })
+            .filter_map(|x| x.ok())
+            .filter(|x| !x.starts_with(RESERVED_PREFIX))
             .collect()
     }
 
-    /// Open a new bucket
+    /// Open a new bucket. The key and value type tags (`Key::TYPE_TAG` and `Value::type_tag`)
+    /// are recorded the first time a bucket is opened, opening it again with types that have
+    /// different tags returns `Error::SchemaMismatch`. Built-in tags name the encoding rather
+    /// than the Rust type, so `&str` and `String` keys are interchangeable, while derived types
+    /// are tagged with their name. Types without a tag, such as `Raw`, are not checked and can be
+    /// used for untyped access
     pub fn bucket<'a, K: Key<'a>, V: Value>(
         &self,
         name: Option<&str>,
     ) -> Result<Bucket<'a, K, V>, Error> {
-        let t = self.db.open_tree(name.unwrap_or("__sled__default"))?;
-        Ok(Bucket::new(t))
+        self.open_bucket(name, None)
+    }
+
+    /// Open a bucket with a user defined schema version. Opening a bucket with a newer version
+    /// than the one recorded updates the stored version, opening it with an older version returns
+    /// `Error::SchemaMismatch`. See `Store::bucket`
+    pub fn bucket_with_version<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+        version: u32,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        self.open_bucket(name, Some(version))
+    }
+
+    fn open_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+        version: Option<u32>,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        let name = name.unwrap_or(DEFAULT_BUCKET);
+        schema::check(
+            &*self.db,
+            name.as_bytes(),
+            BucketInfo::new::<K, V>(0),
+            version,
+        )?;
+        self.open_tree_bucket(name, None)
+    }
+
+    /// Open a bucket in a copy made at `now`: the types are checked against the recorded
+    /// metadata without recording anything, and keys are expired as of `now`
+    pub(crate) fn copied_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+        now: Integer,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        let name = name.unwrap_or(DEFAULT_BUCKET);
+        schema::verify(&*self.db, name.as_bytes(), BucketInfo::new::<K, V>(0))?;
+        self.open_tree_bucket(name, Some(now))
+    }
+
+    fn open_tree_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: &str,
+        now: Option<Integer>,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        let t = self.db.open_tree(name.as_bytes())?;
+        let ttl = self.ttl(name.as_bytes(), now)?;
+        let indexes = self.indexes(name.as_bytes());
+        Ok(Bucket::new(t, ttl, indexes, self.watchers(name.as_bytes())))
+    }
+
+    /// Get the key and value type tags and schema version recorded for the bucket `name`, or
+    /// `None` if the bucket has only been opened using untagged keys and values
+    pub fn bucket_info(&self, name: Option<&str>) -> Result<Option<BucketInfo>, Error> {
+        schema::get(&*self.db, name.unwrap_or(DEFAULT_BUCKET).as_bytes())
+    }
+
+    /// Execute a transaction over any number of buckets opened from this store, use
+    /// `Transactions::bucket` to access each bucket. `f` may be called more than once if the
+    /// transaction conflicts with another write, see `Bucket::transaction`
+    pub fn transaction<A, E, F>(&self, buckets: &Buckets, f: F) -> Result<A, E>
+    where
+        E: From<Error>,
+        F: Fn(&Transactions) -> Result<A, TransactionError<E>>,
+    {
+        buckets.transaction(f)
+    }
+
+    /// Copy every bucket into memory, returning a read-only `MemoryCopy` that is consistent
+    /// across buckets.
+    ///
+    /// This is a blocking full copy, not a lightweight snapshot: it needs as much memory as the
+    /// store holds, and with `sled` every write from other threads waits until the whole store
+    /// has been copied. It suits small stores or maintenance windows; for long-running reads of
+    /// a large store, iterate over buckets directly or read from a backup instead
+    pub fn copy_to_memory(&self) -> Result<MemoryCopy, Error> {
+        let now = Integer::timestamp_ms()?;
+        let db = self.db.copy_to_memory()?;
+        Ok(MemoryCopy::new(
+            Store::from_backend(self.config.clone(), db),
+            now,
+        ))
     }
 
     /// Remove a bucket from the store
     pub fn drop_bucket<S: AsRef<str>>(&self, name: S) -> Result<(), Error> {
-        self.db.drop_tree(name.as_ref().as_bytes())?;
+        let name = name.as_ref().as_bytes();
+        self.db.drop_tree(name)?;
+        let watchers = self.watchers.lock()?.remove(name);
+        if let Some(watchers) = watchers {
+            watchers.close();
+        }
+        self.expiry.lock()?.remove(name);
+        self.indexes.lock()?.remove(name);
+        self.pending_blobs.lock()?.remove(name);
+        self.db.drop_tree(&ttl::tree_name(name))?;
+        schema::remove(&*self.db, name)?;
+
+        let mut prefix = INDEX_PREFIX.as_bytes().to_vec();
+        encode_escaped(name, &mut prefix);
+        for tree in self.db.tree_names() {
+            if tree.starts_with(&prefix) {
+                self.db.drop_tree(&tree)?;
+            }
+        }
         Ok(())
     }
 
+    /// Remove expired keys from all buckets, returning the number of keys removed. Indexes are
+    /// updated for buckets that have been opened since the store was, other buckets should be
+    /// purged using `Bucket::purge_expired` once their indexes are registered
+    pub fn purge_expired(&self) -> Result<usize, Error> {
+        let mut count = 0;
+        for name in self.db.tree_names() {
+            if let Some(name) = name.strip_prefix(TTL_PREFIX.as_bytes()) {
+                let t = self.db.open_tree(name)?;
+                let ttl = self.ttl(name, None)?;
+                let indexes = self.indexes(name).load();
+                count += ttl.purge(&*t, &indexes, &self.watchers(name))?;
+            }
+        }
+        Ok(count)
+    }
+
     /// Returns the size on disk in bytes
     pub fn size_on_disk(&self) -> Result<u64, Error> {
-        let i = self.db.size_on_disk()?;
-        Ok(i)
+        self.db.size_on_disk()
     }
 
     /// Export entire database
+    #[allow(clippy::type_complexity)]
     pub fn export(&self) -> Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>>)> {
         self.db.export()
     }
 
+    /// Write a backup of every bucket to `w`, returning the number of entries written. Entries
+    /// are streamed from the store, and the format is independent of `sled` and the storage
+    /// backend.
+    ///
+    /// The file starts with `b"KVBACKUP"` and a big-endian `u32` version, followed by records:
+    /// `b'I'` and the store's ID counter as a `u64`, `b'T'` and a length-prefixed tree name,
+    /// `b'E'` and a length-prefixed key and value for each entry in the tree, then `b'Z'`, the
+    /// number of entries as a `u64` and a CRC32 checksum of everything before it. Lengths are
+    /// big-endian `u32`s.
+    ///
+    /// The backup is not a point-in-time copy: trees are copied one at a time while other
+    /// threads may still be writing, so a write made during the backup can be missing from some
+    /// trees, e.g. a value without its index entries. Stop writing to the store while the backup
+    /// runs if a consistent copy is needed
+    pub fn backup_to<W: Write>(&self, w: W) -> Result<u64, Error> {
+        backup::backup(&*self.db, w)
+    }
+
+    /// Restore a backup written by `backup_to`, returning the number of entries restored. The
+    /// backup is read twice: nothing is written until the checksum and entry count have been
+    /// verified, then `r` is rewound and the entries are written. Existing keys are overwritten
+    pub fn restore_from<R: Read + Seek>(&self, r: R) -> Result<u64, Error> {
+        backup::restore(&*self.db, r)
+    }
+
     /// Import from database export
-    pub fn import(&self, export: Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>>)>) {
+    pub fn import(
+        &self,
+        export: Vec<(
+            Vec<u8>,
+            Vec<u8>,
+            impl 'static + Iterator<Item = Vec<Vec<u8>>>,
+        )>,
+    ) -> Result<(), Error> {
+        let export = export
+            .into_iter()
+            .map(|(t, n, iter)| {
+                let iter: Box<dyn Iterator<Item = Vec<Vec<u8>>>> = Box::new(iter);
+                (t, n, iter)
+            })
+            .collect();
         self.db.import(export)
     }
 }
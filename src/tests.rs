@@ -1,5 +1,8 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::time::Duration;
 use std::{fs, path};
 
+use crate::blob;
 use crate::*;
 
 fn reset(name: &str) -> String {
@@ -8,6 +11,26 @@ fn reset(name: &str) -> String {
     s
 }
 
+struct Unpark(std::thread::Thread);
+
+impl std::task::Wake for Unpark {
+    fn wake(self: std::sync::Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let waker = std::sync::Arc::new(Unpark(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let std::task::Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+            return x;
+        }
+        std::thread::park();
+    }
+}
+
 #[test]
 fn test_basic() {
     let path = reset("basic");
@@ -243,6 +266,1432 @@ fn test_watch() {
     let next = next.unwrap();
 
     assert!(next.is_remove());
-    assert!(next.value().unwrap() == None);
+    assert!(next.value().unwrap().is_none());
     assert!(next.key().unwrap() == "abc");
 }
+
+#[test]
+fn test_watch_events() {
+    let path = reset("watch_events");
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store
+        .bucket::<String, String>(Some("watch_events"))
+        .unwrap();
+    let mut watch = bucket.watch_prefix(None).unwrap();
+    let mut filtered = bucket
+        .watch_prefix_filter(None, |e: &Event<String, String>| e.is_update())
+        .unwrap();
+
+    bucket.set(&"a".to_string(), &"1".to_string()).unwrap();
+    bucket.set(&"a".to_string(), &"2".to_string()).unwrap();
+    bucket.remove(&"a".to_string()).unwrap();
+
+    let next = watch.next().unwrap().unwrap();
+    assert!(next.is_insert());
+    assert_eq!(next.value().unwrap().unwrap(), "1");
+    assert!(next.old_value().unwrap().is_none());
+
+    let next = watch.next().unwrap().unwrap();
+    assert!(next.is_update());
+    assert_eq!(next.old_value().unwrap().unwrap(), "1");
+    assert_eq!(next.value().unwrap().unwrap(), "2");
+
+    let next = watch.next().unwrap().unwrap();
+    assert!(next.is_remove());
+    assert_eq!(next.key().unwrap(), "a");
+    assert_eq!(next.old_value().unwrap().unwrap(), "2");
+
+    match filtered.next().unwrap().unwrap() {
+        Event::Update { old, new } => {
+            assert_eq!(old.value::<String>().unwrap(), "1");
+            assert_eq!(new.value::<String>().unwrap(), "2");
+        }
+        _ => panic!("expected update"),
+    }
+
+    // Transactions report every write once committed, buckets opened separately share watchers
+    let other = store
+        .bucket::<String, String>(Some("watch_events"))
+        .unwrap();
+    other
+        .transaction(|t| {
+            t.set(&"b".to_string(), &"1".to_string())?;
+            t.set(&"b".to_string(), &"2".to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert!(watch.next().unwrap().unwrap().is_insert());
+    assert!(watch.next().unwrap().unwrap().is_update());
+    assert_eq!(filtered.next().unwrap().unwrap().key().unwrap(), "b");
+
+    store.drop_bucket("watch_events").unwrap();
+    assert!(watch.next().is_none());
+    assert!(filtered.next().is_none());
+}
+
+#[test]
+fn test_watch_nested_writes() {
+    // sled transactions block all other writes, so this only works with `MemoryBackend`
+    let store = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+    let a = store.bucket::<&str, String>(Some("a")).unwrap();
+    let b = store.bucket::<&str, String>(Some("b")).unwrap();
+    let mut watch_a = a.watch_prefix(None).unwrap();
+    let mut watch_b = b.watch_prefix(None).unwrap();
+
+    // Writing to another watched bucket from a transaction or a filter doesn't deadlock
+    let log = store.bucket::<&str, String>(Some("log")).unwrap();
+    let mut filtered = a
+        .watch_prefix_filter(None, move |_: &Event<&str, String>| {
+            log.set(&"seen", &"1".to_string()).is_ok()
+        })
+        .unwrap();
+    a.transaction(|t| {
+        b.set(&"x", &"1".to_string()).unwrap();
+        t.set(&"x", &"2".to_string())?;
+        Ok::<_, TransactionError<Error>>(())
+    })
+    .unwrap();
+
+    assert_eq!(watch_b.next().unwrap().unwrap().key().unwrap(), "x");
+    assert_eq!(watch_a.next().unwrap().unwrap().key().unwrap(), "x");
+    assert!(filtered.next().unwrap().unwrap().is_insert());
+    let log = store.bucket::<&str, String>(Some("log")).unwrap();
+    assert!(log.contains(&"seen").unwrap());
+}
+
+#[test]
+fn test_ttl() {
+    let path = reset("ttl");
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("ttl")).unwrap();
+
+    bucket
+        .set_with_ttl(&"a", &"1".to_string(), std::time::Duration::from_millis(0))
+        .unwrap();
+    bucket
+        .set_with_ttl(&"b", &"2".to_string(), std::time::Duration::from_secs(3600))
+        .unwrap();
+    bucket.set(&"c", &"3".to_string()).unwrap();
+
+    assert!(!bucket.contains(&"a").unwrap());
+    assert!(bucket.get(&"a").unwrap().is_none());
+    assert!(bucket.get(&"b").unwrap().is_some());
+    assert!(bucket.ttl(&"b").unwrap().is_some());
+    assert!(bucket.ttl(&"c").unwrap().is_none());
+    assert_eq!(bucket.iter().count(), 2);
+    assert_eq!(bucket.first().unwrap().unwrap().key::<&str>().unwrap(), "b");
+
+    // Overwriting a key clears the expiry time
+    bucket.set(&"b", &"4".to_string()).unwrap();
+    assert!(bucket.ttl(&"b").unwrap().is_none());
+
+    assert_eq!(bucket.len(), 3);
+    assert_eq!(store.purge_expired().unwrap(), 1);
+    assert_eq!(bucket.len(), 2);
+    assert!(!store.buckets().iter().any(|x| x.starts_with("__kv_")));
+
+    // Transactions hide expired values, and batches and transactions clear the expiry time
+    let expired = std::time::Duration::from_millis(0);
+    bucket
+        .set_with_ttl(&"d", &"5".to_string(), expired)
+        .unwrap();
+    bucket
+        .set_with_ttl(&"e", &"6".to_string(), expired)
+        .unwrap();
+    bucket
+        .transaction(|t| {
+            assert!(t.get(&"d")?.is_none());
+            assert!(!t.contains(&"e")?);
+            assert_eq!(t.iter_prefix(&"")?.count(), 2);
+            assert!(t.set(&"d", &"7".to_string())?.is_none());
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    let mut batch = Batch::new();
+    batch.set(&"e", &"8".to_string()).unwrap();
+    bucket.batch(batch).unwrap();
+    assert!(bucket.ttl(&"d").unwrap().is_none());
+    assert!(bucket.ttl(&"e").unwrap().is_none());
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    assert_eq!(bucket.get(&"d").unwrap().unwrap(), "7");
+    assert_eq!(bucket.get(&"e").unwrap().unwrap(), "8");
+
+    // Expired items are skipped by pop, which also removes their expiry time
+    bucket.clear().unwrap();
+    bucket
+        .set_with_ttl(&"f", &"9".to_string(), expired)
+        .unwrap();
+    bucket.set(&"g", &"10".to_string()).unwrap();
+    assert_eq!(
+        bucket.pop_front().unwrap().unwrap().key::<&str>().unwrap(),
+        "g"
+    );
+    assert!(bucket.is_empty());
+    assert_eq!(store.purge_expired().unwrap(), 0);
+
+    // Expiry times are only tracked once a bucket has used them, which is recorded in the
+    // bucket's metadata
+    let plain = store.bucket::<&str, String>(Some("plain")).unwrap();
+    plain.set(&"a", &"1".to_string()).unwrap();
+    let names: Vec<Vec<u8>> = store.export().into_iter().map(|(_, n, _)| n).collect();
+    assert!(names.contains(&b"__kv_ttl__ttl".to_vec()));
+    assert!(!names.contains(&b"__kv_ttl__plain".to_vec()));
+
+    bucket
+        .set_with_ttl(
+            &"h",
+            &"11".to_string(),
+            std::time::Duration::from_secs(3600),
+        )
+        .unwrap();
+    drop((bucket, plain, store));
+    let store = Store::new(Config::new(path)).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("ttl")).unwrap();
+    assert!(bucket.ttl(&"h").unwrap().is_some());
+    bucket.set(&"h", &"12".to_string()).unwrap();
+    assert!(bucket.ttl(&"h").unwrap().is_none());
+}
+
+#[test]
+fn test_index() {
+    let path = reset("index");
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store
+        .bucket::<&str, String>(Some("index"))
+        .unwrap()
+        .with_index("first", |v: &String| {
+            v.get(..1).map(String::from).into_iter().collect()
+        })
+        .unwrap();
+
+    bucket.set(&"a", &"apple".to_string()).unwrap();
+    bucket.set(&"b", &"banana".to_string()).unwrap();
+    bucket.set(&"c", &"avocado".to_string()).unwrap();
+
+    let keys = |k: &str| -> Vec<String> {
+        bucket
+            .iter_index("first", &k)
+            .unwrap()
+            .map(|item| item.unwrap().key::<String>().unwrap())
+            .collect()
+    };
+    assert_eq!(keys("a"), vec!["a", "c"]);
+    assert_eq!(keys("b"), vec!["b"]);
+
+    bucket.set(&"c", &"cherry".to_string()).unwrap();
+    bucket.remove(&"b").unwrap();
+    assert_eq!(keys("a"), vec!["a"]);
+    assert!(keys("b").is_empty());
+
+    let mut batch = Batch::new();
+    batch.set(&"d", &"blueberry".to_string()).unwrap();
+    bucket.batch(batch).unwrap();
+
+    bucket
+        .transaction(|txn| {
+            txn.set(&"e", &"apricot".to_string())?;
+            txn.remove(&"a")?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(keys("a"), vec!["e"]);
+    assert_eq!(keys("b"), vec!["d"]);
+
+    // Existing data can be indexed after the fact
+    let other = store
+        .bucket::<&str, String>(Some("index"))
+        .unwrap()
+        .with_index("len", |v: &String| vec![Integer::from(v.len())])
+        .unwrap();
+    assert_eq!(
+        other.iter_index("len", &Integer::from(6)).unwrap().count(),
+        0
+    );
+    other.rebuild_index("len").unwrap();
+    assert_eq!(
+        other.iter_index("len", &Integer::from(6)).unwrap().count(),
+        1
+    );
+}
+
+#[test]
+fn test_memory_backend() {
+    let store = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+    let bucket = store
+        .bucket::<Integer, String>(Some("memory"))
+        .unwrap()
+        .with_index("len", |v: &String| vec![Integer::from(v.len())])
+        .unwrap();
+    let mut watch = bucket.watch_prefix(None).unwrap();
+
+    for i in 0..10 {
+        bucket.set(&i.into(), &format!("{}", i * 10)).unwrap();
+    }
+    assert_eq!(bucket.len(), 10);
+    assert!(watch.next().unwrap().unwrap().is_set());
+
+    let keys: Vec<u128> = bucket
+        .iter_range(&2.into(), &5.into())
+        .unwrap()
+        .rev()
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(keys, vec![4, 3, 2]);
+    assert_eq!(
+        bucket.iter_index("len", &Integer::from(2)).unwrap().count(),
+        9
+    );
+
+    bucket
+        .compare_and_swap(&0.into(), Some(&"0".to_string()), None)
+        .unwrap()
+        .unwrap();
+    assert!(bucket
+        .compare_and_swap(&0.into(), Some(&"0".to_string()), None)
+        .unwrap()
+        .is_err());
+
+    let result = bucket.transaction(|txn| {
+        txn.set(&20.into(), &"200".to_string())?;
+        Err::<(), _>(abort(Error::Message("abort".into())))
+    });
+    assert!(result.is_err());
+    assert!(!bucket.contains(&20.into()).unwrap());
+
+    bucket
+        .transaction(|txn| {
+            txn.remove(&1.into())?;
+            txn.set(&20.into(), &"200".to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert!(!bucket.contains(&1.into()).unwrap());
+    assert_eq!(
+        bucket.iter_index("len", &Integer::from(3)).unwrap().count(),
+        1
+    );
+    assert_eq!(
+        bucket.pop_front().unwrap().unwrap().key::<u128>().unwrap(),
+        2
+    );
+    assert_eq!(bucket.last().unwrap().unwrap().key::<u128>().unwrap(), 20);
+
+    assert!(store.buckets().contains(&"memory".to_string()));
+    store.drop_bucket("memory").unwrap();
+    assert!(store.buckets().is_empty());
+
+    let entries = vec![vec![b"a".to_vec(), b"1".to_vec()], vec![b"b".to_vec()]];
+    let export = vec![(b"tree".to_vec(), b"import".to_vec(), entries.into_iter())];
+    assert!(store.import(export).is_err());
+    assert!(store.buckets().contains(&"import".to_string()));
+}
+
+#[test]
+fn test_backup() {
+    let path = reset("backup");
+    let store = Store::new(Config::new(path)).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("backup")).unwrap();
+    bucket.set(&"a", &"1".to_string()).unwrap();
+    bucket
+        .set_with_ttl(&"b", &"2".to_string(), std::time::Duration::from_secs(3600))
+        .unwrap();
+
+    // Two values, two expiry entries and the bucket's metadata
+    let mut data = Vec::new();
+    assert_eq!(store.backup_to(&mut data).unwrap(), 5);
+
+    let restored = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+    assert_eq!(restored.restore_from(Cursor::new(&data)).unwrap(), 5);
+    assert_eq!(
+        restored.bucket_info(Some("backup")).unwrap(),
+        store.bucket_info(Some("backup")).unwrap()
+    );
+    let bucket = restored.bucket::<&str, String>(Some("backup")).unwrap();
+    assert_eq!(bucket.get(&"a").unwrap().unwrap(), "1");
+    assert!(bucket.ttl(&"b").unwrap().is_some());
+
+    // IDs generated before the backup aren't reused after restoring it, and taking a backup
+    // doesn't generate an ID
+    let id = store.generate_id().unwrap();
+    let mut data = Vec::new();
+    store.backup_to(&mut data).unwrap();
+    assert_eq!(store.generate_id().unwrap(), id + 1);
+    let restored = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+    restored.restore_from(Cursor::new(&data)).unwrap();
+    assert!(restored.generate_id().unwrap() > id);
+    let restored = Store::new(Config::new(reset("backup-restored")).temporary(true)).unwrap();
+    restored.restore_from(Cursor::new(&data)).unwrap();
+    assert!(restored.generate_id().unwrap() > id);
+
+    // Nothing is written when the backup is corrupt
+    let n = data.len() - 10;
+    data[n] ^= 1;
+    let store = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+    assert!(matches!(
+        store.restore_from(Cursor::new(&data)),
+        Err(Error::InvalidBackup(_))
+    ));
+    assert!(store.buckets().is_empty());
+    assert!(store.restore_from(Cursor::new(b"garbage")).is_err());
+}
+
+#[test]
+fn test_store_transaction() {
+    let path = reset("store_transaction");
+    let store = Store::new(Config::new(path)).unwrap();
+    let names = ["a", "b", "c", "d", "e"];
+    let buckets: Vec<Bucket<&str, String>> = names
+        .iter()
+        .map(|name| store.bucket(Some(name)).unwrap())
+        .collect();
+    let other = store.bucket::<&str, String>(Some("other")).unwrap();
+    let mut watch = buckets[4].watch_prefix(None).unwrap();
+
+    let mut txn = Buckets::new();
+    for bucket in &buckets {
+        txn = txn.with(bucket);
+    }
+    let txn = txn.with(&other).with(&buckets[0]);
+    assert_eq!(txn.len(), 6);
+
+    store
+        .transaction(&txn, |t| {
+            for (i, bucket) in buckets.iter().enumerate() {
+                t.bucket(bucket)?.set(&"x", &i.to_string())?;
+            }
+            t.bucket(&other)?.set(&"x", &"other".to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    for (i, bucket) in buckets.iter().enumerate() {
+        assert_eq!(bucket.get(&"x").unwrap(), Some(i.to_string()));
+    }
+    assert_eq!(
+        watch.next().unwrap().unwrap().value().unwrap(),
+        Some("4".to_string())
+    );
+
+    // Aborting rolls back every bucket
+    let result = store.transaction(&txn, |t| {
+        t.bucket(&buckets[0])?.set(&"x", &"100".to_string())?;
+        t.bucket(&other)?.remove(&"x")?;
+        Err::<(), _>(abort(Error::Message("abort".into())))
+    });
+    assert!(result.is_err());
+    assert_eq!(buckets[0].get(&"x").unwrap(), Some("0".to_string()));
+    assert!(other.contains(&"x").unwrap());
+
+    // Buckets must be added before they can be used
+    let result = store.transaction(&Buckets::new().with(&other), |t| {
+        t.bucket(&buckets[0])?;
+        Ok(())
+    });
+    assert!(result.is_err());
+
+    // Indexes registered on any handle to a bucket are maintained
+    let indexed = store
+        .bucket::<&str, String>(Some("other"))
+        .unwrap()
+        .with_index("len", |v: &String| vec![Integer::from(v.len())])
+        .unwrap();
+    store
+        .transaction(&Buckets::new().with(&other), |t| {
+            t.bucket(&other)?.set(&"x", &"abc".to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    other.set(&"y", &"xyz".to_string()).unwrap();
+    assert_eq!(
+        indexed
+            .iter_index("len", &Integer::from(3))
+            .unwrap()
+            .count(),
+        2
+    );
+}
+
+fn check_transaction_iter(store: &Store) {
+    let bucket = store
+        .bucket::<&str, String>(Some("transaction_iter"))
+        .unwrap();
+    for k in ["a/1", "a/2", "b/1"] {
+        bucket.set(&k, &k.to_string()).unwrap();
+    }
+
+    bucket
+        .transaction(|t| {
+            t.set(&"a/3", &"a/3".to_string())?;
+            t.remove(&"a/1")?;
+            let keys: Vec<String> = t
+                .iter_prefix(&"a/")?
+                .map(|item| item.key::<&str>().unwrap().to_string())
+                .collect();
+            assert_eq!(keys, ["a/2", "a/3"]);
+
+            let last = t.iter_range(&"a/", &"b/2")?.next_back().unwrap();
+            t.set(&"last", &last.key::<&str>().unwrap().to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(bucket.get(&"last").unwrap().unwrap(), "b/1");
+    assert_eq!(bucket.iter_prefix(&"a/").unwrap().count(), 2);
+}
+
+#[test]
+fn test_transaction_iter() {
+    let path = reset("transaction_iter");
+    let sled = Store::new(Config::new(path)).unwrap();
+    check_transaction_iter(&sled);
+
+    // With sled the first attempt to read a range fails, ignoring the error doesn't cause the
+    // attempt to be committed
+    let bucket = sled.bucket::<&str, U64>(Some("counter")).unwrap();
+    let attempts = std::cell::Cell::new(0);
+    bucket
+        .transaction(|t| {
+            attempts.set(attempts.get() + 1);
+            let _ = t.iter_prefix(&"a");
+            let n = t.get(&"n")?.map_or(0, u64::from);
+            t.set(&"n", &U64::from(n + 1))?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(attempts.get(), 2);
+    assert_eq!(u64::from(bucket.get(&"n").unwrap().unwrap()), 1);
+
+    // Writing outside of the transaction returns an error instead of blocking forever
+    let other = sled.bucket::<&str, U64>(Some("other")).unwrap();
+    let result = bucket.transaction(|_| {
+        other
+            .set(&"n", &U64::from(1))
+            .map_err(TransactionError::Abort)
+    });
+    assert!(result.is_err());
+    assert!(other.is_empty());
+
+    let store = Store::with_backend(Config::new("unused"), MemoryBackend::new());
+    check_transaction_iter(&store);
+
+    // A write to the scanned range before commit causes the transaction to be retried
+    let bucket = store
+        .bucket::<&str, String>(Some("transaction_iter"))
+        .unwrap();
+    let attempts = std::cell::Cell::new(0);
+    let count = bucket
+        .transaction(|t| {
+            attempts.set(attempts.get() + 1);
+            let count = t.iter_prefix(&"a/")?.count();
+            if attempts.get() == 1 {
+                bucket.set(&"a/4", &"a/4".to_string()).unwrap();
+            }
+            Ok::<_, TransactionError<Error>>(count)
+        })
+        .unwrap();
+    assert_eq!(attempts.get(), 2);
+    assert_eq!(count, 3);
+}
+
+fn check_memory_copy(store: &Store) {
+    let a = store.bucket::<&str, String>(Some("a")).unwrap();
+    let b = store.bucket::<&str, String>(Some("b")).unwrap();
+    a.set(&"x", &"100".to_string()).unwrap();
+    b.set(&"x", &"0".to_string()).unwrap();
+
+    // Move value from `a` to `b` while copies are made, the total is always the same
+    let writer = {
+        let (a, b) = (a.clone(), b.clone());
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                a.transaction2(&b, |a, b| {
+                    let x: u32 = a.get(&"x")?.unwrap().parse().unwrap();
+                    let y: u32 = b.get(&"x")?.unwrap().parse().unwrap();
+                    a.set(&"x", &(x - 1).to_string())?;
+                    b.set(&"x", &(y + 1).to_string())?;
+                    Ok::<_, TransactionError<Error>>(())
+                })
+                .unwrap();
+            }
+        })
+    };
+    for _ in 0..10 {
+        let copy = store.copy_to_memory().unwrap();
+        let total: u32 = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let bucket = copy.bucket::<&str, String>(Some(name)).unwrap();
+                bucket.get(&"x").unwrap().unwrap().parse::<u32>().unwrap()
+            })
+            .sum();
+        assert_eq!(total, 100);
+    }
+    writer.join().unwrap();
+
+    let copy = store.copy_to_memory().unwrap();
+    a.set(&"y", &"1".to_string()).unwrap();
+    b.clear().unwrap();
+    let sa = copy.bucket::<&str, String>(Some("a")).unwrap();
+    let sb = copy.bucket::<&str, String>(Some("b")).unwrap();
+    assert_eq!(sa.get(&"x").unwrap().unwrap(), "0");
+    assert!(!sa.contains(&"y").unwrap());
+    assert_eq!(sa.iter().count(), 1);
+    assert_eq!(sb.len(), 1);
+    assert_eq!(sb.iter_prefix(&"x").unwrap().count(), 1);
+    assert_ne!(sb.checksum().unwrap(), b.checksum().unwrap());
+
+    // Keys are expired as of the time the copy was made
+    a.set_with_ttl(&"z", &"1".to_string(), Duration::from_millis(100))
+        .unwrap();
+    let copy = store.copy_to_memory().unwrap();
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(!a.contains(&"z").unwrap());
+    let sa = copy.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(sa.get(&"z").unwrap().unwrap(), "1");
+    assert_eq!(sa.iter_prefix(&"z").unwrap().count(), 1);
+    assert!(sa.ttl(&"z").unwrap().unwrap() > Duration::ZERO);
+
+    // Opening buckets in a copy checks their types without recording them
+    assert!(matches!(
+        copy.bucket::<&str, I64>(Some("a")),
+        Err(Error::SchemaMismatch { .. })
+    ));
+    copy.bucket::<&str, String>(Some("new")).unwrap();
+    copy.bucket::<&str, I64>(Some("new")).unwrap();
+    assert!(copy.buckets().contains(&"b".to_string()));
+}
+
+#[test]
+fn test_memory_copy() {
+    let path = reset("memory_copy");
+    check_memory_copy(&Store::new(Config::new(path)).unwrap());
+    check_memory_copy(&Store::with_backend(
+        Config::new("unused"),
+        MemoryBackend::new(),
+    ));
+}
+
+fn check_update(store: &Store) {
+    let bucket = store.bucket::<&str, String>(Some("update")).unwrap();
+    let append = |x: Option<String>| Some(x.unwrap_or_default() + "a");
+    assert_eq!(bucket.update_and_fetch(&"x", append).unwrap().unwrap(), "a");
+    assert_eq!(bucket.fetch_and_update(&"x", append).unwrap().unwrap(), "a");
+    assert_eq!(bucket.get(&"x").unwrap().unwrap(), "aa");
+    assert!(bucket.update_and_fetch(&"x", |_| None).unwrap().is_none());
+    assert!(!bucket.contains(&"x").unwrap());
+
+    assert!(bucket.merge(&"x", &"b".to_string()).is_err());
+    let bucket = bucket.with_merge_operator(|old, value| Some(old.unwrap_or_default() + &value));
+    bucket.merge(&"x", &"b".to_string()).unwrap();
+    assert_eq!(bucket.merge(&"x", &"c".to_string()).unwrap().unwrap(), "bc");
+
+    // Subscribers are notified of merges
+    let mut watch = bucket.watch_prefix(None).unwrap();
+    bucket.merge(&"x", &"d".to_string()).unwrap();
+    let event = watch.next().unwrap().unwrap();
+    assert_eq!(event.old_value().unwrap().unwrap(), "bc");
+    assert_eq!(event.value().unwrap().unwrap(), "bcd");
+
+    let counters = store.bucket::<&str, I64>(Some("counters")).unwrap();
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let counters = counters.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    counters.increment(&"n", 2).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(counters.increment(&"n", -800).unwrap(), 0);
+    assert_eq!(i64::from(counters.get(&"n").unwrap().unwrap()), 0);
+    counters.set(&"max", &I64::from(i64::MAX)).unwrap();
+    assert!(counters.increment(&"max", 1).is_err());
+    assert_eq!(i64::from(counters.get(&"max").unwrap().unwrap()), i64::MAX);
+}
+
+#[test]
+fn test_update() {
+    let path = reset("update");
+    check_update(&Store::new(Config::new(path)).unwrap());
+    check_update(&Store::with_backend(
+        Config::new("unused"),
+        MemoryBackend::new(),
+    ));
+}
+
+#[test]
+fn test_errors() {
+    let path = reset("errors");
+    let store = Store::new(Config::new(path)).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("errors")).unwrap();
+    bucket.set(&"a", &"1".to_string()).unwrap();
+
+    let e = bucket
+        .compare_and_swap(&"a", None, Some(&"2".to_string()))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(e.current.as_deref(), Some("1"));
+    assert_eq!(e.proposed.as_deref(), Some("2"));
+    let e = Error::from(e);
+    assert!(e.is_conflict());
+    assert!(!e.is_corruption());
+
+    let raw = store.bucket::<&str, Raw>(Some("errors")).unwrap();
+    raw.set(&"b", &Raw::from(&[0xff][..])).unwrap();
+    let e = bucket.get(&"b").unwrap_err();
+    assert!(e.is_corruption());
+    assert!(!e.is_io());
+    let msg = e.to_string();
+    assert!(msg.contains("errors") && msg.contains("\"b\""), "{}", msg);
+    let e = bucket.iter().last().unwrap().unwrap().value::<String>();
+    assert!(matches!(e, Err(Error::Decode { ref key, .. }) if key == b"b"));
+
+    assert!(Error::IO(std::io::ErrorKind::NotFound.into()).is_io());
+}
+
+#[test]
+fn test_schema() {
+    let path = reset("schema");
+    let store = Store::new(Config::new(path)).unwrap();
+    assert!(store.bucket_info(Some("schema")).unwrap().is_none());
+
+    // Untyped access doesn't record any types
+    store.bucket::<Raw, Raw>(Some("schema")).unwrap();
+    assert!(store.bucket_info(Some("schema")).unwrap().is_none());
+
+    // Untagged values are accepted, the key tag is recorded and the value tag is added later
+    store.bucket::<&str, Raw>(Some("schema")).unwrap();
+    let info = store.bucket_info(Some("schema")).unwrap().unwrap();
+    assert_eq!(info.key.as_deref(), Some("String"));
+    assert_eq!(info.value, None);
+    store.bucket::<&str, String>(Some("schema")).unwrap();
+    let info = store.bucket_info(Some("schema")).unwrap().unwrap();
+    assert_eq!(info.value.as_deref(), Some("String"));
+    assert_eq!(info.version, 0);
+    store.bucket::<String, Raw>(Some("schema")).unwrap();
+
+    let e = store
+        .bucket::<Integer, String>(Some("schema"))
+        .err()
+        .unwrap();
+    assert!(matches!(e, Error::SchemaMismatch { ref found, .. } if **found == info));
+    assert!(store.bucket::<&str, Vec<u8>>(Some("schema")).is_err());
+
+    store
+        .bucket_with_version::<&str, String>(Some("schema"), 2)
+        .unwrap();
+    store.bucket::<&str, String>(Some("schema")).unwrap();
+    assert_eq!(
+        store.bucket_info(Some("schema")).unwrap().unwrap().version,
+        2
+    );
+    assert!(store
+        .bucket_with_version::<&str, String>(Some("schema"), 1)
+        .is_err());
+    assert!(!store.buckets().iter().any(|x| x.starts_with("__kv_")));
+
+    store.drop_bucket("schema").unwrap();
+    assert!(store.bucket_info(Some("schema")).unwrap().is_none());
+    store.bucket::<Integer, String>(Some("schema")).unwrap();
+}
+
+struct Name(String);
+
+impl Value for Name {
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        self.0.to_raw_value()
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        Ok(Name(String::from_raw_value(r)?))
+    }
+}
+
+impl Migrate for Name {
+    const VERSION: u32 = 2;
+
+    fn migrate(version: u32, value: Raw) -> Result<Raw, Error> {
+        let s = String::from_raw_value(value)?;
+        match version {
+            0 => Ok(Raw::from(s.trim())),
+            1 => s.to_uppercase().to_raw_value(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn check_versioned(store: &Store) {
+    let raw = store.bucket::<Integer, Raw>(Some("versioned")).unwrap();
+    for i in 0..2500 {
+        raw.set(&Integer::from(i), &Raw::from(" x ")).unwrap();
+    }
+
+    let bucket = store
+        .bucket::<Integer, Versioned<Name>>(Some("versioned"))
+        .unwrap();
+    let mut watch = bucket.watch_prefix(None).unwrap();
+
+    // Values are upgraded when they're read without being written
+    assert_eq!(bucket.get(&Integer::from(0)).unwrap().unwrap().0 .0, "X");
+    assert_eq!(&raw.get(&Integer::from(0)).unwrap().unwrap(), b" x ");
+
+    bucket
+        .set(&Integer::from(1), &Versioned(Name("y".into())))
+        .unwrap();
+    watch.next().unwrap().unwrap();
+    assert_eq!(bucket.migrate_all().unwrap(), 2499);
+    assert_eq!(bucket.migrate_all().unwrap(), 0);
+    assert!(watch.next().unwrap().unwrap().is_update());
+    assert_eq!(bucket.get(&Integer::from(1)).unwrap().unwrap().0 .0, "y");
+    for item in bucket.iter() {
+        assert_eq!(
+            item.unwrap().value::<Versioned<Name>>().unwrap().0 .0.len(),
+            1
+        );
+    }
+
+    // Values written by a newer version can't be read
+    let mut newer = b"\xffv\0\0\0\x03".to_vec();
+    newer.extend_from_slice(b"z");
+    raw.set(&Integer::from(0), &Raw::from(newer)).unwrap();
+    assert!(bucket.get(&Integer::from(0)).is_err());
+}
+
+#[test]
+fn test_versioned() {
+    let path = reset("versioned");
+    check_versioned(&Store::new(Config::new(path)).unwrap());
+    check_versioned(&Store::with_backend(
+        Config::new("unused"),
+        MemoryBackend::new(),
+    ));
+}
+
+#[cfg(all(feature = "zstd-value", feature = "json-value"))]
+#[test]
+fn test_zstd() {
+    let path = reset("zstd");
+    let store = Store::new(Config::new(path)).unwrap();
+    let bucket = store
+        .bucket::<&str, Zstd<Json<Vec<String>>, 64>>(Some("zstd"))
+        .unwrap();
+    let raw = store.bucket::<&str, Raw>(Some("zstd")).unwrap();
+
+    let small = vec!["a".to_string()];
+    bucket.set(&"small", &Zstd(Json(small.clone()))).unwrap();
+    assert_eq!(&raw.get(&"small").unwrap().unwrap(), b"\0[\"a\"]");
+    assert_eq!(bucket.get(&"small").unwrap().unwrap().0 .0, small);
+
+    let large = vec!["abcdefgh".repeat(100); 10];
+    bucket.set(&"large", &Zstd(Json(large.clone()))).unwrap();
+    assert!(raw.get(&"large").unwrap().unwrap().len() < 500);
+    assert_eq!(bucket.get(&"large").unwrap().unwrap().0 .0, large);
+
+    raw.set(&"invalid", &Raw::from(&[1, 2, 3][..])).unwrap();
+    assert!(bucket.get(&"invalid").err().unwrap().is_corruption());
+
+    // The tag includes the tag of the compressed value
+    let info = store.bucket_info(Some("zstd")).unwrap().unwrap();
+    assert_eq!(info.value.as_deref(), Some("Zstd(Json)"));
+    assert!(store.bucket::<&str, Zstd<String>>(Some("zstd")).is_err());
+    assert!(store
+        .bucket::<&str, Zstd<Json<Vec<u32>>>>(Some("zstd"))
+        .is_ok());
+
+    // Values are limited to `MAX_SIZE` bytes once decompressed
+    let limited = store
+        .bucket::<&str, Zstd<String, 64, 1024>>(Some("zstd-limit"))
+        .unwrap();
+    limited.set(&"a", &Zstd("a".repeat(1024))).unwrap();
+    assert_eq!(limited.get(&"a").unwrap().unwrap().0.len(), 1024);
+    assert!(limited.set(&"b", &Zstd("b".repeat(1025))).is_err());
+    let unlimited = store
+        .bucket::<&str, Zstd<String, 64>>(Some("zstd-limit"))
+        .unwrap();
+    unlimited.set(&"b", &Zstd("b".repeat(4096))).unwrap();
+    assert!(limited.get(&"b").err().unwrap().is_corruption());
+}
+
+#[test]
+fn test_queue() {
+    let path = reset("queue");
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    let queue = Queue::<String>::new(&store, "queue")
+        .unwrap()
+        .visibility_timeout(Duration::from_millis(100))
+        .max_attempts(2);
+    for x in ["a", "b", "c"] {
+        queue.push(&x.to_string()).unwrap();
+    }
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.peek().unwrap().unwrap(), "a");
+
+    let a = queue.pop().unwrap().unwrap();
+    assert_eq!(a.value(), "a");
+    assert_eq!(a.attempts(), 1);
+    assert_eq!(queue.peek().unwrap().unwrap(), "b");
+    let b = queue.pop().unwrap().unwrap();
+    assert!(queue.nack(&b).unwrap());
+    assert!(queue.ack(&a).unwrap());
+    assert!(!queue.ack(&a).unwrap());
+
+    // Returned values are delivered before newer values
+    let b = queue.pop().unwrap().unwrap();
+    assert_eq!((b.value().as_str(), b.attempts()), ("b", 2));
+    let c = queue.pop().unwrap().unwrap();
+    assert_eq!(c.value(), "c");
+    assert!(queue.pop().unwrap().is_none());
+
+    // Expired leases are redelivered, until the value is moved to the dead letter bucket
+    let c = queue.pop_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!((c.value().as_str(), c.attempts()), ("c", 2));
+    assert!(!queue.ack(&b).unwrap());
+    std::thread::sleep(Duration::from_millis(150));
+
+    // Leases can't be acknowledged or returned once they expire, even before redelivery
+    assert!(!queue.ack(&c).unwrap());
+    assert!(!queue.nack(&c).unwrap());
+    assert_eq!(queue.len(), 1);
+    assert!(queue.pop().unwrap().is_none());
+    assert!(queue.is_empty());
+    let dead: Vec<String> = queue
+        .dead_letters()
+        .unwrap()
+        .iter()
+        .map(|x| x.unwrap().value().unwrap())
+        .collect();
+    assert_eq!(dead, ["b", "c"]);
+
+    // Blocking pops wake up when a value is pushed
+    let q = queue.clone();
+    let t = std::thread::spawn(move || q.pop_timeout(Duration::from_secs(5)).unwrap());
+    std::thread::sleep(Duration::from_millis(50));
+    queue.push(&"d".to_string()).unwrap();
+    assert_eq!(t.join().unwrap().unwrap().value(), "d");
+    assert!(queue
+        .pop_timeout(Duration::from_millis(10))
+        .unwrap()
+        .is_none());
+
+    // Order is preserved when the store is reopened
+    drop(queue);
+    let queue = Queue::<String>::new(&store, "ordered").unwrap();
+    for i in 0..10 {
+        queue.push(&i.to_string()).unwrap();
+    }
+    drop(queue);
+    drop(store);
+    let store = Store::new(Config::new(path)).unwrap();
+    let queue = Queue::<String>::new(&store, "ordered").unwrap();
+    queue.push(&"10".to_string()).unwrap();
+    for i in 0..11 {
+        assert_eq!(queue.pop().unwrap().unwrap().into_value(), i.to_string());
+    }
+
+    // Ids don't collide after a restore since the ID counter is part of the backup
+    let queue = Queue::<String>::new(&store, "restored").unwrap();
+    for x in ["a", "b"] {
+        queue.push(&x.to_string()).unwrap();
+    }
+    assert_eq!(queue.pop().unwrap().unwrap().value(), "a");
+    let mut backup = Vec::new();
+    store.backup_to(&mut backup).unwrap();
+    let restored = Store::with_backend(Config::new("unused"), MemoryBackend::new());
+    restored.restore_from(Cursor::new(&backup)).unwrap();
+    let queue = Queue::<String>::new(&restored, "restored").unwrap();
+    let id = queue.push(&"c".to_string()).unwrap();
+    assert_eq!(queue.len(), 3);
+    let b = queue.pop().unwrap().unwrap();
+    let c = queue.pop().unwrap().unwrap();
+    assert_eq!((b.value().as_str(), c.value().as_str()), ("b", "c"));
+    assert_eq!(c.id(), id);
+    assert!(b.id() < id);
+    assert!(queue.pop().unwrap().is_none());
+    assert!(queue.ack(&b).unwrap() && queue.ack(&c).unwrap());
+    assert_eq!(queue.len(), 1);
+}
+
+fn check_collections(store: &Store) {
+    let set = SortedSet::<String>::new(store, "scores").unwrap();
+    for (name, score) in [("a", 3.0), ("b", -1.5), ("c", 10.0), ("d", 3.0)] {
+        assert!(set.add(&name.to_string(), score).unwrap().is_none());
+    }
+    assert_eq!(set.add(&"c".to_string(), 0.0).unwrap(), Some(10.0));
+    assert_eq!(set.len(), 4);
+    assert_eq!(set.score(&"c".to_string()).unwrap(), Some(0.0));
+    assert_eq!(set.rank(&"b".to_string()).unwrap(), Some(0));
+    assert_eq!(set.rank(&"d".to_string()).unwrap(), Some(3));
+    assert_eq!(set.rank(&"x".to_string()).unwrap(), None);
+    let names = |x: Vec<(String, f64)>| x.into_iter().map(|(m, _)| m).collect::<Vec<_>>();
+    assert_eq!(
+        names(set.range_by_score(0.0, 3.0).unwrap()),
+        ["c", "a", "d"]
+    );
+    assert_eq!(names(set.range_by_score(-2.0, -1.0).unwrap()), ["b"]);
+    assert_eq!(names(set.range_by_rank(1, 3).unwrap()), ["c", "a"]);
+    assert_eq!(set.remove(&"a".to_string()).unwrap(), Some(3.0));
+    assert_eq!(names(set.range_by_rank(0, 10).unwrap()), ["b", "c", "d"]);
+
+    let map = MultiMap::<String, U64>::new(store, "multimap").unwrap();
+    let k = |x: &str| x.to_string();
+    for (key, value) in [("a", 2), ("a", 1), ("b", 3), ("a", 2)] {
+        map.insert(&k(key), &U64::from(value)).unwrap();
+    }
+    let values = |key: &str| -> Vec<u64> {
+        map.get(&k(key))
+            .unwrap()
+            .into_iter()
+            .map(u64::from)
+            .collect()
+    };
+    assert_eq!(values("a"), [1, 2]);
+    assert_eq!(map.count(&k("a")).unwrap(), 2);
+    assert!(map.contains(&k("b"), &U64::from(3)).unwrap());
+    assert!(map.remove(&k("a"), &U64::from(1)).unwrap());
+    assert!(!map.remove(&k("a"), &U64::from(1)).unwrap());
+    assert_eq!(map.len(), 2);
+
+    // Both collections can be updated in the same transaction as other buckets
+    let log = store.bucket::<&str, String>(Some("log")).unwrap();
+    let buckets = Buckets::new()
+        .with_sorted_set(&set)
+        .with_multimap(&map)
+        .with(&log);
+    store
+        .transaction(&buckets, |t| {
+            let set = t.sorted_set(&set)?;
+            set.add(&"e".to_string(), 1.0)?;
+            assert_eq!(set.score(&"e".to_string())?, Some(1.0));
+            let map = t.multimap(&map)?;
+            map.insert(&k("b"), &U64::from(4))?;
+            assert_eq!(map.get(&k("b"))?.len(), 2);
+            assert_eq!(map.remove_all(&k("a"))?, 1);
+            t.bucket(&log)?.set(&"x", &"done".to_string())?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(set.rank(&"e".to_string()).unwrap(), Some(2));
+    assert!(values("a").is_empty());
+    assert_eq!(values("b"), [3, 4]);
+    assert_eq!(map.remove_all(&k("b")).unwrap(), 2);
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_collections() {
+    let path = reset("collections");
+    check_collections(&Store::new(Config::new(path)).unwrap());
+    check_collections(&Store::with_backend(
+        Config::new("unused"),
+        MemoryBackend::new(),
+    ));
+}
+
+#[test]
+fn test_timeseries() {
+    let path = reset("timeseries");
+    let store = Store::new(Config::new(path)).unwrap();
+    let minute = Duration::from_secs(60);
+    let ts = TimeSeries::<U64>::new(&store, "metrics")
+        .unwrap()
+        .with_rollup(minute, |x| u64::from(*x) as f64)
+        .unwrap()
+        .with_retention(Duration::from_secs(3600));
+
+    for i in 0..6u64 {
+        ts.append("a", i * 30_000, &U64::from(i)).unwrap();
+    }
+    ts.append("b", 0, &U64::from(100)).unwrap();
+
+    // Window queries only include the requested series
+    let window: Vec<u64> = ts
+        .range("a", 30_000, 120_000)
+        .unwrap()
+        .map(|x| u64::from(x.unwrap().1))
+        .collect();
+    assert_eq!(window, [1, 2, 3]);
+    assert_eq!(u64::from(ts.last("a").unwrap().unwrap().1), 5);
+
+    let aggs = ts.aggregate("a", 0, u64::MAX, minute, |x| u64::from(*x) as f64);
+    let aggs = aggs.unwrap();
+    assert_eq!(aggs.len(), 3);
+    assert_eq!(
+        (aggs[1].start, aggs[1].count, aggs[1].sum),
+        (60_000, 2, 5.0)
+    );
+    assert_eq!((aggs[2].min, aggs[2].max, aggs[2].avg()), (4.0, 5.0, 4.5));
+
+    // Rollups match the raw aggregates, including after a sample is replaced
+    assert_eq!(ts.rollup("a", minute, 0, u64::MAX).unwrap(), aggs);
+    ts.append("a", 60_000, &U64::from(10)).unwrap();
+    let rollup = ts.rollup("a", minute, 60_000, 120_000).unwrap();
+    assert_eq!((rollup.len(), rollup[0].count, rollup[0].max), (1, 2, 10.0));
+    assert!(ts.rollup("a", Duration::from_secs(1), 0, u64::MAX).is_err());
+
+    // Retention removes old samples from every series but keeps the rollups
+    let now = ts.append_now("a", &U64::from(6)).unwrap();
+    assert_eq!(ts.apply_retention().unwrap(), 7);
+    let remaining: Vec<u64> = ts
+        .range("a", 0, u64::MAX)
+        .unwrap()
+        .map(|x| x.unwrap().0)
+        .collect();
+    assert_eq!(remaining, [now]);
+    assert!(ts.last("b").unwrap().is_none());
+    assert_eq!(ts.rollup("a", minute, 0, 120_000).unwrap().len(), 2);
+
+    // Replacing a sample keeps the contribution of samples removed by retention
+    let all = Duration::from_millis(1 << 62);
+    let f = |x: &U64| u64::from(*x) as f64;
+    let ts = TimeSeries::<U64>::new(&store, "retained")
+        .unwrap()
+        .with_rollup(all, f)
+        .unwrap();
+    let retained = ts.clone().with_retention(Duration::from_secs(3600));
+    let now = u64::from(Integer::timestamp_ms().unwrap());
+    for (t, x) in [(now - 7_200_000, 1), (now, 5), (now + 1, 3)] {
+        ts.append("a", t, &U64::from(x)).unwrap();
+    }
+    assert_eq!(retained.apply_retention().unwrap(), 1);
+    retained.append("a", now, &U64::from(4)).unwrap();
+    let agg = ts.rollup("a", all, 0, u64::MAX).unwrap()[0];
+    assert_eq!((agg.count, agg.sum, agg.min, agg.max), (3, 8.0, 1.0, 5.0));
+
+    // Intervals that haven't lost any samples are rescanned when the min or max is replaced
+    ts.append("b", 0, &U64::from(1)).unwrap();
+    ts.append("b", 1, &U64::from(2)).unwrap();
+    ts.append("b", 0, &U64::from(5)).unwrap();
+    let agg = ts.rollup("b", all, 0, u64::MAX).unwrap()[0];
+    assert_eq!((agg.count, agg.sum, agg.min, agg.max), (2, 7.0, 2.0, 5.0));
+}
+
+fn check_blob(store: &Store) {
+    let files = BlobBucket::<&str>::new(store, "blobs").unwrap();
+    let chunks = store.bucket::<Raw, Raw>(Some("blobs.chunks")).unwrap();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+    let blob = files.put_blob(&"a", &data[..]).unwrap();
+    assert_eq!((blob.len(), blob.chunks()), (200_000, 4));
+    assert_eq!(blob.checksum(), crc32fast::hash(&data));
+    assert_eq!(files.get(&"a").unwrap(), Some(blob));
+
+    let mut buf = Vec::new();
+    let mut reader = files.get_blob(&"a").unwrap().unwrap();
+    reader.read_to_end(&mut buf).unwrap();
+    assert!(buf == data);
+
+    // Range reads can span chunks
+    let mut range = vec![0; 1000];
+    reader.seek(SeekFrom::Start(65_000)).unwrap();
+    reader.read_exact(&mut range).unwrap();
+    assert!(range[..] == data[65_000..66_000]);
+    reader.seek(SeekFrom::End(-10)).unwrap();
+    buf.clear();
+    reader.read_to_end(&mut buf).unwrap();
+    assert!(buf[..] == data[199_990..]);
+    assert!(reader.seek(SeekFrom::Current(-300_000)).is_err());
+
+    // Replacing or removing the key removes the chunks
+    let mut stale = files.get_blob(&"a").unwrap().unwrap();
+    let blob = files.put_blob(&"a", &b"small"[..]).unwrap();
+    assert_eq!((blob.len(), blob.chunks()), (5, 1));
+    assert_eq!(chunks.len(), 1);
+    let e = stale.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+    let mut stale = files.get_blob(&"a").unwrap().unwrap();
+    assert_eq!(files.remove(&"a").unwrap(), Some(blob));
+    assert!(stale.read_to_end(&mut Vec::new()).is_err());
+    assert!(files.get_blob(&"a").unwrap().is_none());
+    assert!(chunks.is_empty());
+
+    // Empty blobs
+    files.put_blob(&"b", std::io::empty()).unwrap();
+    let mut buf = Vec::new();
+    let mut reader = files.get_blob(&"b").unwrap().unwrap();
+    assert_eq!(reader.read_to_end(&mut buf).unwrap(), 0);
+    assert!(reader.blob().is_empty());
+
+    // Chunks that aren't referenced by a manifest are swept, unless they're still being written
+    struct Sweep<'a>(&'a BlobBucket<'static, &'static str>, &'a [u8], usize);
+    impl Read for Sweep<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.1.len() < 100_000 {
+                self.2 += self.0.sweep().unwrap();
+            }
+            self.1.read(buf)
+        }
+    }
+    let raw = store.bucket::<&str, Raw>(Some("blobs")).unwrap();
+    files.put_blob(&"c", &data[..]).unwrap();
+    raw.remove(&"c").unwrap();
+    chunks
+        .set(&Raw::from(&b"orphan"[..]), &Raw::default())
+        .unwrap();
+    let mut sweep = Sweep(&files, &data, 0);
+    let blob = files.put_blob(&"d", &mut sweep).unwrap();
+    assert_eq!(sweep.2, 5);
+    assert_eq!(chunks.len(), 4);
+    assert_eq!(files.sweep().unwrap(), 0);
+    let mut reader = files.get_blob(&"d").unwrap().unwrap();
+    assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap(), 200_000);
+    assert_eq!(reader.blob(), &blob);
+    assert_eq!(files.len(), 2);
+
+    // Manifests must have a chunk count that matches their length and chunk size
+    let manifest = |len: u64, chunk_size: u32, chunks: u64| {
+        let mut dst = vec![0xff, b'b'];
+        dst.extend_from_slice(&0u64.to_be_bytes());
+        dst.extend_from_slice(&len.to_be_bytes());
+        dst.extend_from_slice(&chunk_size.to_be_bytes());
+        dst.extend_from_slice(&chunks.to_be_bytes());
+        dst.extend_from_slice(&0u32.to_be_bytes());
+        Raw::from(dst)
+    };
+    assert!(blob::Manifest::from_raw_value(manifest(10, 4, 3)).is_ok());
+    assert!(blob::Manifest::from_raw_value(manifest(10, 4, 2)).is_err());
+    assert!(blob::Manifest::from_raw_value(manifest(10, 0, 1)).is_err());
+}
+
+#[test]
+fn test_blob() {
+    let path = reset("blob");
+    check_blob(&Store::new(Config::new(path)).unwrap());
+    check_blob(&Store::with_backend(
+        Config::new("unused"),
+        MemoryBackend::new(),
+    ));
+}
+
+#[test]
+fn test_async() {
+    let path = reset("async");
+    let store = AsyncStore::with_threads(Store::new(Config::new(path)).unwrap(), 2);
+
+    block_on(async {
+        let bucket = store
+            .bucket::<Integer, String>(Some("async"))
+            .await
+            .unwrap();
+        let mut watch = bucket.watch_prefix(None).unwrap();
+
+        for i in 0..10u128 {
+            bucket.set(i.into(), i.to_string()).await.unwrap();
+        }
+        assert!(watch.next().await.unwrap().unwrap().is_set());
+        assert_eq!(bucket.get(3.into()).await.unwrap().unwrap(), "3");
+        assert_eq!(bucket.remove(3.into()).await.unwrap().unwrap(), "3");
+        assert!(!bucket.contains(3.into()).await.unwrap());
+
+        let mut batch = Batch::new();
+        batch.set(&20.into(), &"20".to_string()).unwrap();
+        bucket.batch(batch).await.unwrap();
+
+        let n = bucket
+            .transaction(|txn| {
+                txn.set(&30.into(), &"30".to_string())?;
+                Ok::<_, TransactionError<Error>>(1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(n, 1);
+
+        let mut iter = bucket.iter();
+        let mut keys = Vec::new();
+        while let Some(item) = iter.next().await {
+            keys.push(item.unwrap().key::<u128>().unwrap());
+        }
+        assert_eq!(keys, vec![0, 1, 2, 4, 5, 6, 7, 8, 9, 20, 30]);
+
+        let memory = Store::with_backend(Config::new("memory"), MemoryBackend::new());
+        let other = AsyncStore::with_pool(memory, &store);
+        let bucket = other.bucket::<Integer, String>(None).await.unwrap();
+        bucket.set(1.into(), "1".into()).await.unwrap();
+        assert_eq!(bucket.get(1.into()).await.unwrap().unwrap(), "1");
+    });
+}
+
+#[test]
+fn test_ordered_keys() {
+    let path = reset("ordered_keys");
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+
+    let bucket = store.bucket::<I32, String>(Some("i32")).unwrap();
+    for i in -50..50 {
+        bucket.set(&i.into(), &format!("{}", i)).unwrap();
+    }
+
+    let keys: Vec<i32> = bucket
+        .iter()
+        .map(|item| item.unwrap().key::<I32>().unwrap().into())
+        .collect();
+    assert_eq!(keys, (-50..50).collect::<Vec<_>>());
+
+    let range: Vec<i32> = bucket
+        .iter_range(&I32::from(-3), &I32::from(2))
+        .unwrap()
+        .map(|item| item.unwrap().key::<I32>().unwrap().into())
+        .collect();
+    assert_eq!(range, vec![-3, -2, -1, 0, 1]);
+    assert_eq!(
+        i32::from(bucket.first().unwrap().unwrap().key::<I32>().unwrap()),
+        -50
+    );
+    let prev = bucket.prev_key(&I32::from(0)).unwrap().unwrap();
+    assert_eq!(i32::from(prev.key::<I32>().unwrap()), -1);
+
+    let bucket = store.bucket::<F64, String>(Some("f64")).unwrap();
+    let values = [
+        -f64::INFINITY,
+        -100.5,
+        -1.0,
+        -0.5,
+        0.0,
+        0.25,
+        1.0,
+        1e10,
+        f64::INFINITY,
+    ];
+    for f in values.iter().rev() {
+        bucket.set(&F64::from(*f), &format!("{}", f)).unwrap();
+    }
+    let keys: Vec<f64> = bucket
+        .iter()
+        .map(|item| item.unwrap().key::<F64>().unwrap().into())
+        .collect();
+    assert_eq!(keys, values);
+
+    assert_eq!(i8::from(I8::from(i8::MIN)), i8::MIN);
+    assert_eq!(u64::from(U64::from(u64::MAX)), u64::MAX);
+    assert_eq!(i128::from(I128::from(-1)), -1);
+    assert!(Integer::try_from(&b"short"[..]).is_err());
+    assert!(I64::from_raw_key(&Raw::from(b"abc")).is_err());
+}
+
+#[test]
+fn test_tuple_keys() {
+    let path = reset("tuple_keys");
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store
+        .bucket::<(String, String, I64), String>(Some("tuple"))
+        .unwrap();
+
+    let key = |a: &str, b: &str, c: i64| (a.to_string(), b.to_string(), I64::from(c));
+    for (a, b, c) in [
+        ("acme", "alice", 10),
+        ("acme", "alice", -5),
+        ("acme", "al", 1),
+        ("acme", "bob", 3),
+        ("acme\0", "x", 0),
+        ("other", "alice", 7),
+    ] {
+        bucket.set(&key(a, b, c), &format!("{}", c)).unwrap();
+    }
+
+    let keys: Vec<(String, String, i64)> = bucket
+        .iter_prefix(&("acme".to_string(),))
+        .unwrap()
+        .map(|item| {
+            let (a, b, c) = item.unwrap().key::<(String, String, I64)>().unwrap();
+            (a, b, c.into())
+        })
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            ("acme".to_string(), "al".to_string(), 1),
+            ("acme".to_string(), "alice".to_string(), -5),
+            ("acme".to_string(), "alice".to_string(), 10),
+            ("acme".to_string(), "bob".to_string(), 3),
+        ]
+    );
+
+    let prefix = ("acme".to_string(), "alice".to_string());
+    assert_eq!(bucket.iter_prefix(&prefix).unwrap().count(), 2);
+    assert_eq!(
+        bucket.iter_prefix(&key("acme", "bob", 3)).unwrap().count(),
+        1
+    );
+    assert_eq!(
+        bucket.get(&key("acme\0", "x", 0)).unwrap().unwrap(),
+        "0".to_string()
+    );
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn test_derive_key() {
+    let path = reset("derive_key");
+
+    #[derive(Debug, PartialEq, crate::Key)]
+    struct UserId(String);
+
+    #[derive(Debug, PartialEq, crate::Key)]
+    struct Event {
+        user: String,
+        time: I64,
+    }
+
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+
+    let users = store.bucket::<UserId, String>(Some("users")).unwrap();
+    users
+        .set(&UserId("alice".into()), &"Alice".to_string())
+        .unwrap();
+    let item = users.first().unwrap().unwrap();
+    assert_eq!(item.key::<UserId>().unwrap(), UserId("alice".into()));
+
+    // Derived keys are tagged with the name of the type
+    let info = store.bucket_info(Some("users")).unwrap().unwrap();
+    assert_eq!(info.key.as_deref(), Some("UserId"));
+    assert!(store.bucket::<String, String>(Some("users")).is_err());
+
+    let events = store.bucket::<Event, String>(Some("events")).unwrap();
+    for (user, time) in [("bob", 2), ("alice", 1), ("alice", -1)] {
+        let key = Event {
+            user: user.into(),
+            time: time.into(),
+        };
+        events.set(&key, &format!("{}", time)).unwrap();
+    }
+    let keys: Vec<Event> = events.iter().map(|x| x.unwrap().key().unwrap()).collect();
+    assert_eq!(keys[0].user, "alice");
+    assert_eq!(i64::from(keys[0].time), -1);
+    assert_eq!(keys[2].user, "bob");
+}
+
+#[cfg(all(feature = "derive", feature = "json-value"))]
+#[test]
+fn test_derive_value() {
+    let path = reset("derive_value");
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, crate::Value)]
+    #[kv(codec = "json")]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    let cfg = Config::new(path);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, User>(None).unwrap();
+    let user = User {
+        name: "alice".into(),
+        age: 30,
+    };
+    bucket.set(&"alice", &user).unwrap();
+    assert_eq!(bucket.get(&"alice").unwrap().unwrap(), user);
+
+    // Derived values are tagged with the codec and the name of the type
+    let info = store.bucket_info(None).unwrap().unwrap();
+    assert_eq!(info.value.as_deref(), Some("Json(User)"));
+    assert!(store.bucket::<&str, Json<User>>(None).is_err());
+}
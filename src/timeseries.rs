@@ -0,0 +1,393 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend;
+use crate::bucket::REWRITE_BATCH_SIZE;
+use crate::key::encode_escaped;
+use crate::{
+    Batch, Bucket, Buckets, Error, Integer, Key, Raw, Store, TransactionError, Value, U64,
+};
+
+type Extractor<V> = Arc<dyn Fn(&V) -> f64 + Send + Sync>;
+
+fn now_ms() -> Result<u64, Error> {
+    Ok(u64::from(Integer::timestamp_ms()?))
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis().min(u64::MAX as u128) as u64
+}
+
+/// Raw prefix of every sample in `series`
+fn series_prefix(series: &str) -> Result<Raw, Error> {
+    let mut dst = Vec::new();
+    encode_escaped(&series.to_raw_key()?, &mut dst);
+    Ok(dst.into())
+}
+
+/// Summary of the samples in an interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    /// Start of the interval, in milliseconds from the Unix epoch
+    pub start: u64,
+    /// Number of samples
+    pub count: u64,
+    /// Sum of all samples
+    pub sum: f64,
+    /// Smallest sample
+    pub min: f64,
+    /// Largest sample
+    pub max: f64,
+}
+
+impl Aggregate {
+    fn new(start: u64) -> Aggregate {
+        Aggregate {
+            start,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        self.sum += x;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+    }
+
+    /// Replace the sample `old` with `new`. Returns true if `old` may have been the smallest or
+    /// largest sample and `new` isn't, in which case `min` or `max` needs to be recomputed
+    fn replace(&mut self, old: f64, new: f64) -> bool {
+        let rescan = (old <= self.min && new > old) || (old >= self.max && new < old);
+        self.sum += new - old;
+        self.min = self.min.min(new);
+        self.max = self.max.max(new);
+        rescan
+    }
+
+    /// Average of all samples
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+impl Value for Aggregate {
+    const TYPE_TAG: Option<&'static str> = Some("Aggregate");
+
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        let mut dst = Vec::with_capacity(40);
+        dst.extend_from_slice(&self.start.to_be_bytes());
+        dst.extend_from_slice(&self.count.to_be_bytes());
+        for x in [self.sum, self.min, self.max] {
+            dst.extend_from_slice(&x.to_bits().to_be_bytes());
+        }
+        Ok(dst.into())
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        if r.len() != 40 {
+            return Err(Error::Message("Invalid aggregate".into()));
+        }
+        let field = |i: usize| {
+            let mut x = [0; 8];
+            x.copy_from_slice(&r[i * 8..(i + 1) * 8]);
+            u64::from_be_bytes(x)
+        };
+        Ok(Aggregate {
+            start: field(0),
+            count: field(1),
+            sum: f64::from_bits(field(2)),
+            min: f64::from_bits(field(3)),
+            max: f64::from_bits(field(4)),
+        })
+    }
+}
+
+/// Group samples into intervals of `interval` milliseconds
+fn aggregate<I>(samples: I, interval: u64) -> Result<Vec<Aggregate>, Error>
+where
+    I: Iterator<Item = Result<(u64, f64), Error>>,
+{
+    let mut dst: Vec<Aggregate> = Vec::new();
+    for x in samples {
+        let (ts, x) = x?;
+        let start = ts - ts % interval.max(1);
+        match dst.last_mut() {
+            Some(agg) if agg.start == start => agg.add(x),
+            _ => {
+                let mut agg = Aggregate::new(start);
+                agg.add(x);
+                dst.push(agg);
+            }
+        }
+    }
+    Ok(dst)
+}
+
+struct Rollup<V> {
+    interval: u64,
+    bucket: Bucket<'static, (String, U64), Aggregate>,
+    f: Extractor<V>,
+}
+
+impl<V> Clone for Rollup<V> {
+    fn clone(&self) -> Self {
+        Rollup {
+            interval: self.interval,
+            bucket: self.bucket.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+/// Samples keyed by series id and timestamp, in milliseconds from the Unix epoch. Samples are
+/// stored in the bucket `name` using `(series, timestamp)` keys.
+///
+/// Rollups added using `with_rollup` store an `Aggregate` for each interval in the bucket
+/// `<name>.rollup_<interval in ms>`, they are updated in the same transaction as each sample.
+/// Retention set using `with_retention` only applies to samples, so rollups can be used to keep
+/// a summary of older data. When a sample is replaced its contribution is subtracted from the
+/// rollup, if it was the smallest or largest sample in an interval that has lost samples to
+/// retention the old value can still be reported as the interval's `min` or `max`
+///
+/// ```rust
+/// use std::time::Duration;
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-timeseries").temporary(true))?;
+/// let cpu = TimeSeries::<F64>::new(&store, "cpu")?
+///     .with_rollup(Duration::from_secs(60), |x| f64::from(*x))?
+///     .with_retention(Duration::from_secs(24 * 60 * 60));
+///
+/// cpu.append("host-1", 60_000, &F64::from(0.5))?;
+/// cpu.append("host-1", 90_000, &F64::from(1.5))?;
+///
+/// let minutes = cpu.rollup("host-1", Duration::from_secs(60), 0, 120_000)?;
+/// assert_eq!(minutes[0].avg(), 1.0);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TimeSeries<V: Value> {
+    store: Store,
+    name: String,
+    samples: Bucket<'static, (String, U64), V>,
+    rollups: Vec<Rollup<V>>,
+    retention: Option<Duration>,
+}
+
+impl<V: Value> Clone for TimeSeries<V> {
+    fn clone(&self) -> Self {
+        TimeSeries {
+            store: self.store.clone(),
+            name: self.name.clone(),
+            samples: self.samples.clone(),
+            rollups: self.rollups.clone(),
+            retention: self.retention,
+        }
+    }
+}
+
+impl<V: Value> TimeSeries<V> {
+    /// Open the time series stored in the bucket `name`
+    pub fn new(store: &Store, name: &str) -> Result<TimeSeries<V>, Error> {
+        Ok(TimeSeries {
+            store: store.clone(),
+            name: name.to_string(),
+            samples: store.bucket(Some(name))?,
+            rollups: Vec::new(),
+            retention: None,
+        })
+    }
+
+    /// Maintain aggregates of the numbers returned by `f` for each `interval` as samples are
+    /// appended. Only samples appended after the rollup is added are included
+    pub fn with_rollup<F>(mut self, interval: Duration, f: F) -> Result<Self, Error>
+    where
+        F: 'static + Send + Sync + Fn(&V) -> f64,
+    {
+        let interval = millis(interval).max(1);
+        let name = format!("{}.rollup_{}", self.name, interval);
+        self.rollups.push(Rollup {
+            interval,
+            bucket: self.store.bucket(Some(&name))?,
+            f: Arc::new(f),
+        });
+        Ok(self)
+    }
+
+    /// Set how long samples are kept, see `apply_retention`
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Add a sample to `series`, replacing any existing sample with the same timestamp
+    pub fn append(&self, series: &str, timestamp: u64, value: &V) -> Result<(), Error> {
+        let key = (series.to_string(), U64::from(timestamp));
+        if self.rollups.is_empty() {
+            self.samples.set(&key, value)?;
+            return Ok(());
+        }
+
+        // Samples newer than the cutoff haven't been removed by retention
+        let cutoff = match self.retention {
+            Some(r) => now_ms()?.saturating_sub(millis(r)),
+            None => 0,
+        };
+        let mut buckets = Buckets::new().with(&self.samples);
+        for rollup in &self.rollups {
+            buckets = buckets.with(&rollup.bucket);
+        }
+        buckets.transaction(|t| {
+            let samples = t.bucket(&self.samples)?;
+            let old = samples.set(&key, value)?;
+            for rollup in &self.rollups {
+                let start = timestamp - timestamp % rollup.interval;
+                let rollup_key = (series.to_string(), U64::from(start));
+                let rollups = t.bucket(&rollup.bucket)?;
+                let mut agg = rollups
+                    .get(&rollup_key)?
+                    .unwrap_or_else(|| Aggregate::new(start));
+                let rescan = match &old {
+                    Some(old) if agg.count > 0 => agg.replace((rollup.f)(old), (rollup.f)(value)),
+                    _ => {
+                        agg.add((rollup.f)(value));
+                        false
+                    }
+                };
+
+                // Samples removed by retention can't be rescanned, so `min` and `max` are only
+                // recomputed when every sample in the interval is still present
+                if rescan && start >= cutoff {
+                    let end = (series.to_string(), U64::from(start + rollup.interval));
+                    let iter = samples.iter_range(&rollup_key, &end)?.map(|item| {
+                        let value: V = item.value()?;
+                        Ok((start, (rollup.f)(&value)))
+                    });
+                    let scanned =
+                        aggregate(iter, rollup.interval).map_err(TransactionError::Abort)?;
+                    if let Some(scanned) = scanned.first() {
+                        agg.min = scanned.min;
+                        agg.max = scanned.max;
+                    }
+                }
+                rollups.set(&rollup_key, &agg)?;
+            }
+            Ok::<_, TransactionError<Error>>(())
+        })
+    }
+
+    /// Add a sample to `series` using the current time, returning the timestamp
+    pub fn append_now(&self, series: &str, value: &V) -> Result<u64, Error> {
+        let now = now_ms()?;
+        self.append(series, now, value)?;
+        Ok(now)
+    }
+
+    /// Get the samples in `series` from `start` up to, but not including, `end`
+    pub fn range(
+        &self,
+        series: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<(u64, V), Error>>, Error> {
+        let a = (series.to_string(), U64::from(start));
+        let b = (series.to_string(), U64::from(end));
+        Ok(self.samples.iter_range(&a, &b)?.map(|item| {
+            let item = item?;
+            let (_, ts): (String, U64) = item.key()?;
+            Ok((u64::from(ts), item.value()?))
+        }))
+    }
+
+    /// Get the latest sample in `series`
+    pub fn last(&self, series: &str) -> Result<Option<(u64, V)>, Error> {
+        self.range(series, 0, u64::MAX)?.next_back().transpose()
+    }
+
+    /// Summarize the numbers returned by `f` for each `interval` from `start` up to, but not
+    /// including, `end`. Intervals without any samples are skipped
+    pub fn aggregate<F>(
+        &self,
+        series: &str,
+        start: u64,
+        end: u64,
+        interval: Duration,
+        f: F,
+    ) -> Result<Vec<Aggregate>, Error>
+    where
+        F: Fn(&V) -> f64,
+    {
+        let iter = self
+            .range(series, start, end)?
+            .map(|x| x.map(|(ts, value)| (ts, f(&value))));
+        aggregate(iter, millis(interval))
+    }
+
+    /// Get the aggregates maintained by the rollup added using `with_rollup(interval, ...)` for
+    /// intervals starting from `start` up to, but not including, `end`
+    pub fn rollup(
+        &self,
+        series: &str,
+        interval: Duration,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<Aggregate>, Error> {
+        let interval = millis(interval).max(1);
+        let rollup = match self.rollups.iter().find(|r| r.interval == interval) {
+            Some(r) => r,
+            None => return Err(Error::Message(format!("No rollup for {}ms", interval))),
+        };
+        let a = (series.to_string(), U64::from(start - start % interval));
+        let b = (series.to_string(), U64::from(end));
+        rollup
+            .bucket
+            .iter_range(&a, &b)?
+            .map(|item| item?.value())
+            .collect()
+    }
+
+    /// Remove samples older than the retention period from every series, returning the number
+    /// of samples removed. Samples are removed in batches of up to 1000
+    pub fn apply_retention(&self) -> Result<usize, Error> {
+        let retention = match self.retention {
+            Some(r) => millis(r),
+            None => return Ok(0),
+        };
+        let cutoff = now_ms()?.saturating_sub(retention);
+
+        let mut count = 0;
+        let mut start = Bound::Unbounded;
+        loop {
+            let series: String = match self.samples.iter_bounds(start, Bound::Unbounded)?.next() {
+                Some(item) => item?.key::<(String, U64)>()?.0,
+                None => return Ok(count),
+            };
+
+            let mut batch = Batch::new();
+            let mut n = 0;
+            for item in self.range(&series, 0, cutoff)? {
+                let (ts, _) = item?;
+                batch.remove(&(series.clone(), U64::from(ts)))?;
+                count += 1;
+                n += 1;
+                if n == REWRITE_BATCH_SIZE {
+                    self.samples.batch(std::mem::take(&mut batch))?;
+                    n = 0;
+                }
+            }
+            self.samples.batch(batch)?;
+
+            // Skip to the next series
+            match backend::prefix_end(&series_prefix(&series)?) {
+                Some(end) => start = Bound::Included(end),
+                None => return Ok(count),
+            }
+        }
+    }
+}
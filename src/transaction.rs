@@ -1,73 +1,327 @@
+use std::cell::RefCell;
+use std::fmt;
 use std::marker::PhantomData;
+use std::ops::Bound;
+use std::sync::Arc;
 
-use crate::{Batch, Error, Key, Value};
+use crate::backend::{self, TransactionalTree, Tree};
+use crate::index::{Indexes, SharedIndexes};
+use crate::ttl::{self, Ttl};
+use crate::watch::{Change, Order, Watchers};
+use crate::{Batch, Bucket, Error, Item, Key, Prefix, Raw, Value};
 
 /// Transaction error
-pub type TransactionError<E> = sled::transaction::ConflictableTransactionError<E>;
+#[derive(Debug)]
+pub enum TransactionError<E> {
+    /// The transaction was aborted, the error is returned by the function that started it
+    Abort(E),
+    /// The transaction conflicted with another write, it will be retried
+    Conflict,
+    /// A storage error, the transaction is not retried
+    Storage(Error),
+}
+
+impl<E: fmt::Display> fmt::Display for TransactionError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Abort(e) => write!(f, "Transaction aborted: {}", e),
+            TransactionError::Conflict => write!(f, "Transaction conflict"),
+            TransactionError::Storage(e) => write!(f, "Storage error in transaction: {}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for TransactionError<E> {}
+
+impl From<Error> for TransactionError<Error> {
+    fn from(e: Error) -> TransactionError<Error> {
+        TransactionError::Abort(e)
+    }
+}
 
 /// Transaction
 #[derive(Clone)]
 pub struct Transaction<'a, 'b, K: Key<'a>, V: Value>(
-    &'b sled::transaction::TransactionalTree,
+    &'b dyn TransactionalTree,
+    &'b dyn TransactionalTree,
+    &'b [&'b dyn TransactionalTree],
+    &'b Indexes,
+    &'b RefCell<Vec<Change>>,
     PhantomData<K>,
     PhantomData<V>,
     PhantomData<&'a ()>,
 );
 
 impl<'a, 'b, K: Key<'a>, V: Value> Transaction<'a, 'b, K, V> {
-    pub(crate) fn new(t: &'b sled::transaction::TransactionalTree) -> Self {
-        Transaction(t, PhantomData, PhantomData, PhantomData)
+    /// `trees` are the bucket's tree, expiry tree and index trees, in the order returned by
+    /// `Bucket::trees`
+    pub(crate) fn new(
+        trees: &'b [&'b dyn TransactionalTree],
+        indexes: &'b Indexes,
+        changes: &'b RefCell<Vec<Change>>,
+    ) -> Self {
+        Transaction(
+            trees[0],
+            trees[1],
+            &trees[2..],
+            indexes,
+            changes,
+            PhantomData,
+            PhantomData,
+            PhantomData,
+        )
     }
 
-    /// Get the value associated with the specified key
-    pub fn get(&self, key: &K) -> Result<Option<V>, TransactionError<Error>> {
-        let v = self
-            .0
-            .get(key.to_raw_key().map_err(TransactionError::Abort)?)?;
+    /// Set or remove `key`, clearing its expiry time and keeping indexes up to date
+    fn write(&self, key: Raw, value: Option<Raw>) -> Result<Option<Raw>, TransactionError<Error>> {
+        let prev = match &value {
+            Some(v) => self.0.insert(&key, v.clone())?,
+            None => self.0.remove(&key)?,
+        };
+        let expired = ttl::update(self.1, &key, None)?;
+        self.3.update(self.2, &key, prev.as_ref(), value.as_ref())?;
+        let prev = if expired { None } else { prev };
+        self.4.borrow_mut().push((key, prev.clone(), value));
+        Ok(prev)
+    }
 
-        match v {
-            None => Ok(None),
-            Some(x) => Ok(Some(V::from_raw_value(x).map_err(TransactionError::Abort)?)),
+    /// Get the value of `key`, hiding expired values
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Raw>, TransactionError<Error>> {
+        if ttl::is_expired(self.1, key)? {
+            return Ok(None);
         }
+        self.0.get(key)
+    }
+
+    /// Decode a stored value, adding the bucket name and key to any error
+    fn decode(&self, key: &[u8], value: Option<Raw>) -> Result<Option<V>, TransactionError<Error>> {
+        value
+            .map(V::from_raw_value)
+            .transpose()
+            .map_err(|e| TransactionError::Abort(Error::decode(self.3.name(), key, e)))
+    }
+
+    /// Get the value associated with the specified key
+    pub fn get(&self, key: &K) -> Result<Option<V>, TransactionError<Error>> {
+        let key = key.to_raw_key().map_err(TransactionError::Abort)?;
+        let v = self.get_raw(&key)?;
+        self.decode(&key, v)
     }
 
     /// Returns true if the bucket contains the given key
     pub fn contains(&self, key: &K) -> Result<bool, TransactionError<Error>> {
-        let v = self
-            .0
-            .get(key.to_raw_key().map_err(TransactionError::Abort)?)?;
+        let v = self.get_raw(&key.to_raw_key().map_err(TransactionError::Abort)?)?;
         Ok(v.is_some())
     }
 
+    fn range(
+        &self,
+        start: Bound<Raw>,
+        end: Bound<Raw>,
+    ) -> Result<impl DoubleEndedIterator<Item = Item<K, V>>, TransactionError<Error>> {
+        let mut items = Vec::new();
+        for (k, v) in self.0.range(start, end)? {
+            if !ttl::is_expired(self.1, &k)? {
+                items.push((k, v));
+            }
+        }
+
+        let bucket = Raw::from(self.3.name());
+        Ok(items
+            .into_iter()
+            .map(move |(k, v)| Item::new(&bucket, k, v)))
+    }
+
+    /// Get the keys/values in the specified range, including writes made earlier in the
+    /// transaction. The range is serializable: other writes to it either wait for the
+    /// transaction to commit or cause it to be retried
+    pub fn iter_range(
+        &self,
+        a: &K,
+        b: &K,
+    ) -> Result<impl DoubleEndedIterator<Item = Item<K, V>>, TransactionError<Error>> {
+        let a = a.to_raw_key().map_err(TransactionError::Abort)?;
+        let b = b.to_raw_key().map_err(TransactionError::Abort)?;
+        self.range(Bound::Included(a), Bound::Excluded(b))
+    }
+
+    /// Get the keys/values with the specified prefix, see `iter_range`
+    pub fn iter_prefix<P: Prefix<K>>(
+        &self,
+        a: &P,
+    ) -> Result<impl DoubleEndedIterator<Item = Item<K, V>>, TransactionError<Error>> {
+        let a = a.to_raw_prefix().map_err(TransactionError::Abort)?;
+        let end = match backend::prefix_end(&a) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range(Bound::Included(a), end)
+    }
+
     /// Set the value associated with the specified key to the provided value
     pub fn set(&self, key: &K, value: &V) -> Result<Option<V>, TransactionError<Error>> {
         let v = value.to_raw_value().map_err(TransactionError::Abort)?;
-        Ok(self
-            .0
-            .insert(key.to_raw_key().map_err(TransactionError::Abort)?, v)?
-            .map(|x| V::from_raw_value(x).map_err(TransactionError::Abort))
-            // https://users.rust-lang.org/t/convenience-method-for-flipping-option-result-to-result-option/13695/7
-            .map_or(Ok(None), |v| v.map(Some))?)
+        let key = key.to_raw_key().map_err(TransactionError::Abort)?;
+        let old = self.write(key.clone(), Some(v))?;
+        self.decode(&key, old)
     }
 
     /// Remove the value associated with the specified key from the database
     pub fn remove(&self, key: &K) -> Result<Option<V>, TransactionError<Error>> {
-        Ok(self
-            .0
-            .remove(key.to_raw_key().map_err(TransactionError::Abort)?)?
-            .map(|x| V::from_raw_value(x).map_err(TransactionError::Abort))
-            // https://users.rust-lang.org/t/convenience-method-for-flipping-option-result-to-result-option/13695/7
-            .map_or(Ok(None), |v| v.map(Some))?)
+        let key = key.to_raw_key().map_err(TransactionError::Abort)?;
+        let old = self.write(key.clone(), None)?;
+        self.decode(&key, old)
     }
 
     /// Apply batch update
     pub fn batch(&self, batch: &Batch<K, V>) -> Result<(), TransactionError<Error>> {
-        self.0.apply_batch(&batch.0)?;
+        for (k, v) in &batch.0 {
+            self.write(k.clone(), v.clone())?;
+        }
         Ok(())
     }
 
     /// Generate a monotonic ID. Not guaranteed to be contiguous or idempotent, can produce different values in the same transaction in case of conflicts
     pub fn generate_id(&self) -> Result<u64, TransactionError<Error>> {
-        Ok(self.0.generate_id()?)
+        self.0.generate_id()
+    }
+}
+
+/// Buckets taking part in a transaction started using `Store::transaction`
+///
+/// ```rust
+/// use kv::*;
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-buckets").temporary(true))?;
+/// let orders = store.bucket::<&str, String>(Some("orders"))?;
+/// let stock = store.bucket::<&str, String>(Some("stock"))?;
+///
+/// store.transaction(&Buckets::new().with(&orders).with(&stock), |t| {
+///     t.bucket(&orders)?.set(&"order-1", &"widget".to_string())?;
+///     t.bucket(&stock)?.set(&"widget", &"9".to_string())?;
+///     Ok::<_, TransactionError<Error>>(())
+/// })?;
+///
+/// assert_eq!(stock.get(&"widget")?, Some("9".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Buckets<'b>(Vec<(&'b dyn Tree, &'b Ttl, &'b SharedIndexes, &'b Watchers)>);
+
+impl<'b> Buckets<'b> {
+    /// Create an empty set of buckets
+    pub fn new() -> Buckets<'b> {
+        Buckets::default()
+    }
+
+    /// Add `bucket` to the transaction. Buckets are identified by name, adding another handle to
+    /// a bucket that has already been added does nothing
+    pub fn with<'a, K: Key<'a>, V: Value>(mut self, bucket: &'b Bucket<'a, K, V>) -> Buckets<'b> {
+        if self.position(bucket.2.name()).is_none() {
+            self.0.push((&*bucket.0, &bucket.1, &bucket.2, &bucket.3));
+        }
+        self
+    }
+
+    /// Get the number of buckets
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true when no buckets have been added
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|(_, _, indexes, _)| indexes.name() == name)
+    }
+
+    /// Run `f` in a transaction over every bucket
+    pub(crate) fn transaction<A, E, F>(&self, f: F) -> Result<A, E>
+    where
+        E: From<Error>,
+        F: Fn(&Transactions) -> Result<A, TransactionError<E>>,
+    {
+        let order = Order::new(self.0.iter().map(|(_, _, _, watchers)| *watchers).collect());
+        let changes: Vec<RefCell<Vec<Change>>> =
+            self.0.iter().map(|_| RefCell::default()).collect();
+
+        // Each bucket's tree is followed by its expiry tree and index trees
+        let indexes: Vec<Arc<Indexes>> = self.0.iter().map(|(_, _, x, _)| x.load()).collect();
+        let mut trees = Vec::new();
+        let mut offsets = Vec::with_capacity(self.0.len() + 1);
+        for ((tree, ttl, _, _), indexes) in self.0.iter().zip(&indexes) {
+            offsets.push(trees.len());
+            trees.push(*tree);
+            trees.push(&**ttl.tree()?);
+            trees.extend(indexes.trees());
+        }
+        offsets.push(trees.len());
+
+        let run = |t: &[&dyn TransactionalTree]| {
+            order.attempt(|| {
+                changes.iter().for_each(|c| c.borrow_mut().clear());
+                f(&Transactions {
+                    buckets: self,
+                    indexes: &indexes,
+                    trees: t,
+                    offsets: &offsets,
+                    changes: &changes,
+                })
+            })
+        };
+
+        let x = if trees.is_empty() {
+            loop {
+                match run(&[]) {
+                    Ok(x) => break x,
+                    Err(TransactionError::Conflict) => continue,
+                    Err(TransactionError::Abort(e)) => return Err(e),
+                    Err(TransactionError::Storage(e)) => return Err(e.into()),
+                }
+            }
+        } else {
+            backend::transaction(&trees, run)?
+        };
+
+        let changes: Vec<Vec<Change>> = changes.into_iter().map(RefCell::into_inner).collect();
+        order.notify(&changes);
+        Ok(x)
+    }
+}
+
+/// Handle passed to `Store::transaction`, used to access each bucket in the transaction
+pub struct Transactions<'b> {
+    buckets: &'b Buckets<'b>,
+    indexes: &'b [Arc<Indexes>],
+    trees: &'b [&'b dyn TransactionalTree],
+    offsets: &'b [usize],
+    changes: &'b [RefCell<Vec<Change>>],
+}
+
+impl<'b> Transactions<'b> {
+    /// Get a typed view of `bucket`, which must have been added to the `Buckets` used to start
+    /// the transaction
+    pub fn bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        bucket: &Bucket<'a, K, V>,
+    ) -> Result<Transaction<'a, '_, K, V>, TransactionError<Error>> {
+        let i = self.buckets.position(bucket.2.name()).ok_or_else(|| {
+            TransactionError::Abort(Error::Message(format!(
+                "Bucket is not part of the transaction: {}",
+                String::from_utf8_lossy(bucket.2.name())
+            )))
+        })?;
+        let (start, end) = (self.offsets[i], self.offsets[i + 1]);
+        Ok(Transaction::new(
+            &self.trees[start..end],
+            &self.indexes[i],
+            &self.changes[i],
+        ))
     }
 }
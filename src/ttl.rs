@@ -0,0 +1,240 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::backend::{self, Backend, TransactionalTree, Tree};
+use crate::index::Indexes;
+use crate::schema;
+use crate::watch::{Order, Watchers};
+use crate::{Error, Integer, Raw, TransactionError};
+
+/// Prefix of the hidden trees used to store expiry metadata
+pub(crate) const TTL_PREFIX: &str = "__kv_ttl__";
+
+// Expiry trees contain two kinds of entries:
+//   b'k' + key              => expiry timestamp
+//   b't' + expiry + key     => empty, ordered by expiry time for purging
+const KEY: u8 = b'k';
+const TIME: u8 = b't';
+
+/// Name of the expiry tree associated with the tree `name`
+pub(crate) fn tree_name(name: &[u8]) -> Vec<u8> {
+    let mut dst = TTL_PREFIX.as_bytes().to_vec();
+    dst.extend_from_slice(name);
+    dst
+}
+
+fn key_entry(key: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(key.len() + 1);
+    dst.push(KEY);
+    dst.extend_from_slice(key);
+    dst
+}
+
+fn time_entry(at: &Integer, key: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(key.len() + 17);
+    dst.push(TIME);
+    dst.extend_from_slice(at.as_ref());
+    dst.extend_from_slice(key);
+    dst
+}
+
+/// Expiry state shared by every handle to a bucket. Buckets only read and update expiry times
+/// once a key has been stored with one, until then the expiry tree isn't opened and writes that
+/// don't need a transaction for anything else are applied directly to the bucket's tree
+pub(crate) struct Expiry {
+    backend: Arc<dyn Backend>,
+    name: Vec<u8>,
+    tree: OnceLock<Arc<dyn Tree>>,
+    enabled: AtomicBool,
+
+    /// Held for reading by writes that skip the expiry tree, and for writing while expiry times
+    /// are enabled
+    lock: RwLock<()>,
+}
+
+impl Expiry {
+    /// Expiry state for the bucket `name`, using the flags recorded in its metadata
+    pub(crate) fn new(backend: Arc<dyn Backend>, name: &[u8]) -> Result<Expiry, Error> {
+        let enabled = schema::flags(&*backend, name)? & schema::EXPIRY != 0;
+        Ok(Expiry {
+            backend,
+            name: name.into(),
+            tree: OnceLock::new(),
+            enabled: AtomicBool::new(enabled),
+            lock: RwLock::new(()),
+        })
+    }
+}
+
+/// Expiry metadata for a single bucket, along with the time reads are checked against when it
+/// is fixed, as it is for copies made using `Store::copy_to_memory`
+#[derive(Clone)]
+pub(crate) struct Ttl(pub(crate) Arc<Expiry>, pub(crate) Option<Integer>);
+
+impl Ttl {
+    /// Time used to decide whether a key has expired when reading
+    pub(crate) fn now(&self) -> Result<Integer, Error> {
+        match self.1 {
+            Some(now) => Ok(now),
+            None => Integer::timestamp_ms(),
+        }
+    }
+
+    /// Returns true once a key in the bucket has been stored with an expiry time
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Acquire)
+    }
+
+    /// Record that the bucket uses expiry times, this must be called before the first expiry
+    /// time is written
+    pub(crate) fn enable(&self) -> Result<(), Error> {
+        if self.is_enabled() {
+            return Ok(());
+        }
+
+        // Wait for writes that skipped the expiry tree to finish
+        let _guard = self.0.lock.write().unwrap_or_else(PoisonError::into_inner);
+        if !self.is_enabled() {
+            self.tree()?;
+            schema::set_flag(&*self.0.backend, &self.0.name, schema::EXPIRY)?;
+            self.0.enabled.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns a guard that keeps expiry times from being enabled while it's held, or `None` if
+    /// they already are. Writes made while holding it don't need to update the expiry tree
+    pub(crate) fn unused(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        let guard = self.0.lock.read().unwrap_or_else(PoisonError::into_inner);
+        (!self.is_enabled()).then_some(guard)
+    }
+
+    /// The expiry tree, it's opened the first time it's needed
+    pub(crate) fn tree(&self) -> Result<&Arc<dyn Tree>, Error> {
+        if let Some(tree) = self.0.tree.get() {
+            return Ok(tree);
+        }
+        let tree = self.0.backend.open_tree(&tree_name(&self.0.name))?;
+        Ok(self.0.tree.get_or_init(|| tree))
+    }
+
+    /// Returns true when no key in the bucket has an expiry time
+    pub(crate) fn is_empty(&self) -> Result<bool, Error> {
+        Ok(!self.is_enabled() || self.tree()?.is_empty())
+    }
+
+    /// Get the expiry time of `key`, if one is set
+    pub(crate) fn expiry(&self, key: &[u8]) -> Result<Option<Integer>, Error> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let x = self.tree()?.get(&key_entry(key))?;
+        x.map(|x| Integer::try_from(x.as_ref())).transpose()
+    }
+
+    /// Returns true if `key` has an expiry time at or before `now`
+    pub(crate) fn is_expired(&self, key: &[u8], now: &Integer) -> Result<bool, Error> {
+        if self.is_empty()? {
+            return Ok(false);
+        }
+
+        match self.expiry(key)? {
+            Some(at) => Ok(at <= *now),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove all expired keys from `tree`, returning the number of keys removed
+    pub(crate) fn purge(
+        &self,
+        tree: &dyn Tree,
+        indexes: &Indexes,
+        watchers: &Watchers,
+    ) -> Result<usize, Error> {
+        if !self.is_enabled() {
+            return Ok(0);
+        }
+
+        let ttl = self.tree()?;
+        let now = Integer::timestamp_ms()?;
+        let mut count = 0;
+
+        let start = Bound::Included(Raw::from(&[TIME][..]));
+        let end = Bound::Excluded(time_entry(&Integer::from(u128::from(now) + 1), &[]).into());
+        for entry in ttl.range(start, end) {
+            let (entry, _) = entry?;
+            let key: Raw = entry[17..].into();
+            let mut trees = vec![tree, &**ttl];
+            trees.extend(indexes.trees());
+            let order = Order::new(vec![watchers]);
+            let removed = backend::transaction(&trees, |t| {
+                order.attempt(|| {
+                    // The key may have been overwritten since the scan started
+                    match t[1].get(&key_entry(&key))? {
+                        Some(at) if at == entry[1..17] => {
+                            let prev = t[0].remove(&key)?;
+                            clear(t[1], &key)?;
+                            indexes.update(&t[2..], &key, prev.as_ref(), None)?;
+                            Ok(Some(prev))
+                        }
+                        _ => Ok(None),
+                    }
+                })
+            })?;
+
+            if let Some(prev) = removed {
+                order.notify(&[[(key, prev, None)]]);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Remove all expiry metadata
+    pub(crate) fn clear(&self) -> Result<(), Error> {
+        if self.is_enabled() {
+            self.tree()?.clear()?;
+        }
+        Ok(())
+    }
+}
+
+/// Set the expiry time for `key`, `None` clears the expiry time. Returns true if the key was
+/// already expired
+pub(crate) fn update(
+    ttl: &dyn TransactionalTree,
+    key: &[u8],
+    at: Option<&Integer>,
+) -> Result<bool, TransactionError<Error>> {
+    let expired = clear(ttl, key)?;
+    if let Some(at) = at {
+        ttl.insert(&key_entry(key), at.as_ref().into())?;
+        ttl.insert(&time_entry(at, key), Raw::default())?;
+    }
+    Ok(expired)
+}
+
+/// Returns true if `key` has an expiry time that has already passed
+pub(crate) fn is_expired(
+    ttl: &dyn TransactionalTree,
+    key: &[u8],
+) -> Result<bool, TransactionError<Error>> {
+    match ttl.get(&key_entry(key))? {
+        Some(at) => Ok(Integer::try_from(at.as_ref())? <= Integer::timestamp_ms()?),
+        None => Ok(false),
+    }
+}
+
+/// Remove the expiry time for `key`, returns true if the key was already expired
+fn clear(ttl: &dyn TransactionalTree, key: &[u8]) -> Result<bool, TransactionError<Error>> {
+    match ttl.remove(&key_entry(key))? {
+        Some(at) => {
+            let at = Integer::try_from(at.as_ref())?;
+            ttl.remove(&time_entry(&at, key))?;
+            Ok(at <= Integer::timestamp_ms()?)
+        }
+        None => Ok(false),
+    }
+}
@@ -2,6 +2,15 @@ use crate::Error;
 
 /// A trait used to convert between types and `Raw`
 pub trait Value: Sized {
+    /// Stable name of the value encoding, see `Key::TYPE_TAG`
+    const TYPE_TAG: Option<&'static str> = None;
+
+    /// Tag recorded for buckets storing this type, `TYPE_TAG` by default. Wrappers such as `Zstd`
+    /// and `Versioned` include the tag of the value they wrap, e.g. `Zstd(Json)`
+    fn type_tag() -> Option<String> {
+        Self::TYPE_TAG.map(String::from)
+    }
+
     /// Wrapper around AsRef<[u8]>
     fn to_raw_value(&self) -> Result<Raw, Error>;
 
@@ -9,6 +18,14 @@ pub trait Value: Sized {
     fn from_raw_value(r: Raw) -> Result<Self, Error>;
 }
 
+/// Tag of a wrapper named `name` around a value tagged `inner`
+pub(crate) fn wrapped_tag(name: &str, inner: Option<String>) -> Option<String> {
+    match inner {
+        Some(inner) => Some(format!("{}({})", name, inner)),
+        None => Some(name.into()),
+    }
+}
+
 /// Raw is an alias for `sled::IVec`
 pub type Raw = sled::IVec;
 
@@ -23,6 +40,8 @@ impl Value for Raw {
 }
 
 impl Value for std::sync::Arc<[u8]> {
+    const TYPE_TAG: Option<&'static str> = Some("Bytes");
+
     fn to_raw_value(&self) -> Result<Raw, Error> {
         Ok(self.as_ref().into())
     }
@@ -33,6 +52,8 @@ impl Value for std::sync::Arc<[u8]> {
 }
 
 impl Value for Vec<u8> {
+    const TYPE_TAG: Option<&'static str> = Some("Bytes");
+
     fn to_raw_value(&self) -> Result<Raw, Error> {
         Ok(self.as_slice().into())
     }
@@ -43,6 +64,8 @@ impl Value for Vec<u8> {
 }
 
 impl Value for String {
+    const TYPE_TAG: Option<&'static str> = Some("String");
+
     fn to_raw_value(&self) -> Result<Raw, Error> {
         Ok(self.as_str().into())
     }
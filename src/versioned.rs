@@ -0,0 +1,129 @@
+use crate::value;
+use crate::{Error, Raw, Value};
+
+/// Values written by `Versioned` start with this header followed by a big-endian `u32` schema
+/// version, values without it are treated as version 0
+const HEADER: [u8; 2] = [0xff, b'v'];
+const HEADER_LEN: usize = HEADER.len() + 4;
+
+/// Schema migrations for values stored using `Versioned`
+///
+/// ```rust
+/// use kv::*;
+///
+/// struct User(String);
+///
+/// impl Value for User {
+///     fn to_raw_value(&self) -> Result<Raw, Error> {
+///         self.0.to_raw_value()
+///     }
+///
+///     fn from_raw_value(r: Raw) -> Result<Self, Error> {
+///         Ok(User(String::from_raw_value(r)?))
+///     }
+/// }
+///
+/// impl Migrate for User {
+///     const VERSION: u32 = 2;
+///
+///     fn migrate(version: u32, value: Raw) -> Result<Raw, Error> {
+///         let s = String::from_raw_value(value)?;
+///         match version {
+///             // v0 -> v1: names are trimmed
+///             0 => Ok(Raw::from(s.trim())),
+///             // v1 -> v2: names are lowercase
+///             _ => s.to_lowercase().to_raw_value(),
+///         }
+///     }
+/// }
+///
+/// # fn main() -> Result<(), Error> {
+/// let store = Store::new(Config::new("./test/example-versioned").temporary(true))?;
+/// let bucket = store.bucket::<&str, Raw>(Some("users"))?;
+/// bucket.set(&"a", &Raw::from(" Alice "))?;
+///
+/// let bucket = store.bucket::<&str, Versioned<User>>(Some("users"))?;
+/// assert_eq!(bucket.get(&"a")?.unwrap().0 .0, "alice");
+/// assert_eq!(bucket.migrate_all()?, 1);
+/// # Ok(())
+/// # }
+/// ```
+pub trait Migrate: Value {
+    /// Current schema version, values are written with this version
+    const VERSION: u32;
+
+    /// Upgrade `value`, encoded using schema `version`, to `version + 1`
+    fn migrate(version: u32, value: Raw) -> Result<Raw, Error>;
+}
+
+/// Value tagged with a schema version, older values are upgraded using `Migrate::migrate` when
+/// they are read. Use `Bucket::migrate_all` to rewrite every stored value using the current
+/// version
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned<T>(pub T);
+
+impl<T> Versioned<T> {
+    /// Convert back into inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// Get the schema version and encoded value from `r`
+pub(crate) fn split(r: &[u8]) -> (u32, &[u8]) {
+    match r.strip_prefix(&HEADER[..]) {
+        Some(rest) if rest.len() >= 4 => {
+            let version = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            (version, &rest[4..])
+        }
+        _ => (0, r),
+    }
+}
+
+/// Upgrade `r` to the current version, returning `None` if it's already current
+pub(crate) fn upgrade<T: Migrate>(r: &[u8]) -> Result<Option<Raw>, Error> {
+    let (mut version, value) = split(r);
+    if version >= T::VERSION {
+        return Ok(None);
+    }
+    let mut value = Raw::from(value);
+    while version < T::VERSION {
+        value = T::migrate(version, value)?;
+        version += 1;
+    }
+    Ok(Some(encode(version, &value)))
+}
+
+fn encode(version: u32, value: &[u8]) -> Raw {
+    let mut dst = Vec::with_capacity(value.len() + HEADER_LEN);
+    dst.extend_from_slice(&HEADER);
+    dst.extend_from_slice(&version.to_be_bytes());
+    dst.extend_from_slice(value);
+    dst.into()
+}
+
+impl<T: Migrate> Value for Versioned<T> {
+    fn type_tag() -> Option<String> {
+        value::wrapped_tag("Versioned", T::type_tag())
+    }
+
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        Ok(encode(T::VERSION, &self.0.to_raw_value()?))
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        let (version, value) = split(&r);
+        if version > T::VERSION {
+            return Err(Error::Message(format!(
+                "Value has schema version {}, the newest supported version is {}",
+                version,
+                T::VERSION
+            )));
+        }
+        let value = match upgrade::<T>(&r)? {
+            Some(x) => Raw::from(&x[HEADER_LEN..]),
+            None => Raw::from(value),
+        };
+        Ok(Versioned(T::from_raw_value(value)?))
+    }
+}
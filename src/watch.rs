@@ -0,0 +1,392 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Item, Key, Raw, Value};
+
+/// A write to a single key: key, previous value and new value
+pub(crate) type Change = (Raw, Option<Raw>, Option<Raw>);
+
+type Filter = Box<dyn Fn(&Change) -> bool + Send + Sync>;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Event is used to describe the type of update
+pub enum Event<K, V> {
+    /// A new key has been inserted
+    Insert(Item<K, V>),
+
+    /// An existing key has been overwritten
+    Update {
+        /// The previous value
+        old: Item<K, V>,
+        /// The new value
+        new: Item<K, V>,
+    },
+
+    /// A key has been removed
+    Remove {
+        /// The removed value
+        old: Item<K, V>,
+    },
+}
+
+impl<K, V> Event<K, V> {
+    fn from_change(bucket: &[u8], change: Change) -> Option<Self> {
+        match change {
+            (k, None, Some(v)) => Some(Event::Insert(Item::new(bucket, k, v))),
+            (k, Some(old), Some(new)) => Some(Event::Update {
+                old: Item::new(bucket, k.clone(), old),
+                new: Item::new(bucket, k, new),
+            }),
+            (k, Some(old), None) => Some(Event::Remove {
+                old: Item::new(bucket, k, old),
+            }),
+            (_, None, None) => None,
+        }
+    }
+
+    /// Returns true when event is `Insert`
+    pub fn is_insert(&self) -> bool {
+        matches!(self, Event::Insert(_))
+    }
+
+    /// Returns true when event is `Update`
+    pub fn is_update(&self) -> bool {
+        matches!(self, Event::Update { .. })
+    }
+
+    /// Returns true when event is `Insert` or `Update`
+    pub fn is_set(&self) -> bool {
+        !self.is_remove()
+    }
+
+    /// Returns true when event is `Remove`
+    pub fn is_remove(&self) -> bool {
+        matches!(self, Event::Remove { .. })
+    }
+}
+
+impl<'a, K: Key<'a>, V: Value> Event<K, V> {
+    /// Get event key
+    pub fn key(&'a self) -> Result<K, Error> {
+        match self {
+            Event::Insert(item) | Event::Update { new: item, .. } | Event::Remove { old: item } => {
+                item.key()
+            }
+        }
+    }
+
+    /// Get the new value, `None` for `Remove`
+    pub fn value(&'a self) -> Result<Option<V>, Error> {
+        match self {
+            Event::Insert(item) | Event::Update { new: item, .. } => item.value().map(Some),
+            Event::Remove { .. } => Ok(None),
+        }
+    }
+
+    /// Get the previous value, `None` for `Insert`
+    pub fn old_value(&'a self) -> Result<Option<V>, Error> {
+        match self {
+            Event::Update { old, .. } | Event::Remove { old } => old.value().map(Some),
+            Event::Insert(_) => Ok(None),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    changes: VecDeque<Change>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&self, change: Option<Change>) {
+        let mut state = lock(&self.state);
+        match change {
+            Some(change) => state.changes.push_back(change),
+            None => state.closed = true,
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+struct Subscriber {
+    prefix: Raw,
+    queue: Weak<Queue>,
+}
+
+/// Subscribers to a single bucket, shared by every `Bucket` opened from the same `Store`
+pub(crate) struct Watchers {
+    name: Raw,
+    subscribers: Mutex<Vec<Subscriber>>,
+    order: Arc<Mutex<()>>,
+}
+
+impl Watchers {
+    /// `order` is shared by all buckets in a store, see `Order`
+    pub(crate) fn new(name: &[u8], order: Arc<Mutex<()>>) -> Watchers {
+        Watchers {
+            name: name.into(),
+            subscribers: Mutex::new(Vec::new()),
+            order,
+        }
+    }
+
+    fn subscribe(&self, prefix: Raw) -> Arc<Queue> {
+        let queue = Arc::new(Queue::default());
+        lock(&self.subscribers).push(Subscriber {
+            prefix,
+            queue: Arc::downgrade(&queue),
+        });
+        queue
+    }
+
+    /// Returns true if anyone is subscribed to the bucket
+    pub(crate) fn has_subscribers(&self) -> bool {
+        lock(&self.subscribers)
+            .iter()
+            .any(|s| s.queue.strong_count() > 0)
+    }
+
+    /// Returns a guard that should be held until `notify` has been called, or `None` if there are
+    /// no subscribers
+    fn lock(&self) -> Option<MutexGuard<'_, ()>> {
+        let mut subscribers = lock(&self.subscribers);
+        subscribers.retain(|s| s.queue.strong_count() > 0);
+        if subscribers.is_empty() {
+            return None;
+        }
+        drop(subscribers);
+        Some(lock(&self.order))
+    }
+
+    /// Send `changes` to matching subscribers
+    fn notify(&self, changes: &[Change]) {
+        let subscribers = lock(&self.subscribers);
+        for change in changes {
+            if change.1.is_none() && change.2.is_none() {
+                continue;
+            }
+
+            for s in subscribers.iter() {
+                if !change.0.starts_with(&s.prefix) {
+                    continue;
+                }
+
+                if let Some(queue) = s.queue.upgrade() {
+                    queue.push(Some(change.clone()));
+                }
+            }
+        }
+    }
+
+    /// Send changes that weren't made in a transaction, see `Order`
+    pub(crate) fn send(&self, changes: &[Change]) {
+        if let Some(_order) = self.lock() {
+            self.notify(changes);
+        }
+    }
+
+    /// End all subscriptions
+    pub(crate) fn close(&self) {
+        for s in lock(&self.subscribers).drain(..) {
+            if let Some(queue) = s.queue.upgrade() {
+                queue.push(None);
+            }
+        }
+    }
+}
+
+impl Drop for Watchers {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+/// Sends the changes made by a write to subscribers in the same order as writes are committed
+///
+/// The store-wide order lock is only taken at the end of a transaction attempt, once user code
+/// has run, and held until the committed changes have been sent. A transaction that writes to
+/// another watched bucket from inside its closure doesn't deadlock, and subscribers never run
+/// user code while it's held since filters are applied by `Watch`
+pub(crate) struct Order<'a> {
+    watchers: Vec<&'a Watchers>,
+    guard: RefCell<Option<MutexGuard<'a, ()>>>,
+}
+
+impl<'a> Order<'a> {
+    pub(crate) fn new(watchers: Vec<&'a Watchers>) -> Order<'a> {
+        Order {
+            watchers,
+            guard: RefCell::new(None),
+        }
+    }
+
+    /// Run one attempt of a transaction, `f` may be called again if the attempt conflicts. The
+    /// lock is released when the next attempt starts
+    pub(crate) fn attempt<T, E, F: FnOnce() -> Result<T, E>>(&self, f: F) -> Result<T, E> {
+        self.guard.borrow_mut().take();
+        let x = f()?;
+        *self.guard.borrow_mut() = self.watchers.iter().find_map(|w| w.lock());
+        Ok(x)
+    }
+
+    /// Send the committed changes for each bucket, in the same order as `watchers`
+    pub(crate) fn notify<C: AsRef<[Change]>>(self, changes: &[C]) {
+        if self.guard.borrow().is_some() {
+            for (watchers, changes) in self.watchers.iter().zip(changes) {
+                watchers.notify(changes.as_ref());
+            }
+        }
+    }
+}
+
+/// Subscribe to key updates
+pub struct Watch<K, V> {
+    queue: Arc<Queue>,
+    bucket: Raw,
+    filter: Option<Filter>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> Watch<K, V> {
+    pub(crate) fn new(watchers: &Watchers, prefix: Raw) -> Watch<K, V> {
+        Watch {
+            queue: watchers.subscribe(prefix),
+            bucket: watchers.name.clone(),
+            filter: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub(crate) fn with_filter<F>(watchers: &Watchers, prefix: Raw, f: F) -> Watch<K, V>
+    where
+        K: 'static,
+        V: 'static,
+        F: 'static + Send + Sync + Fn(&Event<K, V>) -> bool,
+    {
+        let bucket = watchers.name.clone();
+        let filter: Filter = Box::new(move |change: &Change| {
+            Event::from_change(&bucket, change.clone()).is_some_and(|event| f(&event))
+        });
+        Watch {
+            queue: watchers.subscribe(prefix),
+            bucket: watchers.name.clone(),
+            filter: Some(filter),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Convert a received change to an event, the filter is called without holding any locks
+    fn event(&self, change: Change) -> Option<Event<K, V>> {
+        if let Some(filter) = &self.filter {
+            if !filter(&change) {
+                return None;
+            }
+        }
+        Event::from_change(&self.bucket, change)
+    }
+}
+
+impl<K, V> Watch<K, V> {
+    /// Wait up to `timeout` for the next event, returns `None` if no event was received in time
+    /// or the bucket has been dropped
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<Event<K, V>, Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let change = {
+                let mut state = lock(&self.queue.state);
+                loop {
+                    if let Some(change) = state.changes.pop_front() {
+                        break change;
+                    }
+                    let now = Instant::now();
+                    if state.closed || now >= deadline {
+                        return None;
+                    }
+                    state = self
+                        .queue
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+            };
+            if let Some(event) = self.event(change) {
+                return Some(Ok(event));
+            }
+        }
+    }
+}
+
+// Watch doesn't contain any `K` or `V` values
+impl<K, V> Unpin for Watch<K, V> {}
+
+impl<K, V> Iterator for Watch<K, V> {
+    type Item = Result<Event<K, V>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let change = {
+                let mut state = lock(&self.queue.state);
+                loop {
+                    if let Some(change) = state.changes.pop_front() {
+                        break change;
+                    }
+                    if state.closed {
+                        return None;
+                    }
+                    state = self
+                        .queue
+                        .ready
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            };
+            if let Some(event) = self.event(change) {
+                return Some(Ok(event));
+            }
+        }
+    }
+}
+
+impl<K, V> Future for Watch<K, V> {
+    type Output = Option<Event<K, V>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            let change = {
+                let mut state = lock(&self.queue.state);
+                match state.changes.pop_front() {
+                    Some(change) => change,
+                    None if state.closed => return Poll::Ready(None),
+                    None => {
+                        state.waker = Some(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                }
+            };
+            if let Some(event) = self.event(change) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}